@@ -0,0 +1,456 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::brain::{BrainError, BrainMetadata};
+use crate::network::{Activation, Level, LevelKind, NeuralNetwork};
+
+/// First bytes of every binary brain file.
+pub const MAGIC: [u8; 4] = *b"CARB";
+
+/// Version written by `encode`.
+pub const BINARY_VERSION: u16 = 1;
+
+/// How weights and biases are stored, smaller ones losing precision.
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightEncoding {
+    /// exact
+    #[default]
+    F32,
+    /// half precision, about three significant digits
+    F16,
+    /// every row, and the biases of every level, scaled to -127..=127
+    Int8,
+}
+
+impl WeightEncoding {
+    fn code(&self) -> u8 {
+        match self {
+            WeightEncoding::F32 => 0,
+            WeightEncoding::F16 => 1,
+            WeightEncoding::Int8 => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        [WeightEncoding::F32, WeightEncoding::F16, WeightEncoding::Int8]
+            .into_iter()
+            .find(|e| e.code() == code)
+    }
+}
+
+const ACTIVATIONS: [Activation; 6] = [
+    Activation::Tanh,
+    Activation::Sigmoid,
+    Activation::Relu,
+    Activation::LeakyRelu,
+    Activation::Linear,
+    Activation::Softmax,
+];
+
+const KINDS: [LevelKind; 3] = [LevelKind::Dense, LevelKind::Elman, LevelKind::Gru];
+
+/// Whether `bytes` start like a binary brain file rather than a JSON one.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Encodes `networks`, which share `metadata`, as
+///
+/// - the magic bytes, the version as a u16 and the weight encoding as a u8
+/// - the metadata as JSON, after its length as a u32
+/// - the network count as a u32, then for every network its level count as
+///   a u32 and for every level its input and output counts as u32, its
+///   activation and kind as u8, its biases and its weights row by row
+/// - a CRC-32 of everything before it
+///
+/// Numbers are little endian. Only the weights and biases are stored, none
+/// of the scratch buffers of the levels.
+pub fn encode(
+    metadata: &BrainMetadata,
+    networks: &[NeuralNetwork],
+    encoding: WeightEncoding,
+) -> Vec<u8> {
+    let metadata = serde_json::to_vec(metadata).expect("metadata serializes");
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(BINARY_VERSION.to_le_bytes());
+    bytes.push(encoding.code());
+    bytes.extend((metadata.len() as u32).to_le_bytes());
+    bytes.extend(metadata);
+    bytes.extend((networks.len() as u32).to_le_bytes());
+    for network in networks {
+        bytes.extend((network.levels.len() as u32).to_le_bytes());
+        for level in network.levels.iter() {
+            bytes.extend((level.input_count() as u32).to_le_bytes());
+            bytes.extend((level.outputs.len() as u32).to_le_bytes());
+            bytes.push(ACTIVATIONS.iter().position(|&a| a == level.activation).unwrap() as u8);
+            bytes.push(KINDS.iter().position(|&k| k == level.kind).unwrap() as u8);
+            encode_values(&mut bytes, &level.biases, encoding);
+            for row in level.rows() {
+                encode_values(&mut bytes, row, encoding);
+            }
+        }
+    }
+    let checksum = crc32(&bytes);
+    bytes.extend(checksum.to_le_bytes());
+    bytes
+}
+
+/// Reverses `encode`, checking the checksum first.
+pub fn decode(
+    bytes: &[u8],
+) -> Result<(BrainMetadata, Vec<NeuralNetwork>, WeightEncoding), BrainError> {
+    if !is_binary(bytes) {
+        return Err(BrainError::Binary("not a binary brain file".to_string()));
+    }
+    let (content, checksum) = bytes
+        .split_last_chunk::<4>()
+        .ok_or_else(|| BrainError::Binary("truncated".to_string()))?;
+    let stored = u32::from_le_bytes(*checksum);
+    let computed = crc32(content);
+    if stored != computed {
+        return Err(BrainError::ChecksumMismatch { stored, computed });
+    }
+
+    let mut reader = Reader {
+        bytes: &content[MAGIC.len()..],
+    };
+    let version = reader.u16()?;
+    if version != BINARY_VERSION {
+        return Err(BrainError::UnsupportedBinaryVersion(version));
+    }
+    let encoding = WeightEncoding::from_code(reader.u8()?)
+        .ok_or_else(|| BrainError::Binary("unknown weight encoding".to_string()))?;
+    let metadata_len = reader.u32()? as usize;
+    let metadata: BrainMetadata = serde_json::from_slice(reader.take(metadata_len)?)?;
+
+    let network_count = reader.u32()?;
+    let mut networks = vec![];
+    for _ in 0..network_count {
+        let level_count = reader.u32()?;
+        let mut levels = vec![];
+        for _ in 0..level_count {
+            let input_count = reader.u32()?;
+            let output_count = reader.u32()?;
+            let activation = *ACTIVATIONS
+                .get(reader.u8()? as usize)
+                .ok_or_else(|| BrainError::Binary("unknown activation".to_string()))?;
+            let kind = *KINDS
+                .get(reader.u8()? as usize)
+                .ok_or_else(|| BrainError::Binary("unknown level kind".to_string()))?;
+            // counts are only trusted once the values they imply are there
+            encoded_level_len(input_count as usize, output_count as usize, kind, encoding)
+                .filter(|&len| len <= reader.bytes.len())
+                .ok_or_else(|| BrainError::Binary("truncated".to_string()))?;
+            let mut level = Level::with_kind(input_count, output_count, kind);
+            level.activation = activation;
+            let width = level.row_width();
+            reader.values(&mut level.biases, encoding)?;
+            for row in level.weights.chunks_exact_mut(width.max(1)) {
+                reader.values(row, encoding)?;
+            }
+            levels.push(level);
+        }
+        networks.push(NeuralNetwork {
+            levels,
+            control: metadata.control,
+        });
+    }
+    if !reader.bytes.is_empty() {
+        return Err(BrainError::Binary(format!(
+            "{} bytes after the last network",
+            reader.bytes.len()
+        )));
+    }
+    Ok((metadata, networks, encoding))
+}
+
+fn encode_values(bytes: &mut Vec<u8>, values: &[f32], encoding: WeightEncoding) {
+    match encoding {
+        WeightEncoding::F32 => values.iter().for_each(|v| bytes.extend(v.to_le_bytes())),
+        WeightEncoding::F16 => values
+            .iter()
+            .for_each(|&v| bytes.extend(f32_to_f16(v).to_le_bytes())),
+        WeightEncoding::Int8 => {
+            let scale = int8_scale(values);
+            bytes.extend(scale.to_le_bytes());
+            for &v in values {
+                let q = if scale > 0.0 { (v / scale).round() } else { 0.0 };
+                bytes.push(q.clamp(-127.0, 127.0) as i8 as u8);
+            }
+        }
+    }
+}
+
+/// Bytes the biases and weights of a level take, `None` when that overflows.
+fn encoded_level_len(
+    input_count: usize,
+    output_count: usize,
+    kind: LevelKind,
+    encoding: WeightEncoding,
+) -> Option<usize> {
+    let rows = output_count.checked_mul(kind.gate_count())?;
+    let width = match kind {
+        LevelKind::Dense => input_count,
+        LevelKind::Elman | LevelKind::Gru => input_count.checked_add(output_count)?,
+    };
+    let weights = if width == 0 { 0 } else { rows.checked_mul(width)? };
+    match encoding {
+        WeightEncoding::F32 => rows.checked_add(weights)?.checked_mul(4),
+        WeightEncoding::F16 => rows.checked_add(weights)?.checked_mul(2),
+        // a scale before the biases and before every row
+        WeightEncoding::Int8 => {
+            let scales = if width == 0 { 1 } else { rows.checked_add(1)? };
+            scales.checked_mul(4)?.checked_add(rows)?.checked_add(weights)
+        }
+    }
+}
+
+/// Step between two int8 levels, the largest magnitude maps to 127.
+fn int8_scale(values: &[f32]) -> f32 {
+    values.iter().fold(0.0f32, |max, v| max.max(v.abs())) / 127.0
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], BrainError> {
+        if self.bytes.len() < count {
+            return Err(BrainError::Binary("truncated".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, BrainError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BrainError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, BrainError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, BrainError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn values(&mut self, values: &mut [f32], encoding: WeightEncoding) -> Result<(), BrainError> {
+        match encoding {
+            WeightEncoding::F32 => {
+                for v in values.iter_mut() {
+                    *v = self.f32()?;
+                }
+            }
+            WeightEncoding::F16 => {
+                for v in values.iter_mut() {
+                    *v = f16_to_f32(self.u16()?);
+                }
+            }
+            WeightEncoding::Int8 => {
+                let scale = self.f32()?;
+                let quantized = self.take(values.len())?;
+                for (v, &q) in values.iter_mut().zip(quantized) {
+                    *v = q as i8 as f32 * scale;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// IEEE 754 half precision, rounded to nearest even.
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // infinity stays infinity, nan stays nan
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let (half, rest, halfway) = if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // subnormal, the implicit leading bit becomes explicit
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        (mantissa >> shift, mantissa & ((1 << shift) - 1), 1 << (shift - 1))
+    } else {
+        (((exponent as u32) << 10) | (mantissa >> 13), mantissa & 0x1fff, 0x1000)
+    };
+    // a carry out of the mantissa correctly bumps the exponent
+    let rounded = if rest > halfway || (rest == halfway && half & 1 == 1) {
+        half + 1
+    } else {
+        half
+    };
+    sign | rounded as u16
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    match exponent {
+        0 => {
+            let value = mantissa as f32 * 2f32.powi(-24);
+            if sign != 0 {
+                -value
+            } else {
+                value
+            }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+    }
+}
+
+/// CRC-32 as used by zip and png.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::brain::BrainFile;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn random_brains(count: usize) -> Vec<NeuralNetwork> {
+        let mut rng = StdRng::seed_from_u64(20);
+        (0..count)
+            .map(|i| {
+                let kinds = if i % 2 == 0 {
+                    [LevelKind::Dense, LevelKind::Dense, LevelKind::Dense]
+                } else {
+                    [LevelKind::Dense, LevelKind::Gru, LevelKind::Dense]
+                };
+                let mut brain = NeuralNetwork::with_kinds(&[64, 64, 64, 4], &kinds);
+                brain.levels[2].activation = Activation::Softmax;
+                brain.randomize(&mut rng);
+                brain
+            })
+            .collect()
+    }
+
+    fn max_error(a: &NeuralNetwork, b: &NeuralNetwork) -> f32 {
+        a.levels
+            .iter()
+            .zip(&b.levels)
+            .flat_map(|(a, b)| {
+                let weights = a.weights.iter().zip(&b.weights);
+                weights.chain(a.biases.iter().zip(&b.biases))
+            })
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn round_trips_every_encoding() {
+        let brains = random_brains(3);
+        let metadata = BrainFile::new(brains[0].clone(), vec![], vec![]).metadata;
+        for (encoding, tolerance) in [
+            (WeightEncoding::F32, 0.0),
+            (WeightEncoding::F16, 1e-3),
+            (WeightEncoding::Int8, 0.5 / 127.0),
+        ] {
+            let bytes = encode(&metadata, &brains, encoding);
+            let (loaded_metadata, loaded, loaded_encoding) = decode(&bytes).unwrap();
+            assert_eq!(loaded_metadata, metadata);
+            assert_eq!(loaded_encoding, encoding);
+            assert_eq!(loaded.len(), brains.len());
+            for (brain, loaded) in brains.iter().zip(&loaded) {
+                assert_eq!(loaded.topology(), brain.topology());
+                assert_eq!(loaded.kinds(), brain.kinds());
+                assert_eq!(loaded.activations(), brain.activations());
+                let error = max_error(brain, loaded);
+                assert!(error <= tolerance, "{:?} is off by {}", encoding, error);
+            }
+        }
+
+        let json = serde_json::to_vec(&BrainFile::new(brains[0].clone(), vec![], vec![])).unwrap();
+        let f16 = encode(&metadata, &brains[..1], WeightEncoding::F16);
+        let int8 = encode(&metadata, &brains[..1], WeightEncoding::Int8);
+        assert!(f16.len() * 4 < json.len(), "{} vs {}", f16.len(), json.len());
+        assert!(int8.len() * 5 < f16.len() * 3, "{} vs {}", int8.len(), f16.len());
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let brains = random_brains(1);
+        let metadata = BrainFile::new(brains[0].clone(), vec![], vec![]).metadata;
+        let mut bytes = encode(&metadata, &brains, WeightEncoding::F32);
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0x10;
+        assert!(matches!(
+            decode(&bytes),
+            Err(BrainError::ChecksumMismatch { .. })
+        ));
+        assert!(decode(&bytes[..10]).is_err());
+        assert!(decode(b"{}").is_err());
+    }
+
+    #[test]
+    fn checks_level_sizes_before_allocating() {
+        let brains = random_brains(1);
+        let metadata = BrainFile::new(brains[0].clone(), vec![], vec![]).metadata;
+        for encoding in [WeightEncoding::F32, WeightEncoding::F16, WeightEncoding::Int8] {
+            let bytes = encode(&metadata, &brains, encoding);
+            // the exact sizes pass, so they are not overestimated
+            assert!(decode(&bytes).is_ok());
+
+            // a file with a valid checksum claiming a huge first level
+            let metadata_len = serde_json::to_vec(&metadata).unwrap().len();
+            let input_count = MAGIC.len() + 2 + 1 + 4 + metadata_len + 4 + 4;
+            let mut crafted = bytes[..bytes.len() - 4].to_vec();
+            crafted[input_count..input_count + 8].copy_from_slice(&[0xff; 8]);
+            let checksum = crc32(&crafted);
+            crafted.extend(checksum.to_le_bytes());
+            assert!(matches!(decode(&crafted), Err(BrainError::Binary(_))));
+        }
+    }
+
+    #[test]
+    fn converts_half_precision() {
+        for (value, half) in [
+            (0.0, 0x0000),
+            (1.0, 0x3c00),
+            (-2.0, 0xc000),
+            (0.1, 0x2e66),
+            (65504.0, 0x7bff),
+            (1e6, 0x7c00),
+            (2f32.powi(-24), 0x0001),
+            (1e-9, 0x0000),
+        ] {
+            assert_eq!(f32_to_f16(value), half, "{}", value);
+        }
+        for half in [0x0001, 0x03ff, 0x3c00, 0xc000, 0x7bff, 0x2e66] {
+            assert_eq!(f32_to_f16(f16_to_f32(half)), half);
+        }
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,841 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::binary::{self, WeightEncoding};
+use crate::car::ControlMode;
+use crate::neat::Genome;
+use crate::network::{Activation, NeuralNetwork};
+use crate::observation::{self, Feature};
+use crate::sensor::SensorLayout;
+
+/// Version written by `BrainFile::save`. Version 1 is the bare network the
+/// first releases wrote, without any envelope, version 2 had a single
+/// activation for the whole network.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Version written by `GenomeFile::save`.
+pub const GENOME_FORMAT_VERSION: u32 = 1;
+
+/// What drives a car: a dense network or a genome grown by NEAT.
+#[derive(Clone)]
+pub enum Brain {
+    Dense(NeuralNetwork),
+    Neat(Genome),
+}
+
+impl Brain {
+    /// Reads a brain, binary or JSON, or a genome file and checks it fits
+    /// `sensors` and `features`, the sensors are also assumed for brain
+    /// files too old to record theirs.
+    pub fn load(
+        path: &str,
+        sensors: &[SensorLayout],
+        features: &[Feature],
+    ) -> Result<Self, BrainError> {
+        let bytes = fs::read(path)?;
+        if binary::is_binary(&bytes) {
+            let file = BrainFile::from_bytes(&bytes)?;
+            file.validate(sensors, features)?;
+            return Ok(Brain::Dense(file.network));
+        }
+        let value: Value = serde_json::from_slice(&bytes)?;
+        if value.get("genome").is_some() {
+            let file = GenomeFile::from_value(value)?;
+            file.validate(sensors, features)?;
+            Ok(Brain::Neat(file.genome))
+        } else {
+            let file = BrainFile::from_value(value, sensors)?;
+            file.validate(sensors, features)?;
+            Ok(Brain::Dense(file.network))
+        }
+    }
+
+    pub fn feed_forward(&mut self, inputs: &[f32]) -> &[f32] {
+        match self {
+            Brain::Dense(network) => network.feed_forward(inputs),
+            Brain::Neat(genome) => genome.feed_forward(inputs),
+        }
+    }
+
+    pub fn as_dense(&self) -> Option<&NeuralNetwork> {
+        match self {
+            Brain::Dense(network) => Some(network),
+            Brain::Neat(_) => None,
+        }
+    }
+
+    pub fn as_genome(&self) -> Option<&Genome> {
+        match self {
+            Brain::Dense(_) => None,
+            Brain::Neat(genome) => Some(genome),
+        }
+    }
+
+    /// The network, if it can be evaluated in a batch with others: dense
+    /// and without recurrent levels.
+    pub fn as_batchable(&self) -> Option<&NeuralNetwork> {
+        self.as_dense().filter(|n| !n.is_recurrent())
+    }
+
+    /// How a car reads the outputs, genomes only press the controls.
+    pub fn control_mode(&self) -> ControlMode {
+        match self {
+            Brain::Dense(network) => network.control,
+            Brain::Neat(_) => ControlMode::Discrete,
+        }
+    }
+
+    /// Forgets what a recurrent brain remembers of earlier inputs.
+    pub fn reset_state(&mut self) {
+        if let Brain::Dense(network) = self {
+            network.reset_state();
+        }
+    }
+}
+impl From<NeuralNetwork> for Brain {
+    fn from(network: NeuralNetwork) -> Self {
+        Brain::Dense(network)
+    }
+}
+impl From<Genome> for Brain {
+    fn from(genome: Genome) -> Self {
+        Brain::Neat(genome)
+    }
+}
+
+/// A brain as stored on disk: the network and what it was trained with.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BrainFile {
+    pub version: u32,
+    pub metadata: BrainMetadata,
+    pub network: NeuralNetwork,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BrainMetadata {
+    /// neuron count of every layer, inputs first
+    pub topology: Vec<u32>,
+    /// activation of every level
+    pub activations: Vec<Activation>,
+    /// sensors whose readings feed the inputs, in order
+    pub sensors: Vec<SensorLayout>,
+    /// features of the car state fed after the sensor readings, in order,
+    /// none for brains saved before they existed
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    /// how cars read the outputs, discrete for brains saved before analog
+    /// controls existed
+    #[serde(default)]
+    pub control: ControlMode,
+    pub generation: Option<u32>,
+    pub fitness: Option<f64>,
+    pub seed: Option<u64>,
+    /// seconds since the unix epoch
+    pub saved_at: u64,
+}
+
+/// A NEAT genome as stored on disk, its topology is part of the genome.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GenomeFile {
+    pub version: u32,
+    pub metadata: GenomeMetadata,
+    pub genome: Genome,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GenomeMetadata {
+    /// sensors whose readings feed the inputs, in order
+    pub sensors: Vec<SensorLayout>,
+    /// features of the car state fed after the sensor readings, in order
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    pub generation: Option<u32>,
+    pub fitness: Option<f64>,
+    pub seed: Option<u64>,
+    /// seconds since the unix epoch
+    pub saved_at: u64,
+}
+
+#[derive(Debug)]
+pub enum BrainError {
+    NotFound,
+    PermissionDenied,
+    Io(io::Error),
+    Parse(serde_json::Error),
+    UnsupportedVersion(Value),
+    UnsupportedGenomeVersion(Value),
+    UnsupportedBinaryVersion(u16),
+    /// a binary brain that is truncated or holds values out of range
+    Binary(String),
+    ChecksumMismatch {
+        stored: u32,
+        computed: u32,
+    },
+    /// a binary file holding several networks where one was expected
+    NetworkCount(usize),
+    /// a level does not take as many inputs as the level before it outputs
+    LevelMismatch {
+        level: usize,
+        inputs: usize,
+        outputs: usize,
+    },
+    /// the network does not have the topology its metadata claims
+    TopologyMismatch {
+        metadata: Vec<u32>,
+        network: Vec<u32>,
+    },
+    ActivationMismatch {
+        metadata: Vec<Activation>,
+        network: Vec<Activation>,
+    },
+    SensorMismatch {
+        brain: Vec<SensorLayout>,
+        car: Vec<SensorLayout>,
+    },
+    FeatureMismatch {
+        brain: Vec<Feature>,
+        car: Vec<Feature>,
+    },
+    InputMismatch {
+        inputs: Option<u32>,
+        rays: u32,
+        features: usize,
+    },
+    OutputMismatch {
+        outputs: Option<u32>,
+    },
+}
+impl fmt::Display for BrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrainError::NotFound => write!(f, "file not found"),
+            BrainError::PermissionDenied => write!(f, "permission denied"),
+            BrainError::Io(e) => write!(f, "{}", e),
+            BrainError::Parse(e) => write!(f, "malformed brain file: {}", e),
+            BrainError::UnsupportedVersion(version) => write!(
+                f,
+                "format version {} is not supported, the newest known is {}",
+                version, FORMAT_VERSION
+            ),
+            BrainError::UnsupportedGenomeVersion(version) => write!(
+                f,
+                "genome format version {} is not supported, the newest known is {}",
+                version, GENOME_FORMAT_VERSION
+            ),
+            BrainError::UnsupportedBinaryVersion(version) => write!(
+                f,
+                "binary format version {} is not supported, the newest known is {}",
+                version,
+                binary::BINARY_VERSION
+            ),
+            BrainError::Binary(reason) => write!(f, "malformed binary brain: {}", reason),
+            BrainError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "binary brain is corrupt, its checksum is {:08x} but its content sums to {:08x}",
+                stored, computed
+            ),
+            BrainError::NetworkCount(count) => {
+                write!(f, "binary file holds {} networks instead of one", count)
+            }
+            BrainError::LevelMismatch {
+                level,
+                inputs,
+                outputs,
+            } => write!(
+                f,
+                "level {} takes {} inputs but the level before it has {} outputs",
+                level, inputs, outputs
+            ),
+            BrainError::TopologyMismatch { metadata, network } => write!(
+                f,
+                "network has topology {:?} but its metadata says {:?}",
+                network, metadata
+            ),
+            BrainError::ActivationMismatch { metadata, network } => write!(
+                f,
+                "network has activations {:?} but its metadata says {:?}",
+                network, metadata
+            ),
+            BrainError::SensorMismatch { brain, car } => write!(
+                f,
+                "brain was trained with sensors {:?} but the car has {:?}",
+                brain, car
+            ),
+            BrainError::FeatureMismatch { brain, car } => write!(
+                f,
+                "brain was trained with features {:?} but the car observes {:?}, see [observation]",
+                brain, car
+            ),
+            BrainError::InputMismatch {
+                inputs,
+                rays,
+                features,
+            } => write!(
+                f,
+                "brain expects {:?} inputs but the car has {} sensor rays and {} features",
+                inputs, rays, features
+            ),
+            BrainError::OutputMismatch { outputs } => {
+                write!(
+                    f,
+                    "brain has {:?} outputs but a car has 4 controls",
+                    outputs
+                )
+            }
+        }
+    }
+}
+impl std::error::Error for BrainError {}
+impl From<io::Error> for BrainError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => BrainError::NotFound,
+            io::ErrorKind::PermissionDenied => BrainError::PermissionDenied,
+            _ => BrainError::Io(e),
+        }
+    }
+}
+impl From<serde_json::Error> for BrainError {
+    fn from(e: serde_json::Error) -> Self {
+        BrainError::Parse(e)
+    }
+}
+
+impl BrainFile {
+    pub fn new(network: NeuralNetwork, sensors: Vec<SensorLayout>, features: Vec<Feature>) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            metadata: BrainMetadata {
+                topology: network.topology(),
+                activations: network.activations(),
+                sensors,
+                features,
+                control: network.control,
+                generation: None,
+                fitness: None,
+                seed: None,
+                saved_at: 0,
+            },
+            network,
+        }
+    }
+
+    /// Writes the brain next to `path` first and renames it over `path`
+    /// once complete, so an interrupted save leaves the previous file intact.
+    /// Missing directories are created.
+    pub fn save(&mut self, path: &str) -> Result<(), BrainError> {
+        self.metadata.saved_at = now();
+        write_atomically(path, serde_json::to_string(&self)?.as_bytes())
+    }
+
+    /// Like `save` but in the binary format, see `binary::encode`.
+    pub fn save_binary(&mut self, path: &str, encoding: WeightEncoding) -> Result<(), BrainError> {
+        self.metadata.saved_at = now();
+        write_atomically(path, &self.to_bytes(encoding))
+    }
+
+    /// Reads a binary brain file or a JSON one of any known version,
+    /// migrating older ones. `legacy_sensors` is assumed for version 1 files,
+    /// which did not record the sensors they were trained with.
+    pub fn load(path: &str, legacy_sensors: &[SensorLayout]) -> Result<Self, BrainError> {
+        let bytes = fs::read(path)?;
+        if binary::is_binary(&bytes) {
+            return Self::from_bytes(&bytes);
+        }
+        let json =
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Self::from_json(&json, legacy_sensors)
+    }
+
+    pub fn to_bytes(&self, encoding: WeightEncoding) -> Vec<u8> {
+        binary::encode(&self.metadata, std::slice::from_ref(&self.network), encoding)
+    }
+
+    /// Reads a binary brain holding a single network. Quantized weights come
+    /// back as the nearest `f32`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BrainError> {
+        let (metadata, mut networks, _) = binary::decode(bytes)?;
+        if networks.len() != 1 {
+            return Err(BrainError::NetworkCount(networks.len()));
+        }
+        let network = networks.remove(0);
+        check_levels(&network)?;
+        Ok(Self {
+            version: FORMAT_VERSION,
+            metadata,
+            network,
+        })
+    }
+
+    pub fn from_json(json: &str, legacy_sensors: &[SensorLayout]) -> Result<Self, BrainError> {
+        Self::from_value(serde_json::from_str(json)?, legacy_sensors)
+    }
+
+    pub fn from_value(value: Value, legacy_sensors: &[SensorLayout]) -> Result<Self, BrainError> {
+        let version = match value.get("version") {
+            Some(v) => v
+                .as_u64()
+                .ok_or_else(|| BrainError::UnsupportedVersion(v.clone()))?,
+            None => 1,
+        };
+        let mut file = match version {
+            1 => {
+                let network: NeuralNetwork = serde_json::from_value(value)?;
+                BrainFile::new(network, legacy_sensors.to_vec(), vec![])
+            }
+            2 => {
+                // every level used tanh, which is also what they deserialize to
+                let mut value = value;
+                if let Some(metadata) = value.get_mut("metadata").and_then(Value::as_object_mut) {
+                    metadata.remove("activation");
+                    metadata.insert("activations".to_string(), Value::Array(vec![]));
+                }
+                let mut file: BrainFile = serde_json::from_value(value)?;
+                file.metadata.activations = file.network.activations();
+                file
+            }
+            v if v == FORMAT_VERSION as u64 => serde_json::from_value(value)?,
+            v => return Err(BrainError::UnsupportedVersion(v.into())),
+        };
+        file.version = FORMAT_VERSION;
+        file.network.control = file.metadata.control;
+        check_levels(&file.network)?;
+        Ok(file)
+    }
+
+    /// Checks the network matches its metadata and what the car it is meant
+    /// for observes.
+    pub fn validate(
+        &self,
+        sensors: &[SensorLayout],
+        features: &[Feature],
+    ) -> Result<(), BrainError> {
+        check_network(&self.metadata, &self.network, sensors, features)
+    }
+}
+
+/// Every brain of a population sharing one metadata, as stored to resume
+/// training. Always binary, since it holds hundreds of brains.
+pub struct PopulationFile {
+    pub metadata: BrainMetadata,
+    pub networks: Vec<NeuralNetwork>,
+}
+
+impl PopulationFile {
+    /// `networks` must not be empty and all share the topology of the first.
+    pub fn new(
+        networks: Vec<NeuralNetwork>,
+        sensors: Vec<SensorLayout>,
+        features: Vec<Feature>,
+    ) -> Self {
+        let metadata = BrainFile::new(networks[0].clone(), sensors, features).metadata;
+        Self { metadata, networks }
+    }
+
+    /// Like `BrainFile::save_binary`.
+    pub fn save(&mut self, path: &str, encoding: WeightEncoding) -> Result<(), BrainError> {
+        self.metadata.saved_at = now();
+        write_atomically(path, &self.to_bytes(encoding))
+    }
+
+    pub fn load(path: &str) -> Result<Self, BrainError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn to_bytes(&self, encoding: WeightEncoding) -> Vec<u8> {
+        binary::encode(&self.metadata, &self.networks, encoding)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BrainError> {
+        let (metadata, networks, _) = binary::decode(bytes)?;
+        if networks.is_empty() {
+            return Err(BrainError::Binary("population without brains".to_string()));
+        }
+        for network in networks.iter() {
+            check_levels(network)?;
+        }
+        Ok(Self { metadata, networks })
+    }
+
+    /// Like `BrainFile::validate`, for every network.
+    pub fn validate(
+        &self,
+        sensors: &[SensorLayout],
+        features: &[Feature],
+    ) -> Result<(), BrainError> {
+        self.networks
+            .iter()
+            .try_for_each(|n| check_network(&self.metadata, n, sensors, features))
+    }
+}
+
+fn check_network(
+    metadata: &BrainMetadata,
+    network: &NeuralNetwork,
+    sensors: &[SensorLayout],
+    features: &[Feature],
+) -> Result<(), BrainError> {
+    let topology = network.topology();
+    if topology != metadata.topology {
+        return Err(BrainError::TopologyMismatch {
+            metadata: metadata.topology.clone(),
+            network: topology,
+        });
+    }
+    let activations = network.activations();
+    if activations != metadata.activations {
+        return Err(BrainError::ActivationMismatch {
+            metadata: metadata.activations.clone(),
+            network: activations,
+        });
+    }
+    check_observation(&metadata.sensors, &metadata.features, sensors, features)?;
+    if topology.first() != Some(&observation::input_count(sensors, features)) {
+        return Err(input_mismatch(topology.first().copied(), sensors, features));
+    }
+    if topology.last() != Some(&4) {
+        return Err(BrainError::OutputMismatch {
+            outputs: topology.last().copied(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks every level takes the outputs of the one before it, which nothing
+/// else about a network read from a file guarantees.
+fn check_levels(network: &NeuralNetwork) -> Result<(), BrainError> {
+    for (i, pair) in network.levels.windows(2).enumerate() {
+        if pair[1].input_count() != pair[0].outputs.len() {
+            return Err(BrainError::LevelMismatch {
+                level: i + 1,
+                inputs: pair[1].input_count(),
+                outputs: pair[0].outputs.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+impl GenomeFile {
+    pub fn new(genome: Genome, sensors: Vec<SensorLayout>, features: Vec<Feature>) -> Self {
+        Self {
+            version: GENOME_FORMAT_VERSION,
+            metadata: GenomeMetadata {
+                sensors,
+                features,
+                generation: None,
+                fitness: None,
+                seed: None,
+                saved_at: 0,
+            },
+            genome,
+        }
+    }
+
+    /// Written atomically like `BrainFile::save`.
+    pub fn save(&mut self, path: &str) -> Result<(), BrainError> {
+        self.metadata.saved_at = now();
+        write_atomically(path, serde_json::to_string(&self)?.as_bytes())
+    }
+
+    pub fn from_value(value: Value) -> Result<Self, BrainError> {
+        match value.get("version") {
+            Some(v) if v.as_u64() == Some(GENOME_FORMAT_VERSION as u64) => {
+                Ok(serde_json::from_value(value)?)
+            }
+            v => Err(BrainError::UnsupportedGenomeVersion(
+                v.cloned().unwrap_or(Value::Null),
+            )),
+        }
+    }
+
+    /// Checks the genome fits what the car it is meant for observes and its controls.
+    pub fn validate(
+        &self,
+        sensors: &[SensorLayout],
+        features: &[Feature],
+    ) -> Result<(), BrainError> {
+        check_observation(&self.metadata.sensors, &self.metadata.features, sensors, features)?;
+        let inputs = self.genome.input_count() as u32;
+        if inputs != observation::input_count(sensors, features) {
+            return Err(input_mismatch(Some(inputs), sensors, features));
+        }
+        if self.genome.output_count() != 4 {
+            return Err(BrainError::OutputMismatch {
+                outputs: Some(self.genome.output_count() as u32),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Checks a brain was trained with what the car observes.
+fn check_observation(
+    brain_sensors: &[SensorLayout],
+    brain_features: &[Feature],
+    sensors: &[SensorLayout],
+    features: &[Feature],
+) -> Result<(), BrainError> {
+    if brain_sensors != sensors {
+        return Err(BrainError::SensorMismatch {
+            brain: brain_sensors.to_vec(),
+            car: sensors.to_vec(),
+        });
+    }
+    if brain_features != features {
+        return Err(BrainError::FeatureMismatch {
+            brain: brain_features.to_vec(),
+            car: features.to_vec(),
+        });
+    }
+    Ok(())
+}
+
+fn input_mismatch(
+    inputs: Option<u32>,
+    sensors: &[SensorLayout],
+    features: &[Feature],
+) -> BrainError {
+    BrainError::InputMismatch {
+        inputs,
+        rays: observation::input_count(sensors, &[]),
+        features: features.len(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Writes `contents` next to `path` first and renames it over `path` once
+/// complete, creating missing directories.
+pub fn write_atomically(path: &str, contents: &[u8]) -> Result<(), BrainError> {
+    let path = Path::new(path);
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let write = || -> io::Result<()> {
+        let mut tmp = fs::File::create(&tmp_path)?;
+        tmp.write_all(contents)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        e.into()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::car;
+    use crate::network::Level;
+
+    #[test]
+    fn migrates_bare_networks() {
+        let network = NeuralNetwork::new(&car::brain_topology(&[]));
+        let legacy = serde_json::json!({ "levels": network.levels.iter().map(|l| {
+            serde_json::json!({
+                "inputs": vec![0.0; l.input_count()],
+                "outputs": l.outputs,
+                "biases": l.biases,
+                "weights": l.rows().collect::<Vec<_>>(),
+            })
+        }).collect::<Vec<_>>() });
+
+        let file = BrainFile::from_json(&legacy.to_string(), &car::SENSOR_LAYOUT).unwrap();
+        assert_eq!(file.version, FORMAT_VERSION);
+        assert_eq!(file.metadata.topology, car::brain_topology(&[]));
+        file.validate(&car::SENSOR_LAYOUT, &[]).unwrap();
+    }
+
+    #[test]
+    fn round_trips_and_checks_sensors() {
+        let features = [Feature::Velocity, Feature::LaneOffset];
+        let mut activations = vec![Activation::LeakyRelu; car::BRAIN_LAYERS.len()];
+        activations[car::BRAIN_LAYERS.len() - 1] = Activation::Softmax;
+        let mut network =
+            NeuralNetwork::with_activations(&car::brain_topology(&features), &activations);
+        network.control = ControlMode::Analog;
+        let mut file = BrainFile::new(network, car::SENSOR_LAYOUT.to_vec(), features.to_vec());
+        file.metadata.generation = Some(12);
+        file.metadata.fitness = Some(345.5);
+
+        let json = serde_json::to_string(&file).unwrap();
+        assert!(!json.contains("inputs"));
+        let loaded = BrainFile::from_json(&json, &[]).unwrap();
+        assert_eq!(loaded.metadata, file.metadata);
+        assert_eq!(loaded.network.levels[0].input_count(), 66);
+        assert_eq!(loaded.network.activations(), activations);
+        assert_eq!(loaded.network.control, ControlMode::Analog);
+
+        loaded.validate(&car::SENSOR_LAYOUT, &features).unwrap();
+        assert!(matches!(
+            loaded.validate(&car::SENSOR_LAYOUT[..2], &features),
+            Err(BrainError::SensorMismatch { .. })
+        ));
+        assert!(matches!(
+            loaded.validate(&car::SENSOR_LAYOUT, &features[..1]),
+            Err(BrainError::FeatureMismatch { .. })
+        ));
+        // brains saved before features existed observe none
+        let mut value: Value = serde_json::from_str(&json).unwrap();
+        value["metadata"].as_object_mut().unwrap().remove("features");
+        value["metadata"].as_object_mut().unwrap().remove("control");
+        let legacy = BrainFile::from_value(value, &[]).unwrap();
+        assert!(legacy.metadata.features.is_empty());
+        // and press the controls
+        assert_eq!(legacy.network.control, ControlMode::Discrete);
+    }
+
+    #[test]
+    fn rejects_levels_that_do_not_chain() {
+        let mut network = NeuralNetwork::new(&[3, 4, 2]);
+        network.levels[1] = Level::new(5, 2);
+        let file = BrainFile::new(network, vec![], vec![]);
+        let json = serde_json::to_string(&file).unwrap();
+        assert!(matches!(
+            BrainFile::from_json(&json, &[]),
+            Err(BrainError::LevelMismatch {
+                level: 1,
+                inputs: 5,
+                outputs: 4
+            })
+        ));
+        assert!(matches!(
+            BrainFile::from_bytes(&file.to_bytes(WeightEncoding::F32)),
+            Err(BrainError::LevelMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn round_trips_populations() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let dir = std::env::temp_dir().join(format!("car-ai-population-{}", std::process::id()));
+        let path = dir.join("population.bin");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let mut rng = StdRng::seed_from_u64(6);
+        let networks: Vec<NeuralNetwork> = (0..3)
+            .map(|_| {
+                let mut network = NeuralNetwork::new(&car::brain_topology(&[]));
+                network.randomize(&mut rng);
+                network
+            })
+            .collect();
+        let mut file = PopulationFile::new(networks.clone(), car::SENSOR_LAYOUT.to_vec(), vec![]);
+        file.metadata.generation = Some(7);
+        file.save(path, WeightEncoding::F32).unwrap();
+
+        let loaded = PopulationFile::load(path).unwrap();
+        assert_eq!(loaded.metadata, file.metadata);
+        assert_eq!(loaded.networks.len(), networks.len());
+        for (network, loaded) in networks.iter().zip(&loaded.networks) {
+            for (level, loaded) in network.levels.iter().zip(&loaded.levels) {
+                assert_eq!(level.weights, loaded.weights);
+                assert_eq!(level.biases, loaded.biases);
+            }
+        }
+        loaded.validate(&car::SENSOR_LAYOUT, &[]).unwrap();
+        // a population is not a single brain
+        assert!(matches!(
+            BrainFile::load(path, &[]),
+            Err(BrainError::NetworkCount(3))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saves_atomically_into_new_directories() {
+        let dir = std::env::temp_dir().join(format!("car-ai-brain-{}", std::process::id()));
+        let path = dir.join("nested/best.json");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(matches!(
+            BrainFile::load(path, &car::SENSOR_LAYOUT),
+            Err(BrainError::NotFound)
+        ));
+        let mut file = BrainFile::new(NeuralNetwork::new(&[3, 2]), vec![], vec![]);
+        file.save(path).unwrap();
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert_eq!(BrainFile::load(path, &[]).unwrap().metadata, file.metadata);
+
+        fs::write(path, "{ \"version\": 2, ").unwrap();
+        assert!(matches!(
+            BrainFile::load(path, &[]),
+            Err(BrainError::Parse(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_either_kind_of_brain() {
+        use crate::neat::{Innovations, NeatConfig};
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let dir = std::env::temp_dir().join(format!("car-ai-genome-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let inputs = car::brain_topology(&[])[0] as usize;
+        let mut innovations = Innovations::new(inputs, 4);
+        let mut rng = StdRng::seed_from_u64(5);
+        let genome = Genome::new(inputs, 4, &NeatConfig::default(), &mut innovations, &mut rng);
+
+        let genome_path = dir.join("genome.json");
+        let genome_path = genome_path.to_str().unwrap();
+        GenomeFile::new(genome.clone(), car::SENSOR_LAYOUT.to_vec(), vec![])
+            .save(genome_path)
+            .unwrap();
+        let Brain::Neat(loaded) = Brain::load(genome_path, &car::SENSOR_LAYOUT, &[]).unwrap() else {
+            panic!("expected a genome");
+        };
+        assert_eq!(loaded.connections(), genome.connections());
+        assert!(matches!(
+            Brain::load(genome_path, &car::SENSOR_LAYOUT[..1], &[]),
+            Err(BrainError::SensorMismatch { .. })
+        ));
+
+        let brain_path = dir.join("brain.json");
+        let brain_path = brain_path.to_str().unwrap();
+        let network = NeuralNetwork::new(&car::brain_topology(&[]));
+        BrainFile::new(network, car::SENSOR_LAYOUT.to_vec(), vec![])
+            .save(brain_path)
+            .unwrap();
+        assert!(matches!(
+            Brain::load(brain_path, &car::SENSOR_LAYOUT, &[]),
+            Ok(Brain::Dense(_))
+        ));
+
+        let binary_path = dir.join("brain.bin");
+        let binary_path = binary_path.to_str().unwrap();
+        let mut file = BrainFile::load(brain_path, &[]).unwrap();
+        file.save_binary(binary_path, WeightEncoding::F16).unwrap();
+        assert!(fs::metadata(binary_path).unwrap().len() < fs::metadata(brain_path).unwrap().len());
+        assert_eq!(BrainFile::load(binary_path, &[]).unwrap().metadata, file.metadata);
+        assert!(matches!(
+            Brain::load(binary_path, &car::SENSOR_LAYOUT, &[]),
+            Ok(Brain::Dense(_))
+        ));
+        let mut bytes = fs::read(binary_path).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        fs::write(binary_path, bytes).unwrap();
+        assert!(matches!(
+            Brain::load(binary_path, &car::SENSOR_LAYOUT, &[]),
+            Err(BrainError::ChecksumMismatch { .. })
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,85 @@
 use std::f64::consts::PI;
 
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::rect::{FRect, Point, Rect};
 use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
 use sdl2::video::{Window, WindowContext};
+use serde::{Deserialize, Serialize};
 
+use crate::brain::Brain;
+use crate::fitness::StepTelemetry;
 use crate::fns::{get_intersectionf, lerpf32};
-use crate::network::NeuralNetwork;
+use crate::imitation::Sample;
+use crate::network::{LevelKind, NeuralNetwork};
+use crate::observation::{self, CarState, Feature};
 use crate::road::Road;
-use crate::sensor::Sensor;
+use crate::sensor::{Sensor, SensorLayout};
 use crate::texture::{self, SizedTexture, TexturePool};
 use crate::units;
 
+pub const TEXTURE_PATH: &str = "assets/car.png";
+
+/// Sensors every AI car is built with, their readings are the brain inputs in this order.
+pub const SENSOR_LAYOUT: [SensorLayout; 3] = [
+    SensorLayout {
+        ray_count: 36,
+        ray_length: 210.0,
+        ray_spread: PI * 1.8,
+    },
+    SensorLayout {
+        ray_count: 22,
+        ray_length: 380.0,
+        ray_spread: PI * 0.3,
+    },
+    SensorLayout {
+        ray_count: 6,
+        ray_length: 560.0,
+        ray_spread: PI * 0.15,
+    },
+];
+
+/// Brain outputs above this press their control.
+pub const CONTROL_THRESHOLD: f32 = 0.33;
+
+/// Control pressed by every brain output, in order.
+pub const CONTROL_NAMES: [&str; 4] = ["forward", "backward", "left", "right"];
+
+/// How a car turns the four outputs of a brain into controls.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlMode {
+    /// every output above `CONTROL_THRESHOLD` presses its control all the way
+    #[default]
+    Discrete,
+    /// the first two outputs press the throttle and the brake as far as they
+    /// go in [0, 1], the right output minus the left one is the target
+    /// steering angle over its maximum
+    Analog,
+}
+
+/// Neuron count of every level of a new brain, inputs excluded.
+pub const BRAIN_LAYERS: [u32; 9] = [64, 64, 64, 64, 64, 64, 64, 64, 4];
+
+/// Neuron count of every layer of the brain of an AI car observing `features`.
+pub fn brain_topology(features: &[Feature]) -> Vec<u32> {
+    let inputs = observation::input_count(&SENSOR_LAYOUT, features);
+    std::iter::once(inputs).chain(BRAIN_LAYERS).collect()
+}
+
+/// Kind of every level of a new brain, all dense but the last hidden level
+/// which is of kind `memory` when set.
+pub fn brain_kinds(memory: Option<LevelKind>) -> Vec<LevelKind> {
+    let mut kinds = vec![LevelKind::Dense; BRAIN_LAYERS.len()];
+    if let Some(memory) = memory {
+        kinds[BRAIN_LAYERS.len() - 2] = memory;
+    }
+    kinds
+}
+
 pub struct Car {
     dimentions: Dimentions,
     pub position: Position,
@@ -23,18 +88,36 @@ pub struct Car {
     controls: Controls,
     pub damaged: bool,
     dummy: bool,
-    pub brain: Option<NeuralNetwork>,
+    pub brain: Option<Brain>,
     src_rect: Option<Rect>,
-    pub score: i64,
+    /// accumulated by the simulation from `telemetry`
+    pub fitness: f64,
+    /// what happened during the last update
+    pub telemetry: StepTelemetry,
     changing_lane: bool,
     break_checking: bool,
     break_checking_frame_count: u32,
     target_lane: u32,
     current_lane: u32,
     hitbox: Vec<Point>,
+    /// appended to the sensor readings, in order
+    features: Vec<Feature>,
+    /// the readings of every sensor followed by the features, what the
+    /// brain is fed
     sensor_readings: Vec<f32>,
     pub did_just_crashed: bool,
     close_to_lane_center: bool,
+    rng: StdRng,
+    step_start: StepStart,
+}
+
+/// State at the start of an update the telemetry of the step is measured against.
+#[derive(Clone, Copy, Default)]
+struct StepStart {
+    damaged: bool,
+    y: f32,
+    steering_angle: f32,
+    traffic_ahead: usize,
 }
 
 impl Car {
@@ -42,40 +125,28 @@ impl Car {
         current_lane: u32,
         texture_width: u32,
         texture_height: u32,
+        features: &[Feature],
         ref_brain: Option<&NeuralNetwork>,
         t: f64,
+        rng: &mut impl Rng,
     ) -> Self {
         let dimentions = Dimentions::new(texture_width, texture_height, 1.0);
         let position = Position::new(400.0, 600.0, 0.0);
         let motion = Motion::new(0.0, 33.33, 1.8, 0.05, 70.0, 220.0);
         let controls = Controls::new();
 
-        let sensors = vec![
-            Sensor::new(
-                36,
-                210.0,
-                PI * 1.8,
-                dimentions.w as u16,
-                dimentions.h as u16,
-            ),
-            Sensor::new(
-                22,
-                380.0,
-                PI * 0.3,
-                dimentions.w as u16,
-                dimentions.h as u16,
-            ),
-            Sensor::new(
-                6,
-                560.0,
-                PI * 0.15,
-                dimentions.w as u16,
-                dimentions.h as u16,
-            ),
-        ];
-        let total_sensors = sensors.iter().map(|s| s.rays.len() as u32).sum();
-        let mut brain = NeuralNetwork::new(&[total_sensors, 64, 64, 64, 64, 64, 64, 64, 64, 4]);
-        brain.randomize();
+        let sensors: Vec<Sensor> = SENSOR_LAYOUT
+            .iter()
+            .map(|&layout| Sensor::from_layout(layout, dimentions.w as u16, dimentions.h as u16))
+            .collect();
+        let inputs = observation::input_count(&SENSOR_LAYOUT, features);
+        // shaped like the reference brain, which may be recurrent
+        let mut brain = match ref_brain {
+            Some(ref_brain) => ref_brain.clone(),
+            None => NeuralNetwork::new(&brain_topology(features)),
+        };
+        brain.randomize(rng);
+        brain.reset_state();
 
         if ref_brain.is_some() {
             brain.prune(ref_brain.unwrap(), t as f32);
@@ -89,18 +160,22 @@ impl Car {
             damaged: false,
             src_rect: None,
             dummy: false,
-            brain: Some(brain),
+            brain: Some(Brain::Dense(brain)),
             sensors,
-            score: 0,
+            fitness: 0.0,
+            telemetry: StepTelemetry::default(),
             target_lane: current_lane,
             current_lane,
             changing_lane: false,
             break_checking: false,
             break_checking_frame_count: 0,
             hitbox: vec![],
-            sensor_readings: vec![0.0; total_sensors as usize],
+            features: features.to_vec(),
+            sensor_readings: vec![0.0; inputs as usize],
             did_just_crashed: false,
             close_to_lane_center: true,
+            rng: StdRng::seed_from_u64(rng.gen()),
+            step_start: StepStart::default(),
         }
     }
 
@@ -147,11 +222,15 @@ impl Car {
         y - scaled_h > (h as f32)
     }
 
-    pub fn reset(&mut self, y: f32, road: &Road, ref_brain: Option<&NeuralNetwork>) {
-        let lane = road.random_lane_idx();
+    pub fn reset(&mut self, y: f32, road: &Road, rng: &mut impl Rng) {
+        let lane = road.random_lane_idx(rng);
         self.damaged = false;
         self.did_just_crashed = false;
-        self.score = 0;
+        self.fitness = 0.0;
+        self.telemetry = StepTelemetry::default();
+        if let Some(brain) = self.brain.as_mut() {
+            brain.reset_state();
+        }
 
         self.current_lane = lane;
         self.target_lane = lane;
@@ -163,31 +242,29 @@ impl Car {
         self.controls.forward = true;
         self.motion.velocity = self.motion.max_velocity * 0.75;
         self.motion.steering_angle = 0.0;
-        if self.brain.is_some() && ref_brain.is_some() {
-            let rand = rand::thread_rng().gen_range(1..5);
-            let t = if rand == 1 { 0.5 } else { 0.92 };
-            self.brain.as_mut().unwrap().randomize();
-			self.brain.as_mut().unwrap().prune(ref_brain.unwrap(), t);
-        }
     }
 
-    pub fn update(&mut self, delta_t_s: f32, offset: f32, road: &Road, traffic: &Vec<Car>) {
-        if !self.damaged && !self.dummy {
-            self.score += 1;
-            if self.position.angle < 20.0 || self.position.angle > 340.0 {
-                self.score += 1;
-            }
+    pub fn update(&mut self, delta_t_s: f32, road: &Road, traffic: &[Car]) {
+        self.sense(road, traffic);
+        self.think();
+        self.act(delta_t_s, road, traffic);
+    }
 
+    /// First part of `update`: checks for crashes and reads the sensors.
+    pub fn sense(&mut self, road: &Road, traffic: &[Car]) {
+        self.step_start = StepStart {
+            damaged: self.damaged,
+            y: self.position.y,
+            steering_angle: self.motion.steering_angle,
+            traffic_ahead: self.traffic_ahead(traffic),
+        };
+        if !self.damaged && !self.dummy {
             self.close_to_lane_center = road.is_close_to_lane_center(
                 self.position.x,
                 self.dimentions.w as f32 / 2.0,
             );
-
-            if self.close_to_lane_center {
-                self.score += 3;
-            }
         }
-        self.hitbox = self.rotate_hitbox_points(offset);
+        self.hitbox = self.rotate_hitbox_points();
 
         for i in 0..self.hitbox.len() {
             let a = self.hitbox[i];
@@ -210,7 +287,7 @@ impl Car {
                     }
                 }
                 for car in traffic.iter() {
-                    let points = car.rotate_hitbox_points(offset);
+                    let points = car.rotate_hitbox_points();
                     for i in 0..points.len() {
                         let c = points[i];
                         let d = points[(i + 1) % points.len()];
@@ -243,33 +320,113 @@ impl Car {
                     self.position.x,
                     self.position.y,
                     self.position.angle,
-                    offset,
                     &road.borders,
                     traffic,
                 );
                 self.sensor_readings.append(&mut r.clone());
             }
+            let state = self.state(road);
+            self.sensor_readings
+                .extend(self.features.iter().map(|f| f.observe(&state)));
         }
+    }
 
-        if self.brain.is_some() && !self.damaged {
-            let outputs = self
-                .brain
-                .as_mut()
-                .unwrap()
-                .feed_forward(&self.sensor_readings);
-            assert_eq!(outputs.len(), 4);
-            self.controls.forward = outputs[0] > 0.33;
-            self.controls.backward = outputs[1] > 0.33;
-            self.controls.left = outputs[2] > 0.33;
-            self.controls.right = outputs[3] > 0.33;
-            // println!("forward:  {}\nbackward: {}\nleft:     {}\nright:    {}\n\n", outputs[0], outputs[1], outputs[2], outputs[3]);
+    /// Whether the car is driven by a brain this step, given its sensor readings.
+    pub fn needs_controls(&self) -> bool {
+        !self.dummy && !self.damaged
+    }
+
+    /// Readings of every sensor followed by the features of the car.
+    pub fn sensor_readings(&self) -> &[f32] {
+        &self.sensor_readings
+    }
+
+    /// Drives with the outputs of its own brain, if it has one and `needs_controls`.
+    pub fn think(&mut self) {
+        if !self.needs_controls() {
+            return;
+        }
+        if let Some(brain) = self.brain.as_mut() {
+            let mode = brain.control_mode();
+            let outputs = brain.feed_forward(&self.sensor_readings);
+            Self::set_controls(&mut self.controls, outputs, mode);
+        }
+    }
+
+    /// Drives with the outputs of a brain fed with `sensor_readings`, read
+    /// the way `mode` says.
+    pub fn apply_brain_outputs(&mut self, outputs: &[f32], mode: ControlMode) {
+        Self::set_controls(&mut self.controls, outputs, mode);
+    }
+
+    fn set_controls(controls: &mut Controls, outputs: &[f32], mode: ControlMode) {
+        assert_eq!(outputs.len(), 4);
+        match mode {
+            ControlMode::Discrete => {
+                controls.analog = None;
+                controls.forward = outputs[0] > CONTROL_THRESHOLD;
+                controls.backward = outputs[1] > CONTROL_THRESHOLD;
+                controls.left = outputs[2] > CONTROL_THRESHOLD;
+                controls.right = outputs[3] > CONTROL_THRESHOLD;
+            }
+            ControlMode::Analog => controls.analog = Some(AnalogControls::from_outputs(outputs)),
         }
+    }
 
+    /// Last part of `update`: moves the car and measures what happened since `sense`.
+    pub fn act(&mut self, delta_t_s: f32, road: &Road, traffic: &[Car]) {
+        let StepStart {
+            damaged: was_damaged,
+            y: start_y,
+            steering_angle: start_steering_angle,
+            traffic_ahead,
+        } = self.step_start;
         self.update_position(delta_t_s, road);
+
+        if !self.dummy {
+            self.telemetry = if was_damaged {
+                StepTelemetry {
+                    delta_t_s,
+                    ..Default::default()
+                }
+            } else {
+                let center_x = self.position.x + self.scaled_width() as f32 / 2.0;
+                StepTelemetry {
+                    delta_t_s,
+                    alive: !self.damaged,
+                    crashed: self.damaged,
+                    distance_m: units::px_to_m(start_y - self.position.y),
+                    lane_deviation_m: units::px_to_m(road.lane_center_deviation(center_x)),
+                    steering_rate: (self.motion.steering_angle - start_steering_angle)
+                        / delta_t_s.max(f32::EPSILON),
+                    overtakes: traffic_ahead as i32 - self.traffic_ahead(traffic) as i32,
+                }
+            };
+        }
+    }
+
+    fn state(&self, road: &Road) -> CarState {
+        let center_x = self.position.x + self.scaled_width() as f32 / 2.0;
+        CarState {
+            velocity: self.motion.velocity,
+            max_velocity: self.motion.max_velocity,
+            steering_angle: self.motion.steering_angle,
+            steering_max_angle: self.motion.steering_max_angle,
+            heading: self.position.angle,
+            lane_offset: road.lane_center_offset(center_x),
+            lane_width: road.lane_width(),
+        }
+    }
+
+    fn traffic_ahead(&self, traffic: &[Car]) -> usize {
+        traffic
+            .iter()
+            .filter(|c| c.position.y < self.position.y)
+            .count()
     }
 
     pub fn render(
-        &mut self,
+        &self,
         canvas: &mut Canvas<Window>,
         offset: f32,
         is_best: bool,
@@ -312,17 +469,16 @@ impl Car {
         )?;
 
         // render hitbox
+        let offset_y = offset as i32;
         for i in 0..self.hitbox.len() {
-            let a = self.hitbox[i];
-            let b = self.hitbox[(i + 1) % self.hitbox.len()];
+            let a = self.hitbox[i].offset(0, -offset_y);
+            let b = self.hitbox[(i + 1) % self.hitbox.len()].offset(0, -offset_y);
             canvas.draw_line(a, b)?;
         }
 
-        if !self.damaged {
-            for sensor in self.sensors.iter_mut() {
-                if is_best {
-                    sensor.render(canvas).map_err(|e| e.to_string())?;
-                }
+        if !self.damaged && is_best {
+            for sensor in self.sensors.iter() {
+                sensor.render(canvas, offset)?;
             }
         }
         Ok(())
@@ -383,10 +539,11 @@ impl Car {
         &self.hitbox
     }
 
-    pub fn rotate_hitbox_points(&self, offset: f32) -> Vec<Point> {
+    /// Hitbox polygon in world coordinates.
+    pub fn rotate_hitbox_points(&self) -> Vec<Point> {
         let (w, h) = self.src_dimentions_scaled();
         let center_x = self.position.x + w / 2.0;
-        let center_y = (self.position.y - offset) + h / 2.0;
+        let center_y = self.position.y + h / 2.0;
         let angle_rad = self.position.angle.to_radians() as f32;
         self.get_hitbox_points(w, h)
             .iter()
@@ -398,11 +555,7 @@ impl Car {
             .collect()
     }
 
-    fn update_position(&mut self, delta_t_s: f32, road: &Road) {
-        if self.damaged {
-            self.motion.velocity = 0.0;
-            return;
-        }
+    fn apply_discrete_controls(&mut self, delta_t_s: f32) {
         if self.controls.forward {
             self.motion.velocity += self.motion.acceleration * delta_t_s * 11.34;
         } else if self.controls.backward {
@@ -423,11 +576,44 @@ impl Car {
         } else if self.controls.right {
             self.turn_right(delta_t_s);
         }
+    }
+
+    /// Accelerates and brakes in proportion to the pedals, the brake stopping
+    /// the car without reversing it, and turns the wheels toward the target
+    /// angle no faster than the discrete controls turn them.
+    fn apply_analog_controls(&mut self, analog: AnalogControls, delta_t_s: f32) {
+        let pedal = self.motion.acceleration * delta_t_s * 11.34;
+        if analog.throttle > 0.0 {
+            self.motion.velocity += pedal * analog.throttle;
+        } else if analog.brake == 0.0 {
+            self.apply_friction(delta_t_s);
+        }
+        if analog.brake > 0.0 && self.motion.velocity > 0.0 {
+            let braked = self.motion.velocity - pedal / 1.5 * analog.brake;
+            self.motion.velocity = braked.max(0.0);
+        }
+        self.normalize_velocity();
+
+        let target = analog.steering * self.motion.steering_max_angle;
+        let turn = self.motion.steering_velocity * delta_t_s;
+        self.motion.steering_angle += (target - self.motion.steering_angle).clamp(-turn, turn);
+    }
+
+    fn update_position(&mut self, delta_t_s: f32, road: &Road) {
+        if self.damaged {
+            self.motion.velocity = 0.0;
+            return;
+        }
+        if let Some(analog) = self.controls.analog {
+            self.apply_analog_controls(analog, delta_t_s);
+        } else {
+            self.apply_discrete_controls(delta_t_s);
+        }
         self.normalize_angle();
 
         if self.dummy {
             if !self.break_checking {
-                let should_break_check = rand::thread_rng().gen_range(1..60 * 4) == 1;
+                let should_break_check = self.rng.gen_range(1..60 * 4) == 1;
                 if should_break_check && !self.changing_lane {
                     self.break_checking = true;
                     self.motion.velocity /= 1.2;
@@ -441,10 +627,10 @@ impl Car {
             }
 
             if !self.changing_lane {
-                let should_change_lane = rand::thread_rng().gen_range(1..60 * 6) == 1;
+                let should_change_lane = self.rng.gen_range(1..60 * 6) == 1;
                 if should_change_lane && !self.break_checking {
                     self.changing_lane = true;
-                    self.target_lane = road.random_lane_idx();
+                    self.target_lane = road.random_lane_idx(&mut self.rng);
                     // let aggressiveness = rand::thread_rng().gen_range(5..7);
                     // self.motion.steering_angle = aggressiveness as f32;
                     if self.current_lane > self.target_lane {
@@ -620,6 +806,9 @@ pub struct Controls {
     pub backward: bool,
     pub left: bool,
     pub right: bool,
+    /// set by brains driving in `ControlMode::Analog`, in which case the
+    /// booleans are ignored
+    pub analog: Option<AnalogControls>,
 }
 impl Controls {
     pub fn new() -> Self {
@@ -628,41 +817,78 @@ impl Controls {
             backward: false,
             left: false,
             right: false,
+            analog: None,
+        }
+    }
+
+    /// Whether each control is pressed, in the order of `CONTROL_NAMES`.
+    pub fn pressed(&self) -> [bool; 4] {
+        [self.forward, self.backward, self.left, self.right]
+    }
+}
+
+/// How far the pedals are pressed and where the wheels are turned to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnalogControls {
+    /// in [0, 1]
+    pub throttle: f32,
+    /// in [0, 1]
+    pub brake: f32,
+    /// target steering angle over its maximum, in [-1, 1], negative to the left
+    pub steering: f32,
+}
+impl AnalogControls {
+    pub fn from_outputs(outputs: &[f32]) -> Self {
+        Self {
+            throttle: outputs[0].clamp(0.0, 1.0),
+            brake: outputs[1].clamp(0.0, 1.0),
+            steering: (outputs[3] - outputs[2]).clamp(-1.0, 1.0),
         }
     }
 }
 
 pub struct ControlledCar {
-    car: Car,
+    pub car: Car,
+    /// what the driver saw and pressed at every step, while recording
+    recording: Option<Vec<Sample>>,
 }
 impl ControlledCar {
     pub fn new(mut car: Car) -> Self {
         car.brain = None;
-        Self { car }
+        Self {
+            car,
+            recording: None,
+        }
+    }
+
+    /// Starts keeping a sample of every step the car drives undamaged.
+    pub fn record(&mut self) {
+        self.recording = Some(vec![]);
+    }
+
+    /// Samples recorded so far, none if not recording.
+    pub fn take_recording(&mut self) -> Vec<Sample> {
+        self.recording.as_mut().map(std::mem::take).unwrap_or_default()
     }
 
     pub fn screen_offset(&self, target_y: f32) -> f32 {
         self.car.position.y - target_y
     }
 
-    pub fn update(
-        &mut self,
-        delta_t_s: f32,
-        offset: f32,
-        road: &Road,
-        traffic: &Vec<Car>,
-        cars_alive: &mut i32,
-    ) {
+    pub fn update(&mut self, delta_t_s: f32, road: &Road, traffic: &Vec<Car>) {
         // println!("vel: {}", self.car.motion.velocity);
-        self.car.update(delta_t_s, offset, road, traffic);
-        if self.car.did_just_crashed {
-            *cars_alive -= 1;
-            self.car.did_just_crashed = false;
+        self.car.sense(road, traffic);
+        if let Some(recording) = self.recording.as_mut().filter(|_| !self.car.damaged) {
+            recording.push(Sample {
+                readings: self.car.sensor_readings.clone(),
+                controls: self.car.controls.pressed(),
+            });
         }
+        self.car.act(delta_t_s, road, traffic);
     }
 
     pub fn render(
-        &mut self,
+        &self,
         canvas: &mut Canvas<Window>,
         offset: f32,
         is_best: bool,
@@ -738,10 +964,15 @@ impl ControlledCar {
     }
 }
 
+/// Size of the car texture, needed to lay out cars without loading it into SDL.
+pub fn texture_size() -> Result<(u32, u32), String> {
+    image::image_dimensions(TEXTURE_PATH).map_err(|e| e.to_string())
+}
+
 pub fn create_main_texture<'a>(
     tc: &'a TextureCreator<WindowContext>,
 ) -> Result<SizedTexture<'a>, String> {
-    let mut main = texture::from_file("assets/car.png", &tc)?;
+    let mut main = texture::from_file(TEXTURE_PATH, &tc)?;
     main.texture.set_blend_mode(BlendMode::Blend);
     Ok(main)
 }
@@ -749,7 +980,7 @@ pub fn create_main_texture<'a>(
 pub fn create_damaged_texture<'a>(
     tc: &'a TextureCreator<WindowContext>,
 ) -> Result<SizedTexture<'a>, String> {
-    let mut damaged = texture::from_file("assets/car.png", &tc)?;
+    let mut damaged = texture::from_file(TEXTURE_PATH, &tc)?;
     damaged.texture.set_blend_mode(BlendMode::Blend);
     damaged.texture.set_alpha_mod(128);
     damaged.texture.set_color_mod(64, 64, 64);
@@ -759,7 +990,7 @@ pub fn create_damaged_texture<'a>(
 pub fn create_unfocused_texture<'a>(
     tc: &'a TextureCreator<WindowContext>,
 ) -> Result<SizedTexture<'a>, String> {
-    let mut unfocused = texture::from_file("assets/car.png", &tc)?;
+    let mut unfocused = texture::from_file(TEXTURE_PATH, &tc)?;
     unfocused.texture.set_blend_mode(BlendMode::Blend);
     unfocused.texture.set_alpha_mod(128);
     Ok(unfocused)
@@ -0,0 +1,893 @@
+use clap::{Args, Parser, Subcommand};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::{prelude::*, ThreadPoolBuilder};
+
+use crate::binary::WeightEncoding;
+use crate::brain::{Brain, BrainError, BrainFile, GenomeFile, PopulationFile};
+use crate::car::{self, ControlMode};
+use crate::dqn::{self, Agent};
+use crate::es::EsPopulation;
+use crate::evolution::{Evolve, Population};
+use crate::experiment::{self, Experiment};
+use crate::imitation::{self, Dataset, Trainer};
+use crate::inference::Backend;
+use crate::neat::NeatPopulation;
+use crate::network::{LevelKind, NeuralNetwork};
+use crate::observation::Feature;
+use crate::simulation::Simulation;
+use crate::viewer::{self, Mode};
+
+/// timestep used when running headless and the experiment has none
+const DEFAULT_DELTA_T_S: f32 = 1.0 / 60.0;
+
+#[derive(Parser)]
+#[command(name = "car-ai", about = "Self driving cars trained by a genetic algorithm")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Args)]
+pub struct ExperimentArgs {
+    /// experiment file
+    #[arg(short, long, default_value = experiment::DEFAULT_PATH)]
+    pub config: String,
+    /// overrides the seed of the experiment
+    #[arg(short, long)]
+    pub seed: Option<u64>,
+    /// overrides where the experiment evaluates the brains
+    #[arg(long, value_enum)]
+    pub inference: Option<Backend>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Evolve a population, in a window or headless
+    Train {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        /// brains the first generation is derived from, the experiment output brains by default
+        #[arg(short, long = "brain")]
+        brains: Vec<String>,
+        /// directory the best brains are saved to, instead of the experiment output paths
+        #[arg(short, long)]
+        output: Option<String>,
+        /// run without a window
+        #[arg(long)]
+        headless: bool,
+        /// stop after this many generations
+        #[arg(short, long)]
+        generations: Option<u32>,
+    },
+    /// Replay saved brains without evolving them
+    Watch {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        /// brains to replay, the experiment best brain by default
+        #[arg(short, long = "brain")]
+        brains: Vec<String>,
+        /// with a single brain, how many cars drive it
+        #[arg(long, default_value_t = 1)]
+        cars: usize,
+    },
+    /// Score brains on fixed seeds and print the results
+    Eval {
+        /// experiment file
+        #[arg(short, long, default_value = experiment::DEFAULT_PATH)]
+        config: String,
+        /// seeds every brain is evaluated on
+        #[arg(short, long = "seed", default_values_t = [0, 1, 2, 3, 4])]
+        seeds: Vec<u64>,
+        /// brains to evaluate
+        #[arg(short, long = "brain", required = true)]
+        brains: Vec<String>,
+        /// directory an eval.csv with every result is written to
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Drive with the arrow keys against traffic
+    Drive {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        /// brains of AI cars driving along, none by default
+        #[arg(short, long = "brain")]
+        brains: Vec<String>,
+        /// dataset the sensor readings and pressed keys of every step are
+        /// added to when the window closes
+        #[arg(short, long)]
+        record: Option<String>,
+    },
+    /// Fit a brain to recorded driving, a population can then start from it
+    Imitate {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        /// datasets recorded with drive --record
+        #[arg(short, long = "data", required = true)]
+        data: Vec<String>,
+        /// where the brain is saved, the experiment best brain by default
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Train a single car by deep Q-learning, saving its policy as a brain
+    Dqn {
+        #[command(flatten)]
+        experiment: ExperimentArgs,
+        /// where the brain is saved, the experiment best brain by default
+        #[arg(short, long)]
+        output: Option<String>,
+        /// stop after this many episodes instead of the experiment count
+        #[arg(short, long)]
+        episodes: Option<u32>,
+    },
+    /// Convert a brain between the JSON and the binary format
+    Convert {
+        /// brain to convert, in either format
+        input: String,
+        /// file to write, as JSON if it ends in .json and binary otherwise
+        output: String,
+        /// how the binary format stores weights
+        #[arg(short, long, value_enum, default_value_t = WeightEncoding::F32)]
+        weights: WeightEncoding,
+    },
+}
+
+pub fn run(cli: Cli) -> Result<(), String> {
+    match cli.command {
+        Command::Train {
+            experiment,
+            brains,
+            output,
+            headless,
+            generations,
+        } => {
+            let mut experiment = load_experiment(
+                &experiment.config,
+                experiment.seed,
+                experiment.inference,
+            )?;
+            if let Some(dir) = output {
+                experiment.output.best_brain = format!("{}/best.json", dir);
+                experiment.output.second_best_brain = format!("{}/second_best.json", dir);
+            }
+            train(&experiment, &brains, headless, generations)
+        }
+        Command::Watch {
+            experiment,
+            brains,
+            cars,
+        } => {
+            let experiment = load_experiment(
+                &experiment.config,
+                experiment.seed,
+                experiment.inference,
+            )?;
+            let features = &experiment.observation.features;
+            let mut brains = if brains.is_empty() {
+                vec![load_brain(&experiment.output.best_brain, features)?]
+            } else {
+                load_brains(&brains, features)?
+            };
+            let mut config = experiment.simulation_config();
+            config.amount_cars = 0;
+            let mut sim = Simulation::new(config, None, None)?;
+            sim.fitness = experiment.fitness();
+            if brains.len() == 1 {
+                sim.restart_shared(brains.remove(0), cars.max(1));
+            } else {
+                sim.restart(&brains);
+            }
+            viewer::run(&mut sim, Mode::Watch, &experiment.window)
+        }
+        Command::Eval {
+            config,
+            seeds,
+            brains,
+            output,
+        } => {
+            let experiment = load_experiment(&config, None, None)?;
+            eval(&experiment, &brains, &seeds, output.as_deref())
+        }
+        Command::Drive {
+            experiment,
+            brains,
+            record,
+        } => {
+            let experiment = load_experiment(
+                &experiment.config,
+                experiment.seed,
+                experiment.inference,
+            )?;
+            let brains = load_brains(&brains, &experiment.observation.features)?;
+            let mut config = experiment.simulation_config();
+            config.amount_cars = brains.len() as u32;
+            let mut sim = Simulation::new(config, None, None)?;
+            sim.fitness = experiment.fitness();
+            sim.restart(&brains);
+            sim.spawn_controlled_car()?;
+            if record.is_some() {
+                sim.controlled_car.as_mut().unwrap().record();
+            }
+            viewer::run(&mut sim, Mode::Drive, &experiment.window)?;
+            match record {
+                Some(path) => save_recording(&path, &mut sim),
+                None => Ok(()),
+            }
+        }
+        Command::Imitate {
+            experiment,
+            data,
+            output,
+        } => {
+            let experiment = load_experiment(
+                &experiment.config,
+                experiment.seed,
+                experiment.inference,
+            )?;
+            let output = output.unwrap_or_else(|| experiment.output.best_brain.clone());
+            imitate(&experiment, &data, &output)
+        }
+        Command::Dqn {
+            experiment,
+            output,
+            episodes,
+        } => {
+            let mut experiment = load_experiment(
+                &experiment.config,
+                experiment.seed,
+                experiment.inference,
+            )?;
+            if let Some(episodes) = episodes {
+                experiment.dqn.episodes = episodes;
+            }
+            let output = output.unwrap_or_else(|| experiment.output.best_brain.clone());
+            train_dqn(&experiment, &output)
+        }
+        Command::Convert {
+            input,
+            output,
+            weights,
+        } => convert(&input, &output, weights),
+    }
+}
+
+fn load_experiment(
+    path: &str,
+    seed: Option<u64>,
+    inference: Option<Backend>,
+) -> Result<Experiment, String> {
+    let mut experiment = Experiment::load(path)?;
+    if seed.is_some() {
+        experiment.world.seed = seed;
+    }
+    if let Some(inference) = inference {
+        experiment.inference = inference;
+    }
+    println!("experiment: {}", experiment.name);
+    ThreadPoolBuilder::new()
+        .num_threads(experiment.threads)
+        .build_global()
+        .map_err(|e| e.to_string())?;
+    Ok(experiment)
+}
+
+/// Loads a brain or a genome and checks it fits the sensors of the AI cars
+/// and the `features` they observe.
+fn load_brain(path: &str, features: &[Feature]) -> Result<Brain, String> {
+    Brain::load(path, &car::SENSOR_LAYOUT, features)
+        .map_err(|e| format!("could not load brain {}: {}", path, e))
+}
+
+fn load_brains(paths: &[String], features: &[Feature]) -> Result<Vec<Brain>, String> {
+    paths.iter().map(|p| load_brain(p, features)).collect()
+}
+
+/// Loads a brain a dense population can start from.
+fn load_dense_brain(path: &str, features: &[Feature]) -> Result<NeuralNetwork, String> {
+    match load_brain(path, features)? {
+        Brain::Dense(network) => Ok(network),
+        Brain::Neat(_) => Err(format!(
+            "{} is a genome, only experiments with a [neat] table evolve genomes",
+            path
+        )),
+    }
+}
+
+/// Loads the brain a training run saved before, `None` when there is none
+/// yet or it is a genome. Any other error is returned so the run does not
+/// start fresh and overwrite a brain it could not read.
+fn load_saved_brain(path: &str, features: &[Feature]) -> Result<Option<NeuralNetwork>, String> {
+    match Brain::load(path, &car::SENSOR_LAYOUT, features) {
+        Ok(Brain::Dense(network)) => Ok(Some(network)),
+        Ok(Brain::Neat(_)) | Err(BrainError::NotFound) => Ok(None),
+        Err(e) => Err(format!("could not load brain {}: {}", path, e)),
+    }
+}
+
+/// Loads the population checkpoint at `path`, `None` when there is none yet.
+fn load_saved_population(
+    path: &str,
+    features: &[Feature],
+) -> Result<Option<PopulationFile>, String> {
+    let file = match PopulationFile::load(path) {
+        Ok(file) => file,
+        Err(BrainError::NotFound) => return Ok(None),
+        Err(e) => return Err(format!("could not load population {}: {}", path, e)),
+    };
+    file.validate(&car::SENSOR_LAYOUT, features)
+        .map_err(|e| format!("could not load population {}: {}", path, e))?;
+    Ok(Some(file))
+}
+
+fn save_champions(
+    experiment: &Experiment,
+    population: &Population,
+    sim: &Simulation,
+) -> Result<(), String> {
+    let champions = champions_or_leaders(population.champions(), sim, Brain::as_dense);
+    let seed = sim.seed();
+    save_brains(experiment, &champions, population.generation, seed)?;
+    let Some(path) = &experiment.output.population else {
+        return Ok(());
+    };
+    let mut file = PopulationFile::new(
+        population.brains().to_vec(),
+        car::SENSOR_LAYOUT.to_vec(),
+        experiment.observation.features.clone(),
+    );
+    file.metadata.generation = Some(population.generation);
+    file.metadata.seed = Some(seed);
+    file.save(path, experiment.output.weights.unwrap_or_default())
+        .map_err(|e| format!("could not save population {}: {}", path, e))
+}
+
+fn save_es_champions(
+    experiment: &Experiment,
+    population: &EsPopulation,
+    sim: &Simulation,
+) -> Result<(), String> {
+    let champions = champions_or_leaders(population.champions(), sim, Brain::as_dense);
+    save_brains(experiment, &champions, population.generation, sim.seed())
+}
+
+/// `champions`, or the cars of `sim` fittest first while no generation has
+/// ended yet, so that closing the window early still saves the leaders.
+fn champions_or_leaders<T: Clone>(
+    champions: &[(T, f64)],
+    sim: &Simulation,
+    brain: fn(&Brain) -> Option<&T>,
+) -> Vec<(T, f64)> {
+    if !champions.is_empty() {
+        return champions.to_vec();
+    }
+    let mut leaders: Vec<(T, f64)> = sim
+        .cars
+        .iter()
+        .filter_map(|c| Some((brain(c.brain.as_ref()?)?.clone(), c.fitness)))
+        .collect();
+    leaders.sort_by(|a, b| b.1.total_cmp(&a.1));
+    leaders
+}
+
+/// Saves the fittest brains of the generation before `generation` to the
+/// experiment output paths.
+fn save_brains(
+    experiment: &Experiment,
+    champions: &[(NeuralNetwork, f64)],
+    generation: u32,
+    seed: u64,
+) -> Result<(), String> {
+    let paths = [
+        &experiment.output.best_brain,
+        &experiment.output.second_best_brain,
+    ];
+    for ((brain, fitness), path) in champions.iter().zip(paths) {
+        let mut file = BrainFile::new(
+            brain.clone(),
+            car::SENSOR_LAYOUT.to_vec(),
+            experiment.observation.features.clone(),
+        );
+        file.metadata.generation = generation.checked_sub(1);
+        file.metadata.fitness = Some(*fitness);
+        file.metadata.seed = Some(seed);
+        match experiment.output.weights {
+            Some(encoding) => file.save_binary(path, encoding),
+            None => file.save(path),
+        }
+        .map_err(|e| format!("could not save brain {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+fn save_genome_champions(
+    experiment: &Experiment,
+    population: &NeatPopulation,
+    sim: &Simulation,
+) -> Result<(), String> {
+    let champions = champions_or_leaders(population.champions(), sim, Brain::as_genome);
+    let seed = sim.seed();
+    let paths = [
+        &experiment.output.best_brain,
+        &experiment.output.second_best_brain,
+    ];
+    for ((genome, fitness), path) in champions.iter().zip(paths) {
+        let mut file = GenomeFile::new(
+            genome.clone(),
+            car::SENSOR_LAYOUT.to_vec(),
+            experiment.observation.features.clone(),
+        );
+        file.metadata.generation = population.generation.checked_sub(1);
+        file.metadata.fitness = Some(*fitness);
+        file.metadata.seed = Some(seed);
+        file.save(path)
+            .map_err(|e| format!("could not save genome {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+fn train(
+    experiment: &Experiment,
+    brains: &[String],
+    headless: bool,
+    generations: Option<u32>,
+) -> Result<(), String> {
+    if experiment.neat.is_some() {
+        if !brains.is_empty() {
+            return Err("NEAT starts from minimal genomes, it takes no --brain".to_string());
+        }
+        return train_neat(experiment, headless, generations);
+    }
+    if experiment.es.is_some() {
+        return train_es(experiment, brains, headless, generations);
+    }
+    let features = &experiment.observation.features;
+    let kinds = car::brain_kinds(experiment.population.memory);
+    let control = experiment.population.control;
+    let (ref_brain, ref_brain2) = if brains.is_empty() {
+        // brains of another kind are left for a fresh start
+        let load = |path: &str| {
+            load_saved_brain(path, features)
+                .map(|b| b.filter(|b| b.kinds() == kinds && b.control == control))
+        };
+        (
+            load(&experiment.output.best_brain)?,
+            load(&experiment.output.second_best_brain)?,
+        )
+    } else {
+        let mut loaded = brains
+            .iter()
+            .map(|p| {
+                let brain = load_dense_brain(p, features)?;
+                if brain.kinds() != kinds {
+                    return Err(format!(
+                        "{} has levels {:?} but the experiment builds {:?}",
+                        p,
+                        brain.kinds(),
+                        kinds
+                    ));
+                }
+                check_control(p, &brain, control)?;
+                Ok(brain)
+            })
+            .collect::<Result<Vec<_>, String>>()?
+            .into_iter();
+        let first = loaded.next();
+        (first.clone(), loaded.next().or(first))
+    };
+    // a population of another kind or size is left for a fresh start
+    let checkpoint = match experiment.output.population.as_deref() {
+        Some(path) if brains.is_empty() => load_saved_population(path, features)?,
+        _ => None,
+    };
+    let checkpoint = checkpoint.filter(|file| {
+        file.networks.len() == experiment.population.size as usize
+            && file
+                .networks
+                .iter()
+                .all(|b| b.kinds() == kinds && b.control == control)
+    });
+    let fresh_start = ref_brain.is_none() && checkpoint.is_none();
+    let mut sim = Simulation::new(experiment.simulation_config(), ref_brain, ref_brain2)?;
+    sim.fitness = experiment.fitness();
+    println!("seed: {}", sim.seed());
+    // random brains of the simulation are dense and discrete
+    let default_brains = experiment.population.memory.is_none() && control == ControlMode::Discrete;
+    if fresh_start && !default_brains {
+        let mut rng = StdRng::seed_from_u64(sim.seed());
+        let brains: Vec<NeuralNetwork> = (0..experiment.population.size)
+            .map(|_| {
+                let mut brain = NeuralNetwork::with_kinds(&car::brain_topology(features), &kinds);
+                brain.randomize(&mut rng);
+                brain.control = control;
+                brain
+            })
+            .collect();
+        sim.restart(&brains);
+    }
+
+    let mut population = match checkpoint {
+        Some(file) => {
+            let generation = file.metadata.generation.unwrap_or(0);
+            println!("resuming generation {}", generation);
+            sim.restart(&file.networks);
+            let mut population =
+                Population::new(experiment.evolution_config(), file.networks, sim.seed());
+            population.generation = generation;
+            population
+        }
+        None => Population::from_simulation(experiment.evolution_config(), &sim),
+    };
+    run_training(
+        experiment,
+        &mut sim,
+        &mut population,
+        headless,
+        generations,
+        save_champions,
+    )?;
+    println!("saved networks");
+    Ok(())
+}
+
+fn check_control(path: &str, brain: &NeuralNetwork, control: ControlMode) -> Result<(), String> {
+    if brain.control != control {
+        return Err(format!(
+            "{} drives with {:?} controls but the experiment with {:?}",
+            path, brain.control, control
+        ));
+    }
+    Ok(())
+}
+
+fn train_neat(
+    experiment: &Experiment,
+    headless: bool,
+    generations: Option<u32>,
+) -> Result<(), String> {
+    let mut config = experiment.simulation_config();
+    config.amount_cars = 0;
+    let mut sim = Simulation::new(config, None, None)?;
+    sim.fitness = experiment.fitness();
+    println!("seed: {}", sim.seed());
+
+    let inputs = car::brain_topology(&experiment.observation.features)[0] as usize;
+    let mut population = NeatPopulation::new(
+        experiment.neat.clone().unwrap_or_default(),
+        experiment.population.size as usize,
+        inputs,
+        4,
+        sim.seed(),
+    );
+    sim.restart(population.genomes());
+    run_training(
+        experiment,
+        &mut sim,
+        &mut population,
+        headless,
+        generations,
+        save_genome_champions,
+    )?;
+    println!("saved genomes");
+    Ok(())
+}
+
+fn train_es(
+    experiment: &Experiment,
+    brains: &[String],
+    headless: bool,
+    generations: Option<u32>,
+) -> Result<(), String> {
+    let es = experiment.es.clone().unwrap_or_default();
+    let memory = experiment.population.memory;
+    let control = experiment.population.control;
+    let features = &experiment.observation.features;
+    let mut topology = car::brain_topology(features);
+    let mut kinds = car::brain_kinds(memory);
+    if let Some(hidden_layers) = &es.hidden_layers {
+        topology = std::iter::once(topology[0])
+            .chain(hidden_layers.iter().copied())
+            .chain([4])
+            .collect();
+        kinds = vec![LevelKind::Dense; hidden_layers.len() + 1];
+        if let Some(memory) = memory {
+            kinds[hidden_layers.len() - 1] = memory;
+        }
+    }
+    let mean = match brains {
+        // a brain of another shape is left for a fresh start
+        [] => load_saved_brain(&experiment.output.best_brain, features)?
+            .filter(|b| b.topology() == topology && b.kinds() == kinds && b.control == control),
+        [path] => {
+            let brain = load_dense_brain(path, features)?;
+            check_control(path, &brain, control)?;
+            Some(brain)
+        }
+        _ => return Err("evolution strategies start from a single --brain".to_string()),
+    };
+
+    let mut config = experiment.simulation_config();
+    config.amount_cars = 0;
+    let mut sim = Simulation::new(config, None, None)?;
+    sim.fitness = experiment.fitness();
+    println!("seed: {}", sim.seed());
+    let mean = mean.unwrap_or_else(|| {
+        let mut brain = NeuralNetwork::with_kinds(&topology, &kinds);
+        brain.randomize(&mut StdRng::seed_from_u64(sim.seed()));
+        brain.control = control;
+        brain
+    });
+    println!("{} parameters", mean.parameter_count());
+
+    let mut population = EsPopulation::new(
+        es,
+        mean,
+        experiment.population.size as usize,
+        sim.seed(),
+    )?;
+    sim.restart(population.brains());
+    run_training(
+        experiment,
+        &mut sim,
+        &mut population,
+        headless,
+        generations,
+        save_es_champions,
+    )?;
+    println!("saved networks");
+    Ok(())
+}
+
+/// Lets a DQN agent drive one car per episode, scoring its greedy policy like
+/// `eval` every `dqn.evaluation_interval` episodes and saving it to `output`
+/// whenever it improves.
+fn train_dqn(experiment: &Experiment, output: &str) -> Result<(), String> {
+    let config = &experiment.dqn;
+    let mut sim_config = experiment.simulation_config();
+    sim_config.amount_cars = 0;
+    sim_config.inference = Backend::Cpu;
+    let delta_t_s = sim_config.fixed_delta_t_s.unwrap_or(DEFAULT_DELTA_T_S);
+    let mut sim = Simulation::new(sim_config, None, None)?;
+    sim.fitness = experiment.fitness();
+    println!("seed: {}", sim.seed());
+
+    let inputs = car::brain_topology(&experiment.observation.features)[0];
+    let mut agent = Agent::new(config.clone(), inputs, sim.seed());
+    let seeds: Vec<u64> = (0..config.evaluation_seeds).collect();
+    let mut best = f64::NEG_INFINITY;
+    for episode in 1..=config.episodes {
+        let stats = dqn::run_episode(&mut agent, &mut sim, delta_t_s);
+        println!(
+            "episode {}: fitness = {:.1}, steps = {}, epsilon = {:.3}, loss = {}",
+            episode,
+            stats.fitness,
+            stats.steps,
+            agent.epsilon(),
+            stats.loss.map_or("-".to_string(), |l| format!("{:.4}", l))
+        );
+        if episode % config.evaluation_interval != 0 && episode != config.episodes {
+            continue;
+        }
+        let brain = Brain::Dense(agent.policy_brain());
+        let fitness = seeds
+            .par_iter()
+            .map(|&seed| evaluate(experiment, &brain, seed))
+            .collect::<Result<Vec<f64>, String>>()?;
+        let mean = fitness.iter().sum::<f64>() / fitness.len().max(1) as f64;
+        println!("greedy policy: mean {:.1} over {} seeds", mean, fitness.len());
+        if mean > best {
+            best = mean;
+            let mut file = BrainFile::new(
+                agent.policy_brain(),
+                car::SENSOR_LAYOUT.to_vec(),
+                experiment.observation.features.clone(),
+            );
+            file.metadata.fitness = Some(mean);
+            file.metadata.seed = Some(sim.seed());
+            match experiment.output.weights {
+                Some(encoding) => file.save_binary(output, encoding),
+                None => file.save(output),
+            }
+            .map_err(|e| format!("could not save brain {}: {}", output, e))?;
+            println!("saved {}", output);
+        }
+    }
+    Ok(())
+}
+
+/// Evolves `population` until `generations` or until the window is closed,
+/// saving its champions with `save` after every generation when headless
+/// and once at the end otherwise, the leaders of the generation under way
+/// when the window is closed before the first one ends.
+fn run_training<P: Evolve>(
+    experiment: &Experiment,
+    sim: &mut Simulation,
+    population: &mut P,
+    headless: bool,
+    generations: Option<u32>,
+    save: fn(&Experiment, &P, &Simulation) -> Result<(), String>,
+) -> Result<(), String> {
+    if headless {
+        let delta_t_s = experiment
+            .world
+            .fixed_delta_t_s
+            .unwrap_or(DEFAULT_DELTA_T_S);
+        while generations.is_none_or(|n| population.generation() < n) {
+            while !sim.is_generation_over() {
+                sim.step(delta_t_s);
+            }
+            println!("{}", population.next_generation(sim));
+            save(experiment, population, sim)?;
+        }
+    } else {
+        viewer::run(
+            sim,
+            Mode::Train {
+                population,
+                generations,
+            },
+            &experiment.window,
+        )?;
+        save(experiment, population, sim)?;
+    }
+    Ok(())
+}
+
+fn eval(
+    experiment: &Experiment,
+    paths: &[String],
+    seeds: &[u64],
+    output: Option<&str>,
+) -> Result<(), String> {
+    let brains = load_brains(paths, &experiment.observation.features)?;
+    let jobs: Vec<(usize, u64)> = (0..brains.len())
+        .flat_map(|b| seeds.iter().map(move |&s| (b, s)))
+        .collect();
+    let results = jobs
+        .par_iter()
+        .map(|&(b, seed)| evaluate(experiment, &brains[b], seed).map(|f| (b, seed, f)))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut csv = String::from("brain,seed,fitness\n");
+    for (b, seed, fitness) in results.iter() {
+        println!("{} seed {}: {:.1}", paths[*b], seed, fitness);
+        csv.push_str(&format!("{},{},{}\n", paths[*b], seed, fitness));
+    }
+    for (b, path) in paths.iter().enumerate() {
+        let fitness: Vec<f64> = results
+            .iter()
+            .filter(|r| r.0 == b)
+            .map(|r| r.2)
+            .collect();
+        let mean = fitness.iter().sum::<f64>() / fitness.len().max(1) as f64;
+        println!("{}: mean {:.1} over {} seeds", path, mean, fitness.len());
+    }
+    if let Some(dir) = output {
+        let path = format!("{}/eval.csv", dir);
+        std::fs::write(&path, csv).map_err(|e| format!("could not write {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// Fitness of `brain` driving alone until it is out or the time limit is reached.
+fn evaluate(experiment: &Experiment, brain: &Brain, seed: u64) -> Result<f64, String> {
+    let mut config = experiment.simulation_config();
+    config.amount_cars = 1;
+    config.seed = Some(seed);
+    // a single car is not worth a round trip to the GPU
+    config.inference = Backend::Cpu;
+    let delta_t_s = config.fixed_delta_t_s.unwrap_or(DEFAULT_DELTA_T_S);
+    let mut sim = Simulation::new(config, None, None)?;
+    sim.fitness = experiment.fitness();
+    sim.restart_shared(brain.clone(), 1);
+    while !sim.is_generation_over() {
+        sim.step(delta_t_s);
+    }
+    Ok(sim.cars[0].fitness)
+}
+
+/// Rewrites a brain file in the format `output` asks for, keeping its metadata.
+fn convert(input: &str, output: &str, weights: WeightEncoding) -> Result<(), String> {
+    let mut file = BrainFile::load(input, &car::SENSOR_LAYOUT)
+        .map_err(|e| format!("could not load brain {}: {}", input, e))?;
+    if output.ends_with(".json") {
+        file.save(output)
+    } else {
+        file.save_binary(output, weights)
+    }
+    .map_err(|e| format!("could not save brain {}: {}", output, e))?;
+    let size = |path: &str| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    println!("{}: {} bytes -> {}: {} bytes", input, size(input), output, size(output));
+    Ok(())
+}
+
+/// Adds what the human driver recorded to the dataset at `path`, creating it
+/// if needed.
+fn save_recording(path: &str, sim: &mut Simulation) -> Result<(), String> {
+    let samples = sim.controlled_car.as_mut().unwrap().take_recording();
+    let features = &sim.config.features;
+    let dataset = if std::path::Path::new(path).exists() {
+        Dataset::load(path)?
+    } else {
+        Dataset::new(car::SENSOR_LAYOUT.to_vec(), features.clone())
+    };
+    let mut dataset = check_dataset(path, dataset, features)?;
+    println!("recorded {} samples to {}", samples.len(), path);
+    dataset.samples.extend(samples);
+    dataset.save(path)
+}
+
+/// Checks the samples of a dataset hold what the cars observe.
+fn check_dataset(path: &str, dataset: Dataset, features: &[Feature]) -> Result<Dataset, String> {
+    if dataset.sensors != car::SENSOR_LAYOUT {
+        return Err(format!(
+            "dataset {} was recorded with sensors {:?} but the car has {:?}",
+            path,
+            dataset.sensors,
+            car::SENSOR_LAYOUT
+        ));
+    }
+    if dataset.features != features {
+        return Err(format!(
+            "dataset {} was recorded with features {:?} but the car observes {:?}",
+            path, dataset.features, features
+        ));
+    }
+    Ok(dataset)
+}
+
+/// Fits a new brain to the samples of every dataset and saves it to `output`.
+fn imitate(experiment: &Experiment, data: &[String], output: &str) -> Result<(), String> {
+    if experiment.population.memory.is_some() {
+        return Err("imitation only trains brains without memory".to_string());
+    }
+    let features = &experiment.observation.features;
+    let mut samples = vec![];
+    for path in data {
+        let dataset = check_dataset(path, Dataset::load(path)?, features)?;
+        samples.extend(dataset.samples);
+    }
+    let config = &experiment.imitation;
+    let (train, validation) = imitation::split(&samples, config.validation_split);
+    if train.is_empty() {
+        return Err("no samples to train on".to_string());
+    }
+    println!(
+        "{} training samples, {} validation samples",
+        train.len(),
+        validation.len()
+    );
+
+    let seed = experiment.world.seed.unwrap_or_else(rand::random);
+    println!("seed: {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut network = NeuralNetwork::new(&car::brain_topology(features));
+    network.randomize(&mut rng);
+    // a pressed key is a pedal pressed all the way or the wheel turned to its lock
+    network.control = experiment.population.control;
+    let mut trainer = Trainer::new(&network, config.clone());
+    for epoch in 1..=config.epochs {
+        let loss = trainer.epoch(&mut network, train, &mut rng);
+        if validation.is_empty() {
+            println!("epoch {:>3}: loss {:.4}", epoch, loss);
+            continue;
+        }
+        let evaluation = imitation::evaluate(&network, validation);
+        println!(
+            "epoch {:>3}: loss {:.4}, validation loss {:.4}, accuracy {:.1}%",
+            epoch,
+            loss,
+            evaluation.loss,
+            evaluation.accuracy * 100.0
+        );
+    }
+
+    let mut file = BrainFile::new(network, car::SENSOR_LAYOUT.to_vec(), features.to_vec());
+    file.metadata.seed = Some(seed);
+    match experiment.output.weights {
+        Some(encoding) => file.save_binary(output, encoding),
+        None => file.save(output),
+    }
+    .map_err(|e| format!("could not save brain {}: {}", output, e))?;
+    println!("saved {}", output);
+    Ok(())
+}
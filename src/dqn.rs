@@ -0,0 +1,431 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::gradient::{backpropagate, forward, Adam, Gradients};
+use crate::network::{Activation, Level, NeuralNetwork};
+use crate::simulation::Simulation;
+
+/// Combinations of controls the agent picks from, pressed in the order of
+/// `car::CONTROL_NAMES`: no pedal, forward or backward, each going straight,
+/// left or right.
+pub const ACTIONS: [[bool; 4]; 9] = [
+    [false, false, false, false],
+    [false, false, true, false],
+    [false, false, false, true],
+    [true, false, false, false],
+    [true, false, true, false],
+    [true, false, false, true],
+    [false, true, false, false],
+    [false, true, true, false],
+    [false, true, false, true],
+];
+
+/// How much the exported brain scales the Q-values before its softmax, large
+/// enough for the softmax to pick the best action alone.
+const SHARPNESS: f32 = 1e4;
+
+/// Settings of the deep Q-learning agent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DqnConfig {
+    /// neurons of the hidden levels of the Q-network, all relu
+    pub hidden_layers: Vec<u32>,
+    /// generations the agent drives through while learning
+    pub episodes: u32,
+    /// transitions kept for replay, the oldest are overwritten
+    pub replay_capacity: usize,
+    /// transitions replayed by every learning step
+    pub batch_size: usize,
+    /// step size of Adam
+    pub learning_rate: f32,
+    /// weight of the value of the next state in the target of a transition
+    pub discount: f32,
+    /// chance of a random action, decaying linearly from `epsilon_start` to
+    /// `epsilon_end` over `epsilon_decay_steps` steps
+    pub epsilon_start: f32,
+    pub epsilon_end: f32,
+    pub epsilon_decay_steps: u64,
+    /// steps between copies of the Q-network into the target network
+    pub target_update_interval: u64,
+    /// steps driven before the first learning step
+    pub warmup_steps: u64,
+    /// steps between learning steps
+    pub train_interval: u64,
+    /// episodes between evaluations of the greedy policy
+    pub evaluation_interval: u32,
+    /// the greedy policy is scored like `eval` does on seeds 0 to
+    /// `evaluation_seeds - 1`
+    pub evaluation_seeds: u64,
+}
+impl Default for DqnConfig {
+    fn default() -> Self {
+        Self {
+            hidden_layers: vec![64, 64],
+            episodes: 500,
+            replay_capacity: 100_000,
+            batch_size: 64,
+            learning_rate: 5e-4,
+            discount: 0.99,
+            epsilon_start: 1.0,
+            epsilon_end: 0.05,
+            epsilon_decay_steps: 50_000,
+            target_update_interval: 1_000,
+            warmup_steps: 1_000,
+            train_interval: 4,
+            evaluation_interval: 10,
+            evaluation_seeds: 5,
+        }
+    }
+}
+
+/// Brain outputs pressing the controls of `ACTIONS[action]`.
+pub fn action_outputs(action: usize) -> [f32; 4] {
+    ACTIONS[action].map(|pressed| pressed as u8 as f32)
+}
+
+/// What happened after an action, `next_state` is missing when the car crashed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub state: Vec<f32>,
+    pub action: usize,
+    pub reward: f32,
+    pub next_state: Option<Vec<f32>>,
+}
+
+/// The most recent transitions, replayed in random minibatches so that
+/// consecutive and nearly identical steps do not dominate a learning step.
+pub struct ReplayBuffer {
+    capacity: usize,
+    transitions: Vec<Transition>,
+    /// where the next transition goes once the buffer is full
+    next: usize,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "a replay buffer needs room for a transition");
+        Self {
+            capacity,
+            transitions: Vec::with_capacity(capacity.min(1 << 16)),
+            next: 0,
+        }
+    }
+
+    /// Adds a transition, overwriting the oldest one when full.
+    pub fn push(&mut self, transition: Transition) {
+        if self.transitions.len() < self.capacity {
+            self.transitions.push(transition);
+        } else {
+            self.transitions[self.next] = transition;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    /// `count` transitions drawn uniformly with replacement.
+    pub fn sample<'a>(&'a self, count: usize, rng: &mut impl Rng) -> Vec<&'a Transition> {
+        if self.transitions.is_empty() {
+            return vec![];
+        }
+        (0..count)
+            .map(|_| &self.transitions[rng.gen_range(0..self.transitions.len())])
+            .collect()
+    }
+}
+
+/// Learns the value of every action in `ACTIONS` with a Q-network, trained
+/// on replayed transitions against a target network that lags behind it.
+pub struct Agent {
+    config: DqnConfig,
+    online: NeuralNetwork,
+    target: NeuralNetwork,
+    adam: Adam,
+    replay: ReplayBuffer,
+    /// transitions observed so far
+    steps: u64,
+    rng: StdRng,
+}
+
+impl Agent {
+    pub fn new(config: DqnConfig, input_count: u32, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let topology: Vec<u32> = std::iter::once(input_count)
+            .chain(config.hidden_layers.iter().copied())
+            .chain([ACTIONS.len() as u32])
+            .collect();
+        let mut activations = vec![Activation::Relu; config.hidden_layers.len()];
+        activations.push(Activation::Linear);
+        let mut online = NeuralNetwork::with_activations(&topology, &activations);
+        online.randomize(&mut rng);
+        // scaled down by the fan-in so that the first Q-values stay small
+        for level in online.levels.iter_mut() {
+            let scale = 1.0 / (level.input_count() as f32).sqrt();
+            level.weights.iter_mut().for_each(|w| *w *= scale);
+            level.biases.fill(0.0);
+        }
+        Self {
+            adam: Adam::new(&online, config.learning_rate),
+            replay: ReplayBuffer::new(config.replay_capacity),
+            target: online.clone(),
+            online,
+            config,
+            steps: 0,
+            rng,
+        }
+    }
+
+    /// Chance of a random action at the current step.
+    pub fn epsilon(&self) -> f32 {
+        let config = &self.config;
+        let progress = (self.steps as f32 / config.epsilon_decay_steps.max(1) as f32).min(1.0);
+        config.epsilon_start + (config.epsilon_end - config.epsilon_start) * progress
+    }
+
+    /// Index into `ACTIONS`, a random one with a chance of `epsilon`.
+    pub fn act(&mut self, state: &[f32]) -> usize {
+        if self.rng.gen::<f32>() < self.epsilon() {
+            self.rng.gen_range(0..ACTIONS.len())
+        } else {
+            self.greedy(state)
+        }
+    }
+
+    /// Index into `ACTIONS` of the action of highest value.
+    pub fn greedy(&self, state: &[f32]) -> usize {
+        argmax(&q_values(&self.online, state))
+    }
+
+    /// Stores a transition and learns from replayed ones when due, returns
+    /// the loss of the learning step if there was one.
+    pub fn observe(&mut self, transition: Transition) -> Option<f32> {
+        self.replay.push(transition);
+        self.steps += 1;
+        let config = &self.config;
+        if self
+            .steps
+            .is_multiple_of(config.target_update_interval.max(1))
+        {
+            self.target = self.online.clone();
+        }
+        if self.steps < config.warmup_steps
+            || !self.steps.is_multiple_of(config.train_interval.max(1))
+        {
+            return None;
+        }
+        Some(self.learn())
+    }
+
+    /// One step of gradient descent on the huber loss between the value of
+    /// the replayed actions and their bootstrapped targets.
+    fn learn(&mut self) -> f32 {
+        let batch = self.replay.sample(self.config.batch_size, &mut self.rng);
+        let mut gradients = Gradients::zeros(&self.online);
+        let mut loss = 0.0;
+        for transition in batch.iter() {
+            let future = transition.next_state.as_ref().map_or(0.0, |next| {
+                let values = q_values(&self.target, next);
+                values.iter().copied().fold(f32::NEG_INFINITY, f32::max)
+            });
+            let target = transition.reward + self.config.discount * future;
+            let layers = forward(&self.online, &transition.state);
+            let error = layers.last().unwrap()[transition.action] - target;
+            loss += if error.abs() <= 1.0 {
+                0.5 * error * error
+            } else {
+                error.abs() - 0.5
+            };
+            let mut output_gradient = vec![0.0; ACTIONS.len()];
+            output_gradient[transition.action] = error.clamp(-1.0, 1.0);
+            backpropagate(&self.online, &layers, output_gradient, &mut gradients);
+        }
+        self.adam.step(&mut self.online, &gradients, batch.len());
+        loss / batch.len().max(1) as f32
+    }
+
+    /// A brain with the four outputs of any other, pressing the controls of
+    /// the greedy action: the Q-values go through a steep softmax, which a
+    /// last linear level maps onto the controls of `ACTIONS`.
+    pub fn policy_brain(&self) -> NeuralNetwork {
+        let mut brain = self.online.clone();
+        let q = brain.levels.last_mut().unwrap();
+        q.weights.iter_mut().for_each(|w| *w *= SHARPNESS);
+        q.biases.iter_mut().for_each(|b| *b *= SHARPNESS);
+        q.activation = Activation::Softmax;
+        let mut controls = Level::new(ACTIONS.len() as u32, 4);
+        for (c, row) in controls.weights.chunks_exact_mut(ACTIONS.len()).enumerate() {
+            for (w, action) in row.iter_mut().zip(ACTIONS) {
+                *w = action[c] as u8 as f32;
+            }
+        }
+        controls.activation = Activation::Linear;
+        brain.levels.push(controls);
+        brain
+    }
+}
+
+/// How an episode went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Episode {
+    pub fitness: f64,
+    pub steps: u32,
+    /// mean loss of the learning steps during the episode
+    pub loss: Option<f32>,
+}
+
+/// Drives the single car of a generation of `sim` with `agent` until it
+/// crashes or time runs out, learning along the way. The reward of an action
+/// is the fitness the car gained until the next one.
+pub fn run_episode(agent: &mut Agent, sim: &mut Simulation, delta_t_s: f32) -> Episode {
+    sim.restart_brainless(1);
+    // state, action and reward of the last step, waiting for the next state
+    let mut pending: Option<(Vec<f32>, usize, f32)> = None;
+    let mut losses = vec![];
+    let mut steps = 0;
+    while !sim.is_generation_over() {
+        let fitness = sim.cars[0].fitness;
+        let mut acted = None;
+        sim.step_with(delta_t_s, |_, readings| {
+            if let Some((state, action, reward)) = pending.take() {
+                let next_state = Some(readings.to_vec());
+                losses.extend(agent.observe(Transition {
+                    state,
+                    action,
+                    reward,
+                    next_state,
+                }));
+            }
+            let action = agent.act(readings);
+            acted = Some((readings.to_vec(), action));
+            Some(action_outputs(action))
+        });
+        let reward = (sim.cars[0].fitness - fitness) as f32;
+        match acted {
+            Some((state, action)) => {
+                pending = Some((state, action, reward));
+                steps += 1;
+            }
+            // a crash is only noticed at the start of the next step, which
+            // is when it is scored too
+            None => {
+                if let Some(pending) = pending.as_mut() {
+                    pending.2 += reward;
+                }
+            }
+        }
+        if sim.cars[0].damaged {
+            if let Some((state, action, reward)) = pending.take() {
+                losses.extend(agent.observe(Transition {
+                    state,
+                    action,
+                    reward,
+                    next_state: None,
+                }));
+            }
+        }
+    }
+    // the last step before the time limit has no next state and is dropped
+    Episode {
+        fitness: sim.cars[0].fitness,
+        steps,
+        loss: (!losses.is_empty()).then(|| losses.iter().sum::<f32>() / losses.len() as f32),
+    }
+}
+
+fn q_values(network: &NeuralNetwork, state: &[f32]) -> Vec<f32> {
+    forward(network, state).pop().unwrap()
+}
+
+fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        .map_or(0, |(i, _)| i)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn transition(reward: f32) -> Transition {
+        Transition {
+            state: vec![reward],
+            action: 0,
+            reward,
+            next_state: None,
+        }
+    }
+
+    #[test]
+    fn replays_the_latest_transitions() {
+        let mut replay = ReplayBuffer::new(3);
+        let mut rng = StdRng::seed_from_u64(1);
+        assert!(replay.sample(4, &mut rng).is_empty());
+        for reward in 0..5 {
+            replay.push(transition(reward as f32));
+        }
+        let sampled = replay.sample(100, &mut rng);
+        assert_eq!(sampled.len(), 100);
+        for reward in 2..5 {
+            assert!(sampled.iter().any(|t| t.reward == reward as f32));
+        }
+        assert!(sampled.iter().all(|t| t.reward >= 2.0));
+    }
+
+    #[test]
+    fn exported_brain_presses_the_greedy_controls() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let agent = Agent::new(DqnConfig::default(), 6, 2);
+        let mut brain = agent.policy_brain();
+        assert_eq!(brain.topology(), vec![6, 64, 64, 9, 4]);
+        for _ in 0..200 {
+            let state: Vec<f32> = (0..6).map(|_| rng.gen::<f32>() * 2.0 - 1.0).collect();
+            let action = agent.greedy(&state);
+            let outputs = brain.feed_forward(&state);
+            let pressed: Vec<bool> = outputs
+                .iter()
+                .map(|&o| o > crate::car::CONTROL_THRESHOLD)
+                .collect();
+            assert_eq!(pressed, ACTIONS[action], "{:?}", outputs);
+        }
+    }
+
+    #[test]
+    fn learns_which_action_pays() {
+        // the state says which action is rewarded, nothing else matters
+        let config = DqnConfig {
+            hidden_layers: vec![16],
+            batch_size: 32,
+            learning_rate: 1e-2,
+            discount: 0.5,
+            epsilon_decay_steps: 2_000,
+            target_update_interval: 100,
+            warmup_steps: 100,
+            train_interval: 1,
+            ..DqnConfig::default()
+        };
+        let mut agent = Agent::new(config, ACTIONS.len() as u32, 3);
+        let mut rng = StdRng::seed_from_u64(3);
+        let state = |good: usize| {
+            let mut state = vec![0.0; ACTIONS.len()];
+            state[good] = 1.0;
+            state
+        };
+        let mut good = rng.gen_range(0..ACTIONS.len());
+        for _ in 0..4_000 {
+            let action = agent.act(&state(good));
+            let next = rng.gen_range(0..ACTIONS.len());
+            agent.observe(Transition {
+                state: state(good),
+                action,
+                reward: (action == good) as u8 as f32,
+                next_state: Some(state(next)),
+            });
+            good = next;
+        }
+        assert!(agent.epsilon() < 0.06);
+        for good in 0..ACTIONS.len() {
+            assert_eq!(agent.greedy(&state(good)), good);
+        }
+    }
+}
@@ -0,0 +1,708 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+use crate::evolution::{self, Evolve, GenerationStats};
+use crate::network::NeuralNetwork;
+use crate::simulation::Simulation;
+
+/// How the search distribution follows the fitness of its samples.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EsMethod {
+    /// antithetic gaussian perturbations of a fixed size, the mean follows
+    /// an estimate of the natural gradient of the fitness with Adam
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// the perturbations learn their covariance and their size, which costs
+    /// memory and time quadratic in the number of parameters
+    #[serde(rename = "cma")]
+    Cma,
+}
+
+/// Settings of the evolution strategies, the population size is
+/// `population.size`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EsConfig {
+    pub method: EsMethod,
+    /// standard deviation of the perturbations, only the initial one for
+    /// CMA-ES
+    pub sigma: f32,
+    /// step size of the mean, OpenAI-ES only
+    pub learning_rate: f32,
+    /// pulls the mean toward zero, OpenAI-ES only
+    pub weight_decay: f32,
+    /// neuron count of the hidden layers of the brain searched from scratch,
+    /// those of the brain of the cars by default
+    pub hidden_layers: Option<Vec<u32>>,
+    /// CMA-ES refuses brains with more weights and biases than this
+    pub max_cma_parameters: usize,
+}
+impl Default for EsConfig {
+    fn default() -> Self {
+        Self {
+            method: EsMethod::default(),
+            sigma: 0.05,
+            learning_rate: 0.01,
+            weight_decay: 0.005,
+            hidden_layers: None,
+            max_cma_parameters: 1000,
+        }
+    }
+}
+
+/// Brains sampled around a mean network by an evolution strategy, which
+/// treats the weights and biases as one vector of parameters.
+pub struct EsPopulation {
+    pub generation: u32,
+    pub history: Vec<GenerationStats>,
+    /// every brain is shaped like it, its parameters are those of the mean
+    template: NeuralNetwork,
+    brains: Vec<NeuralNetwork>,
+    /// fittest brains of the last evaluated generation with their fitness, best first
+    champions: Vec<(NeuralNetwork, f64)>,
+    search: Box<dyn Search + Send>,
+    rng: StdRng,
+}
+
+impl EsPopulation {
+    /// `size` brains sampled around `mean`, which also gives their shape.
+    pub fn new(
+        config: EsConfig,
+        mean: NeuralNetwork,
+        size: usize,
+        seed: u64,
+    ) -> Result<Self, String> {
+        let parameters = mean.parameters();
+        let search: Box<dyn Search + Send> = match config.method {
+            EsMethod::OpenAi => Box::new(OpenAi::new(&config, parameters)),
+            EsMethod::Cma => {
+                if parameters.len() > config.max_cma_parameters {
+                    return Err(format!(
+                        "CMA-ES is limited to {} parameters but the brain has {}, \
+                         set smaller es.hidden_layers or use the openai method",
+                        config.max_cma_parameters,
+                        parameters.len()
+                    ));
+                }
+                Box::new(Cma::new(&config, parameters, size))
+            }
+        };
+        let mut population = Self {
+            generation: 0,
+            history: vec![],
+            template: mean,
+            brains: vec![],
+            champions: vec![],
+            search,
+            rng: StdRng::seed_from_u64(seed),
+        };
+        population.sample(size);
+        Ok(population)
+    }
+
+    pub fn brains(&self) -> &[NeuralNetwork] {
+        &self.brains
+    }
+
+    pub fn champions(&self) -> &[(NeuralNetwork, f64)] {
+        &self.champions
+    }
+
+    /// Moves the distribution toward the fittest brains and samples the next
+    /// generation, `fitness[i]` being the fitness of `brains()[i]`.
+    pub fn evolve(&mut self, fitness: &[f64]) -> GenerationStats {
+        assert_eq!(fitness.len(), self.brains.len());
+        let ranking = evolution::rank(fitness);
+        let stats = evolution::stats(self.generation, fitness, &ranking);
+        self.champions = ranking
+            .iter()
+            .take(2)
+            .map(|&i| (self.brains[i].clone(), fitness[i]))
+            .collect();
+        self.search.tell(fitness);
+        self.sample(self.brains.len());
+        self.history.push(stats.clone());
+        self.generation += 1;
+        stats
+    }
+
+    fn sample(&mut self, size: usize) {
+        self.brains = self
+            .search
+            .ask(size, &mut self.rng)
+            .iter()
+            .map(|parameters| {
+                let mut brain = self.template.clone();
+                brain.set_parameters(parameters);
+                brain
+            })
+            .collect();
+    }
+}
+
+impl Evolve for EsPopulation {
+    fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn next_generation(&mut self, sim: &mut Simulation) -> GenerationStats {
+        let stats = self.evolve(&sim.fitness());
+        sim.restart(&self.brains);
+        stats
+    }
+}
+
+/// A search distribution over parameter vectors.
+trait Search {
+    /// `count` parameter vectors to evaluate.
+    fn ask(&mut self, count: usize, rng: &mut StdRng) -> Vec<Vec<f32>>;
+
+    /// Moves the distribution toward the fittest of the vectors `ask` last
+    /// returned, `fitness` being in the same order.
+    fn tell(&mut self, fitness: &[f64]);
+}
+
+const BETA1: f32 = 0.9;
+const BETA2: f32 = 0.999;
+const EPSILON: f32 = 1e-8;
+
+/// Salimans et al. 2017, "Evolution Strategies as a Scalable Alternative to
+/// Reinforcement Learning".
+struct OpenAi {
+    mean: Vec<f32>,
+    sigma: f32,
+    learning_rate: f32,
+    weight_decay: f32,
+    /// one vector per pair of samples, added to and subtracted from the mean
+    noise: Vec<Vec<f32>>,
+    /// Adam moments of every parameter
+    moments: Vec<(f32, f32)>,
+    steps: i32,
+}
+
+impl OpenAi {
+    fn new(config: &EsConfig, mean: Vec<f32>) -> Self {
+        Self {
+            moments: vec![(0.0, 0.0); mean.len()],
+            mean,
+            sigma: config.sigma,
+            learning_rate: config.learning_rate,
+            weight_decay: config.weight_decay,
+            noise: vec![],
+            steps: 0,
+        }
+    }
+}
+
+impl Search for OpenAi {
+    /// Pairs of mirrored samples, the mean itself last when `count` is odd.
+    fn ask(&mut self, count: usize, rng: &mut StdRng) -> Vec<Vec<f32>> {
+        let n = self.mean.len();
+        self.noise = (0..count / 2)
+            .map(|_| (0..n).map(|_| rng.sample(StandardNormal)).collect())
+            .collect();
+        let mut samples = Vec::with_capacity(count);
+        for noise in self.noise.iter() {
+            for sign in [1.0, -1.0] {
+                let sample = self.mean.iter().zip(noise);
+                samples.push(sample.map(|(m, e)| m + sign * self.sigma * e).collect());
+            }
+        }
+        if count % 2 == 1 {
+            samples.push(self.mean.clone());
+        }
+        samples
+    }
+
+    fn tell(&mut self, fitness: &[f64]) {
+        let pairs = self.noise.len();
+        if pairs == 0 {
+            return;
+        }
+        // ranks rather than raw fitness, so that outliers do not dominate
+        let shaped = centered_ranks(&fitness[..2 * pairs]);
+        let mut gradient = vec![0.0f32; self.mean.len()];
+        for (k, noise) in self.noise.iter().enumerate() {
+            let difference = shaped[2 * k] - shaped[2 * k + 1];
+            gradient
+                .iter_mut()
+                .zip(noise)
+                .for_each(|(g, e)| *g += difference * e);
+        }
+        let scale = 1.0 / (2 * pairs) as f32 / self.sigma;
+
+        self.steps += 1;
+        let correction1 = 1.0 - BETA1.powi(self.steps);
+        let correction2 = 1.0 - BETA2.powi(self.steps);
+        for ((mean, moments), g) in self.mean.iter_mut().zip(&mut self.moments).zip(gradient) {
+            // ascent, the decay keeps the weights from growing without bound
+            let g = g * scale - self.weight_decay * *mean;
+            moments.0 = BETA1 * moments.0 + (1.0 - BETA1) * g;
+            moments.1 = BETA2 * moments.1 + (1.0 - BETA2) * g * g;
+            let m = moments.0 / correction1;
+            let v = moments.1 / correction2;
+            *mean += self.learning_rate * m / (v.sqrt() + EPSILON);
+        }
+    }
+}
+
+/// Ranks of `fitness` scaled to [-0.5, 0.5], the fittest highest.
+fn centered_ranks(fitness: &[f64]) -> Vec<f32> {
+    let n = fitness.len();
+    let mut shaped = vec![0.0; n];
+    for (position, &i) in evolution::rank(fitness).iter().enumerate() {
+        shaped[i] = if n > 1 {
+            0.5 - position as f32 / (n - 1) as f32
+        } else {
+            0.0
+        };
+    }
+    shaped
+}
+
+/// Hansen 2016, "The CMA Evolution Strategy: A Tutorial", with its default
+/// parameters.
+struct Cma {
+    mean: Vec<f64>,
+    sigma: f64,
+    /// the fittest `weights.len()` samples make the new mean
+    weights: Vec<f64>,
+    mu_eff: f64,
+    cc: f64,
+    cs: f64,
+    c1: f64,
+    cmu: f64,
+    damps: f64,
+    /// expected length of a standard normal vector
+    chi_n: f64,
+    /// evolution paths of the covariance and of the step size
+    pc: Vec<f64>,
+    ps: Vec<f64>,
+    covariance: Vec<Vec<f64>>,
+    /// eigenvectors of the covariance, in columns
+    b: Vec<Vec<f64>>,
+    /// square roots of the eigenvalues of the covariance
+    d: Vec<f64>,
+    /// samples evaluated so far and when the covariance was last decomposed
+    evaluations: usize,
+    decomposed_at: usize,
+    samples: Vec<Vec<f64>>,
+}
+
+impl Cma {
+    fn new(config: &EsConfig, mean: Vec<f32>, lambda: usize) -> Self {
+        let n = mean.len();
+        let nf = n as f64;
+        let mu = (lambda / 2).max(1);
+        let weights: Vec<f64> = (0..mu)
+            .map(|i| (mu as f64 + 0.5).ln() - ((i + 1) as f64).ln())
+            .collect();
+        let total: f64 = weights.iter().sum();
+        let weights: Vec<f64> = weights.iter().map(|w| w / total).collect();
+        let mu_eff = 1.0 / weights.iter().map(|w| w * w).sum::<f64>();
+
+        let cc = (4.0 + mu_eff / nf) / (nf + 4.0 + 2.0 * mu_eff / nf);
+        let cs = (mu_eff + 2.0) / (nf + mu_eff + 5.0);
+        let c1 = 2.0 / ((nf + 1.3).powi(2) + mu_eff);
+        let cmu =
+            (1.0 - c1).min(2.0 * (mu_eff - 2.0 + 1.0 / mu_eff) / ((nf + 2.0).powi(2) + mu_eff));
+        let damps = 1.0 + 2.0 * (((mu_eff - 1.0) / (nf + 1.0)).sqrt() - 1.0).max(0.0) + cs;
+        let identity: Vec<Vec<f64>> = (0..n)
+            .map(|i| (0..n).map(|j| (i == j) as u8 as f64).collect())
+            .collect();
+        Self {
+            mean: mean.iter().map(|&m| m as f64).collect(),
+            sigma: config.sigma as f64,
+            weights,
+            mu_eff,
+            cc,
+            cs,
+            c1,
+            cmu,
+            damps,
+            chi_n: nf.sqrt() * (1.0 - 1.0 / (4.0 * nf) + 1.0 / (21.0 * nf * nf)),
+            pc: vec![0.0; n],
+            ps: vec![0.0; n],
+            covariance: identity.clone(),
+            b: identity,
+            d: vec![1.0; n],
+            evaluations: 0,
+            decomposed_at: 0,
+            samples: vec![],
+        }
+    }
+
+    /// B·D·z, a sample of the normal distribution with the covariance.
+    fn scale(&self, z: &[f64]) -> Vec<f64> {
+        let dz: Vec<f64> = self.d.iter().zip(z).map(|(d, z)| d * z).collect();
+        self.b
+            .iter()
+            .map(|row| row.iter().zip(&dz).map(|(b, x)| b * x).sum())
+            .collect()
+    }
+
+    /// C^-1/2·y = B·D^-1·Bᵀ·y.
+    fn whiten(&self, y: &[f64]) -> Vec<f64> {
+        let n = y.len();
+        let mut t = vec![0.0; n];
+        for (row, y) in self.b.iter().zip(y) {
+            t.iter_mut().zip(row).for_each(|(t, b)| *t += b * y);
+        }
+        t.iter_mut().zip(&self.d).for_each(|(t, d)| *t /= d);
+        self.b
+            .iter()
+            .map(|row| row.iter().zip(&t).map(|(b, t)| b * t).sum())
+            .collect()
+    }
+}
+
+impl Search for Cma {
+    fn ask(&mut self, count: usize, rng: &mut StdRng) -> Vec<Vec<f32>> {
+        let n = self.mean.len();
+        self.samples = (0..count)
+            .map(|_| {
+                let z: Vec<f64> = (0..n).map(|_| rng.sample(StandardNormal)).collect();
+                let y = self.scale(&z);
+                self.mean
+                    .iter()
+                    .zip(y)
+                    .map(|(m, y)| m + self.sigma * y)
+                    .collect()
+            })
+            .collect();
+        self.samples
+            .iter()
+            .map(|x| x.iter().map(|&v| v as f32).collect())
+            .collect()
+    }
+
+    fn tell(&mut self, fitness: &[f64]) {
+        let n = self.mean.len();
+        let lambda = self.samples.len();
+        self.evaluations += lambda;
+        let ranking = evolution::rank(fitness);
+        let selected: Vec<&Vec<f64>> = ranking
+            .iter()
+            .take(self.weights.len())
+            .map(|&i| &self.samples[i])
+            .collect();
+
+        let old_mean = self.mean.clone();
+        self.mean = vec![0.0; n];
+        for (w, x) in self.weights.iter().zip(&selected) {
+            self.mean
+                .iter_mut()
+                .zip(x.iter())
+                .for_each(|(m, x)| *m += w * x);
+        }
+        let y_w: Vec<f64> = self
+            .mean
+            .iter()
+            .zip(&old_mean)
+            .map(|(m, o)| (m - o) / self.sigma)
+            .collect();
+
+        let cs_factor = (self.cs * (2.0 - self.cs) * self.mu_eff).sqrt();
+        let whitened = self.whiten(&y_w);
+        for (ps, w) in self.ps.iter_mut().zip(whitened) {
+            *ps = (1.0 - self.cs) * *ps + cs_factor * w;
+        }
+        let ps_norm = self.ps.iter().map(|p| p * p).sum::<f64>().sqrt();
+        let generations = (self.evaluations / lambda.max(1)) as i32;
+        // stalls the covariance path while the step size path is long
+        let hsig = ps_norm / (1.0 - (1.0 - self.cs).powi(2 * generations)).sqrt() / self.chi_n
+            < 1.4 + 2.0 / (n as f64 + 1.0);
+        let hsig = hsig as u8 as f64;
+        let cc_factor = (self.cc * (2.0 - self.cc) * self.mu_eff).sqrt();
+        for (pc, y) in self.pc.iter_mut().zip(&y_w) {
+            *pc = (1.0 - self.cc) * *pc + hsig * cc_factor * y;
+        }
+
+        let steps: Vec<Vec<f64>> = selected
+            .iter()
+            .map(|x| {
+                x.iter()
+                    .zip(&old_mean)
+                    .map(|(x, o)| (x - o) / self.sigma)
+                    .collect()
+            })
+            .collect();
+        let keep = 1.0 - self.c1 - self.cmu + (1.0 - hsig) * self.c1 * self.cc * (2.0 - self.cc);
+        for i in 0..n {
+            for j in 0..=i {
+                let rank_mu: f64 = self
+                    .weights
+                    .iter()
+                    .zip(&steps)
+                    .map(|(w, s)| w * s[i] * s[j])
+                    .sum();
+                let value = keep * self.covariance[i][j]
+                    + self.c1 * self.pc[i] * self.pc[j]
+                    + self.cmu * rank_mu;
+                self.covariance[i][j] = value;
+                self.covariance[j][i] = value;
+            }
+        }
+        self.sigma *= ((self.cs / self.damps) * (ps_norm / self.chi_n - 1.0)).exp();
+
+        // decomposing is cubic in n, and the covariance changes slowly
+        let interval = lambda as f64 / (self.c1 + self.cmu) / n as f64 / 10.0;
+        if (self.evaluations - self.decomposed_at) as f64 > interval {
+            self.decomposed_at = self.evaluations;
+            let mut vectors = self.covariance.clone();
+            let mut values = vec![0.0; n];
+            symmetric_eigen(&mut vectors, &mut values);
+            self.b = vectors;
+            self.d = values.iter().map(|v| v.max(1e-20).sqrt()).collect();
+        }
+    }
+}
+
+/// Eigen decomposition of the symmetric matrix in `v`, which is replaced by
+/// the eigenvectors in columns while `d` receives the eigenvalues.
+/// Householder tridiagonalization and the QL algorithm, after JAMA.
+fn symmetric_eigen(v: &mut [Vec<f64>], d: &mut [f64]) {
+    let mut e = vec![0.0; d.len()];
+    tridiagonalize(v, d, &mut e);
+    diagonalize(v, d, &mut e);
+}
+
+fn tridiagonalize(v: &mut [Vec<f64>], d: &mut [f64], e: &mut [f64]) {
+    let n = d.len();
+    if n == 0 {
+        return;
+    }
+    d.copy_from_slice(&v[n - 1]);
+    for i in (1..n).rev() {
+        let scale: f64 = d[..i].iter().map(|x| x.abs()).sum();
+        let mut h = 0.0;
+        if scale == 0.0 {
+            e[i] = d[i - 1];
+            for j in 0..i {
+                d[j] = v[i - 1][j];
+                v[i][j] = 0.0;
+                v[j][i] = 0.0;
+            }
+        } else {
+            for x in d[..i].iter_mut() {
+                *x /= scale;
+                h += *x * *x;
+            }
+            let mut f = d[i - 1];
+            let mut g = if f > 0.0 { -h.sqrt() } else { h.sqrt() };
+            e[i] = scale * g;
+            h -= f * g;
+            d[i - 1] = f - g;
+            e[..i].iter_mut().for_each(|x| *x = 0.0);
+            for j in 0..i {
+                f = d[j];
+                v[j][i] = f;
+                g = e[j] + v[j][j] * f;
+                for k in j + 1..i {
+                    g += v[k][j] * d[k];
+                    e[k] += v[k][j] * f;
+                }
+                e[j] = g;
+            }
+            f = 0.0;
+            for j in 0..i {
+                e[j] /= h;
+                f += e[j] * d[j];
+            }
+            let hh = f / (h + h);
+            for j in 0..i {
+                e[j] -= hh * d[j];
+            }
+            for j in 0..i {
+                f = d[j];
+                g = e[j];
+                for k in j..i {
+                    v[k][j] -= f * e[k] + g * d[k];
+                }
+                d[j] = v[i - 1][j];
+                v[i][j] = 0.0;
+            }
+        }
+        d[i] = h;
+    }
+
+    // accumulates the transformations
+    for i in 0..n - 1 {
+        v[n - 1][i] = v[i][i];
+        v[i][i] = 1.0;
+        let h = d[i + 1];
+        if h != 0.0 {
+            for k in 0..=i {
+                d[k] = v[k][i + 1] / h;
+            }
+            for j in 0..=i {
+                let g: f64 = (0..=i).map(|k| v[k][i + 1] * v[k][j]).sum();
+                for k in 0..=i {
+                    v[k][j] -= g * d[k];
+                }
+            }
+        }
+        for row in v[..=i].iter_mut() {
+            row[i + 1] = 0.0;
+        }
+    }
+    for j in 0..n {
+        d[j] = v[n - 1][j];
+        v[n - 1][j] = 0.0;
+    }
+    v[n - 1][n - 1] = 1.0;
+    e[0] = 0.0;
+}
+
+fn diagonalize(v: &mut [Vec<f64>], d: &mut [f64], e: &mut [f64]) {
+    let n = d.len();
+    if n == 0 {
+        return;
+    }
+    for i in 1..n {
+        e[i - 1] = e[i];
+    }
+    e[n - 1] = 0.0;
+
+    let mut f = 0.0;
+    let mut largest = 0.0f64;
+    let eps = f64::EPSILON;
+    for l in 0..n {
+        largest = largest.max(d[l].abs() + e[l].abs());
+        let mut m = l;
+        while m < n - 1 && e[m].abs() > eps * largest {
+            m += 1;
+        }
+        if m > l {
+            loop {
+                // implicit shift
+                let mut g = d[l];
+                let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+                let mut r = p.hypot(1.0);
+                if p < 0.0 {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let mut h = g - d[l];
+                for x in d[l + 2..].iter_mut() {
+                    *x -= h;
+                }
+                f += h;
+
+                // implicit QL transformation
+                p = d[m];
+                let (mut c, mut c2, mut c3) = (1.0, 1.0, 1.0);
+                let el1 = e[l + 1];
+                let (mut s, mut s2) = (0.0, 0.0);
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    g = c * e[i];
+                    h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+                    for row in v.iter_mut() {
+                        h = row[i + 1];
+                        row[i + 1] = s * row[i] + c * h;
+                        row[i] = c * row[i] - s * h;
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+                if e[l].abs() <= eps * largest {
+                    break;
+                }
+            }
+        }
+        d[l] += f;
+        e[l] = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Fitness of a brain whose parameters should all be 0.5.
+    fn closeness(brain: &NeuralNetwork) -> f64 {
+        -brain
+            .parameters()
+            .iter()
+            .map(|p| (p - 0.5).powi(2) as f64)
+            .sum::<f64>()
+    }
+
+    fn optimize(config: EsConfig, generations: u32) -> (f64, f64) {
+        let mut mean = NeuralNetwork::new(&[3, 4, 2]);
+        mean.set_parameters(&vec![0.0; mean.parameter_count()]);
+        let start = closeness(&mean);
+        // odd, so that the mean of OpenAI-ES is among the brains
+        let mut population = EsPopulation::new(config, mean, 21, 22).unwrap();
+        for _ in 0..generations {
+            let fitness: Vec<f64> = population.brains().iter().map(closeness).collect();
+            population.evolve(&fitness);
+        }
+        (start, population.champions()[0].1)
+    }
+
+    #[test]
+    fn openai_climbs_toward_the_optimum() {
+        let config = EsConfig {
+            learning_rate: 0.05,
+            weight_decay: 0.0,
+            ..EsConfig::default()
+        };
+        let (start, end) = optimize(config, 100);
+        assert!(end > start / 20.0, "{} then {}", start, end);
+    }
+
+    #[test]
+    fn cma_adapts_its_step_size() {
+        let config = EsConfig {
+            method: EsMethod::Cma,
+            sigma: 0.3,
+            ..EsConfig::default()
+        };
+        let (start, end) = optimize(config, 150);
+        assert!(end > start / 1000.0, "{} then {}", start, end);
+
+        let too_big = EsConfig {
+            method: EsMethod::Cma,
+            max_cma_parameters: 10,
+            ..EsConfig::default()
+        };
+        assert!(EsPopulation::new(too_big, NeuralNetwork::new(&[3, 4, 2]), 10, 0).is_err());
+    }
+
+    #[test]
+    fn decomposes_symmetric_matrices() {
+        let matrix = [
+            vec![4.0, 1.0, -2.0, 0.5],
+            vec![1.0, 3.0, 0.0, 1.5],
+            vec![-2.0, 0.0, 5.0, -1.0],
+            vec![0.5, 1.5, -1.0, 2.0],
+        ];
+        let mut vectors = matrix.to_vec();
+        let mut values = vec![0.0; 4];
+        symmetric_eigen(&mut vectors, &mut values);
+        // A·v = λ·v for every column v
+        for (k, value) in values.iter().enumerate() {
+            for i in 0..4 {
+                let av: f64 = (0..4).map(|j| matrix[i][j] * vectors[j][k]).sum();
+                assert!((av - value * vectors[i][k]).abs() < 1e-9);
+            }
+        }
+        assert!((values.iter().sum::<f64>() - 14.0).abs() < 1e-9);
+    }
+}
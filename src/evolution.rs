@@ -0,0 +1,371 @@
+use std::fmt;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::brain::Brain;
+use crate::network::{Crossover, MutationParams, NeuralNetwork};
+use crate::simulation::Simulation;
+
+/// How parents are picked from an evaluated generation.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Selection {
+    /// parents are drawn uniformly from the `count` fittest brains
+    Elitism { count: usize },
+    /// best of `size` brains drawn at random
+    Tournament { size: usize },
+    /// chance proportional to fitness
+    Roulette,
+    /// chance proportional to the position in the ranking
+    Rank,
+}
+
+/// How a child is derived from its parent.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Mutation {
+    /// a fresh random brain pulled toward the parent, by 0.92 most of the time
+    /// and by 0.5 once in four children
+    RandomizeAndLerp,
+    Gaussian(MutationParams),
+}
+
+/// How the gaussian mutation strength changes from one generation to the next.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MutationSchedule {
+    Constant,
+    /// sigma is multiplied by `factor` every generation
+    Decay { factor: f32, min_sigma: f32 },
+    /// sigma grows by `factor` while the best fitness stagnates and shrinks
+    /// by it when it improves
+    Adaptive {
+        factor: f32,
+        min_sigma: f32,
+        max_sigma: f32,
+    },
+}
+
+pub struct EvolutionConfig {
+    pub selection: Selection,
+    /// how many of the fittest brains are copied unchanged into the next generation
+    pub elite_count: usize,
+    /// when set, children are bred from two selected parents instead of one
+    pub crossover: Option<Crossover>,
+    pub mutation: Mutation,
+    /// only affects `Mutation::Gaussian`
+    pub mutation_schedule: MutationSchedule,
+}
+impl Default for EvolutionConfig {
+    fn default() -> Self {
+        Self {
+            selection: Selection::Elitism { count: 2 },
+            elite_count: 2,
+            crossover: None,
+            mutation: Mutation::RandomizeAndLerp,
+            mutation_schedule: MutationSchedule::Constant,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GenerationStats {
+    pub generation: u32,
+    pub best: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub worst: f64,
+    /// species the generation was split into, only for NEAT
+    pub species: Option<usize>,
+}
+impl fmt::Display for GenerationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "generation {}: best = {:.1}, mean = {:.1}, median = {:.1}, worst = {:.1}",
+            self.generation, self.best, self.mean, self.median, self.worst
+        )?;
+        if let Some(species) = self.species {
+            write!(f, ", species = {}", species)?;
+        }
+        Ok(())
+    }
+}
+
+/// A population the simulation evaluates one generation after the other.
+pub trait Evolve {
+    fn generation(&self) -> u32;
+
+    /// Scores the generation that just ran in `sim` and restarts it with the next one.
+    fn next_generation(&mut self, sim: &mut Simulation) -> GenerationStats;
+}
+
+pub struct Population {
+    pub config: EvolutionConfig,
+    pub generation: u32,
+    pub history: Vec<GenerationStats>,
+    brains: Vec<NeuralNetwork>,
+    /// fittest brains of the last evaluated generation with their fitness, best first
+    champions: Vec<(NeuralNetwork, f64)>,
+    /// current mutation, as moved along by the schedule
+    mutation: Mutation,
+    rng: StdRng,
+}
+
+impl Population {
+    pub fn new(config: EvolutionConfig, brains: Vec<NeuralNetwork>, seed: u64) -> Self {
+        Self {
+            mutation: config.mutation,
+            config,
+            generation: 0,
+            history: vec![],
+            brains,
+            champions: vec![],
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Starts from the brains the simulation cars were created with.
+    pub fn from_simulation(config: EvolutionConfig, sim: &Simulation) -> Self {
+        let brains = sim
+            .cars
+            .iter()
+            .filter_map(|c| c.brain.as_ref().and_then(Brain::as_dense).cloned())
+            .collect();
+        Self::new(config, brains, sim.seed())
+    }
+
+    pub fn brains(&self) -> &[NeuralNetwork] {
+        &self.brains
+    }
+
+    pub fn champions(&self) -> &[(NeuralNetwork, f64)] {
+        &self.champions
+    }
+
+    #[cfg(test)]
+    pub fn mutation(&self) -> Mutation {
+        self.mutation
+    }
+
+    /// Replaces the current generation with its offspring, `fitness[i]` being
+    /// the fitness of `brains()[i]`.
+    pub fn evolve(&mut self, fitness: &[f64]) -> GenerationStats {
+        assert_eq!(fitness.len(), self.brains.len());
+        let ranking = rank(fitness);
+        let stats = stats(self.generation, fitness, &ranking);
+
+        let mut next = Vec::with_capacity(self.brains.len());
+        for &i in ranking.iter().take(self.config.elite_count.min(self.brains.len())) {
+            next.push(self.brains[i].clone());
+        }
+        while next.len() < self.brains.len() {
+            let parent = select(self.config.selection, fitness, &ranking, &mut self.rng);
+            let base = match self.config.crossover {
+                Some(method) => {
+                    let other = select(self.config.selection, fitness, &ranking, &mut self.rng);
+                    let parents = [&self.brains[parent], &self.brains[other]];
+                    NeuralNetwork::crossover(&parents, method, &mut self.rng)
+                        .unwrap_or_else(|_| self.brains[parent].clone())
+                }
+                None => self.brains[parent].clone(),
+            };
+            next.push(breed(&base, self.mutation, &mut self.rng));
+        }
+
+        self.champions = ranking
+            .iter()
+            .take(2)
+            .map(|&i| (self.brains[i].clone(), fitness[i]))
+            .collect();
+        self.brains = next;
+        self.update_mutation(&stats);
+        self.history.push(stats.clone());
+        self.generation += 1;
+        stats
+    }
+
+    fn update_mutation(&mut self, stats: &GenerationStats) {
+        let Mutation::Gaussian(params) = &mut self.mutation else {
+            return;
+        };
+        match self.config.mutation_schedule {
+            MutationSchedule::Constant => {}
+            MutationSchedule::Decay { factor, min_sigma } => {
+                params.sigma = (params.sigma * factor).max(min_sigma);
+            }
+            MutationSchedule::Adaptive {
+                factor,
+                min_sigma,
+                max_sigma,
+            } => {
+                let previous_best = self.history.iter().map(|s| s.best).reduce(f64::max);
+                let improved = previous_best.is_none_or(|best| stats.best > best);
+                let sigma = if improved {
+                    params.sigma / factor
+                } else {
+                    params.sigma * factor
+                };
+                params.sigma = sigma.clamp(min_sigma, max_sigma);
+            }
+        }
+    }
+}
+
+impl Evolve for Population {
+    fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn next_generation(&mut self, sim: &mut Simulation) -> GenerationStats {
+        let stats = self.evolve(&sim.fitness());
+        sim.restart(&self.brains);
+        stats
+    }
+}
+
+/// Best, mean, median and worst of `fitness`, `ranking` being its indices
+/// from the fittest to the least fit.
+pub fn stats(generation: u32, fitness: &[f64], ranking: &[usize]) -> GenerationStats {
+    let n = fitness.len();
+    let (best, worst, median) = if n == 0 {
+        (0.0, 0.0, 0.0)
+    } else {
+        (
+            fitness[ranking[0]],
+            fitness[ranking[n - 1]],
+            fitness[ranking[n / 2]],
+        )
+    };
+    GenerationStats {
+        generation,
+        best,
+        mean: fitness.iter().sum::<f64>() / n.max(1) as f64,
+        median,
+        worst,
+        species: None,
+    }
+}
+
+/// Indices sorted from the fittest to the least fit.
+pub fn rank(fitness: &[f64]) -> Vec<usize> {
+    let mut ranking: Vec<usize> = (0..fitness.len()).collect();
+    ranking.sort_by(|&a, &b| {
+        fitness[b]
+            .partial_cmp(&fitness[a])
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    ranking
+}
+
+fn select(selection: Selection, fitness: &[f64], ranking: &[usize], rng: &mut impl Rng) -> usize {
+    let n = ranking.len();
+    match selection {
+        Selection::Elitism { count } => ranking[rng.gen_range(0..count.clamp(1, n))],
+        Selection::Tournament { size } => (0..size.max(1))
+            .map(|_| rng.gen_range(0..n))
+            .min()
+            .map(|pos| ranking[pos])
+            .unwrap(),
+        Selection::Roulette => {
+            // shift so that the least fit brain still has a small chance
+            let min = fitness[ranking[n - 1]];
+            let weights: Vec<f64> = fitness.iter().map(|f| f - min + 1.0).collect();
+            pick_weighted(&weights, rng)
+        }
+        Selection::Rank => {
+            let pos = pick_weighted(
+                &(0..n).map(|pos| (n - pos) as f64).collect::<Vec<_>>(),
+                rng,
+            );
+            ranking[pos]
+        }
+    }
+}
+
+fn pick_weighted(weights: &[f64], rng: &mut impl Rng) -> usize {
+    let total: f64 = weights.iter().sum();
+    let mut target = rng.gen_range(0.0..total);
+    for (i, w) in weights.iter().enumerate() {
+        if target < *w {
+            return i;
+        }
+        target -= w;
+    }
+    weights.len() - 1
+}
+
+fn breed(parent: &NeuralNetwork, mutation: Mutation, rng: &mut impl Rng) -> NeuralNetwork {
+    let mut child = parent.clone();
+    match mutation {
+        Mutation::RandomizeAndLerp => {
+            let t = if rng.gen_range(1..5) == 1 { 0.5 } else { 0.92 };
+            child.randomize(rng);
+            child.prune(parent, t);
+        }
+        Mutation::Gaussian(params) => child.mutate(&params, rng),
+    }
+    child
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_elites_and_size() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let brains: Vec<NeuralNetwork> = (0..10)
+            .map(|_| {
+                let mut brain = NeuralNetwork::new(&[3, 4, 2]);
+                brain.randomize(&mut rng);
+                brain
+            })
+            .collect();
+        let best = brains[6].clone();
+        let config = EvolutionConfig {
+            selection: Selection::Tournament { size: 3 },
+            elite_count: 1,
+            crossover: Some(Crossover::Neuron),
+            ..Default::default()
+        };
+        let mut population = Population::new(config, brains, 7);
+        let fitness: Vec<f64> = (0..10).map(|i| if i == 6 { 100.0 } else { i as f64 }).collect();
+        let stats = population.evolve(&fitness);
+
+        assert_eq!(population.brains().len(), 10);
+        assert_eq!(population.generation, 1);
+        assert_eq!(stats.best, 100.0);
+        assert_eq!(stats.worst, 0.0);
+        assert_eq!(population.brains()[0].levels[0].weights, best.levels[0].weights);
+    }
+
+    #[test]
+    fn decays_mutation_strength() {
+        let brains = vec![NeuralNetwork::new(&[3, 2]); 4];
+        let config = EvolutionConfig {
+            mutation: Mutation::Gaussian(MutationParams {
+                sigma: 0.4,
+                ..Default::default()
+            }),
+            mutation_schedule: MutationSchedule::Decay {
+                factor: 0.5,
+                min_sigma: 0.15,
+            },
+            ..Default::default()
+        };
+        let mut population = Population::new(config, brains, 1);
+        let sigmas: Vec<f32> = (0..3)
+            .map(|_| {
+                population.evolve(&[1.0, 2.0, 3.0, 4.0]);
+                match population.mutation() {
+                    Mutation::Gaussian(params) => params.sigma,
+                    Mutation::RandomizeAndLerp => unreachable!(),
+                }
+            })
+            .collect();
+        assert_eq!(sigmas, vec![0.2, 0.15, 0.15]);
+    }
+}
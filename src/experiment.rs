@@ -0,0 +1,664 @@
+use serde::{Deserialize, Serialize};
+
+use crate::binary::WeightEncoding;
+use crate::car::ControlMode;
+use crate::dqn::DqnConfig;
+use crate::es::EsConfig;
+use crate::evolution::{EvolutionConfig, Mutation, MutationSchedule, Selection};
+use crate::fitness::{self, Fitness, Weighted};
+use crate::imitation::ImitationConfig;
+use crate::inference::Backend;
+use crate::neat::NeatConfig;
+use crate::network::{Activation, Crossover, LevelKind};
+use crate::observation::ObservationConfig;
+use crate::simulation::SimulationConfig;
+
+pub const DEFAULT_PATH: &str = "experiments/default.toml";
+
+/// Everything describing a training run, loaded from a TOML file so that
+/// presets can be kept under `experiments/`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Experiment {
+    pub name: String,
+    /// size of the rayon thread pool
+    pub threads: usize,
+    /// where the brains of the cars are evaluated
+    pub inference: Backend,
+    pub window: WindowConfig,
+    pub world: WorldConfig,
+    pub traffic: TrafficConfig,
+    /// what the brains are fed besides the sensor readings
+    pub observation: ObservationConfig,
+    pub population: PopulationConfig,
+    /// when present the population evolves NEAT genomes of `population.size`
+    /// instead of dense brains, and the rest of `population` is ignored
+    pub neat: Option<NeatConfig>,
+    /// when present the brains of `population.size` are sampled around a
+    /// mean by an evolution strategy instead of bred, only `population.memory`
+    /// and `population.control` apply of the rest of `population`
+    pub es: Option<EsConfig>,
+    pub fitness: FitnessConfig,
+    /// how `imitate` fits a brain to recorded driving
+    pub imitation: ImitationConfig,
+    /// how `dqn` trains a single car by reinforcement learning
+    pub dqn: DqnConfig,
+    pub output: OutputConfig,
+}
+impl Default for Experiment {
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            threads: 16,
+            inference: Backend::default(),
+            window: WindowConfig::default(),
+            world: WorldConfig::default(),
+            traffic: TrafficConfig::default(),
+            observation: ObservationConfig::default(),
+            population: PopulationConfig::default(),
+            neat: None,
+            es: None,
+            fitness: FitnessConfig::default(),
+            imitation: ImitationConfig::default(),
+            dqn: DqnConfig::default(),
+            output: OutputConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 1080,
+            height: 800,
+            fps: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorldConfig {
+    pub lanes: i32,
+    /// road width as a fraction of the window width
+    pub road_width: f32,
+    pub seed: Option<u64>,
+    /// in seconds
+    pub fixed_delta_t_s: Option<f32>,
+    /// in seconds
+    pub generation_time_limit_s: f32,
+}
+impl Default for WorldConfig {
+    fn default() -> Self {
+        Self {
+            lanes: 3,
+            road_width: 0.3,
+            seed: None,
+            fixed_delta_t_s: None,
+            generation_time_limit_s: 60.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafficConfig {
+    pub size: u32,
+    /// in meters per second
+    pub min_velocity: f32,
+}
+impl Default for TrafficConfig {
+    fn default() -> Self {
+        Self {
+            size: 4,
+            min_velocity: 27.33, // ~98 km/h
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PopulationConfig {
+    pub size: u32,
+    pub elite_count: usize,
+    pub selection: Selection,
+    pub crossover: Option<Crossover>,
+    pub mutation: Mutation,
+    pub mutation_schedule: MutationSchedule,
+    /// kind of the last hidden level of new brains, a recurrent one lets
+    /// them remember earlier frames
+    pub memory: Option<LevelKind>,
+    /// how cars read the outputs of new brains, brains loaded to start from
+    /// must read them the same way
+    pub control: ControlMode,
+}
+impl Default for PopulationConfig {
+    fn default() -> Self {
+        let evolution = EvolutionConfig::default();
+        Self {
+            size: 200,
+            elite_count: evolution.elite_count,
+            selection: evolution.selection,
+            crossover: evolution.crossover,
+            mutation: evolution.mutation,
+            mutation_schedule: evolution.mutation_schedule,
+            memory: None,
+            control: ControlMode::Discrete,
+        }
+    }
+}
+
+/// Weights of the built-in fitness functions, zero leaves one out.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FitnessConfig {
+    pub distance: f64,
+    pub time_alive: f64,
+    pub overtakes: f64,
+    pub lane_deviation: f64,
+    pub harsh_steering: f64,
+    /// in degrees per second
+    pub harsh_steering_max_rate: f32,
+    pub collisions: f64,
+}
+impl Default for FitnessConfig {
+    fn default() -> Self {
+        Self {
+            distance: 1.0,
+            time_alive: 0.0,
+            overtakes: 20.0,
+            lane_deviation: -2.0,
+            harsh_steering: -1.0,
+            harsh_steering_max_rate: 120.0,
+            collisions: -100.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// brains are loaded from and saved to these files
+    pub best_brain: String,
+    pub second_best_brain: String,
+    /// when set, brains are saved in the binary format with their weights
+    /// encoded this way instead of as JSON
+    pub weights: Option<WeightEncoding>,
+    /// when set, every brain of the population is saved there in the binary
+    /// format along with the champions, and `train` resumes from it
+    pub population: Option<String>,
+}
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            best_brain: "brains/best.json".to_string(),
+            second_best_brain: "brains/second_best.json".to_string(),
+            weights: None,
+            population: None,
+        }
+    }
+}
+
+impl Experiment {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read experiment file {}: {}", path, e))?;
+        let experiment: Experiment =
+            toml::from_str(&content).map_err(|e| format!("invalid experiment file {}: {}", path, e))?;
+        experiment
+            .validate()
+            .map_err(|e| format!("invalid experiment file {}:\n{}", path, e))?;
+        Ok(experiment)
+    }
+
+    /// Checks the values serde cannot, all problems are reported at once, one per line.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, msg: String| {
+            if !ok {
+                errors.push(msg);
+            }
+        };
+
+        check(self.threads > 0, "threads must be at least 1".to_string());
+        check(
+            self.window.width > 0 && self.window.height > 0,
+            format!(
+                "window must not be empty, got {}x{}",
+                self.window.width, self.window.height
+            ),
+        );
+        check(self.window.fps > 0, "window.fps must be at least 1".to_string());
+        check(
+            self.world.lanes > 0,
+            format!("world.lanes must be at least 1, got {}", self.world.lanes),
+        );
+        check(
+            self.world.road_width > 0.0 && self.world.road_width <= 1.0,
+            format!(
+                "world.road_width is a fraction of the window width and must be in (0, 1], got {}",
+                self.world.road_width
+            ),
+        );
+        if let Some(dt) = self.world.fixed_delta_t_s {
+            check(
+                dt > 0.0,
+                format!("world.fixed_delta_t_s must be positive, got {}", dt),
+            );
+        }
+        check(
+            self.world.generation_time_limit_s > 0.0,
+            format!(
+                "world.generation_time_limit_s must be positive, got {}",
+                self.world.generation_time_limit_s
+            ),
+        );
+        check(
+            self.traffic.min_velocity >= 0.0,
+            format!(
+                "traffic.min_velocity must not be negative, got {}",
+                self.traffic.min_velocity
+            ),
+        );
+        let features = &self.observation.features;
+        for (i, feature) in features.iter().enumerate() {
+            check(
+                !features[..i].contains(feature),
+                format!("observation.features lists {:?} more than once", feature),
+            );
+        }
+
+        let population = &self.population;
+        check(
+            population.size >= 2,
+            format!("population.size must be at least 2, got {}", population.size),
+        );
+        check(
+            population.elite_count < population.size as usize,
+            format!(
+                "population.elite_count must be smaller than population.size, got {} of {}",
+                population.elite_count, population.size
+            ),
+        );
+        match population.selection {
+            Selection::Elitism { count } => check(
+                count > 0,
+                "population.selection.count must be at least 1".to_string(),
+            ),
+            Selection::Tournament { size } => check(
+                size > 0,
+                "population.selection.size must be at least 1".to_string(),
+            ),
+            Selection::Roulette | Selection::Rank => {}
+        }
+        if let Mutation::Gaussian(params) = population.mutation {
+            check(
+                (0.0..=1.0).contains(&params.rate),
+                format!("population.mutation.rate must be in [0, 1], got {}", params.rate),
+            );
+            check(
+                (0.0..=1.0).contains(&params.reset_rate),
+                format!(
+                    "population.mutation.reset_rate must be in [0, 1], got {}",
+                    params.reset_rate
+                ),
+            );
+            check(
+                params.sigma >= 0.0,
+                format!("population.mutation.sigma must not be negative, got {}", params.sigma),
+            );
+            if let Some(clamp) = params.clamp {
+                check(
+                    clamp > 0.0,
+                    format!("population.mutation.clamp must be positive, got {}", clamp),
+                );
+            }
+        }
+        match population.mutation_schedule {
+            MutationSchedule::Constant => {}
+            MutationSchedule::Decay { factor, .. } => check(
+                factor > 0.0 && factor <= 1.0,
+                format!("population.mutation_schedule.factor must be in (0, 1], got {}", factor),
+            ),
+            MutationSchedule::Adaptive {
+                factor,
+                min_sigma,
+                max_sigma,
+            } => {
+                check(
+                    factor >= 1.0,
+                    format!(
+                        "population.mutation_schedule.factor must be at least 1, got {}",
+                        factor
+                    ),
+                );
+                check(
+                    min_sigma <= max_sigma,
+                    format!(
+                        "population.mutation_schedule.min_sigma must not exceed max_sigma, got {} > {}",
+                        min_sigma, max_sigma
+                    ),
+                );
+            }
+        }
+        if let Some(neat) = &self.neat {
+            for (name, rate) in [
+                ("add_node_rate", neat.add_node_rate),
+                ("add_connection_rate", neat.add_connection_rate),
+                ("disable_connection_rate", neat.disable_connection_rate),
+                ("crossover_rate", neat.crossover_rate),
+                ("weights.rate", neat.weights.rate),
+                ("weights.reset_rate", neat.weights.reset_rate),
+            ] {
+                check(
+                    (0.0..=1.0).contains(&rate),
+                    format!("neat.{} must be in [0, 1], got {}", name, rate),
+                );
+            }
+            check(
+                neat.survival_threshold > 0.0 && neat.survival_threshold <= 1.0,
+                format!(
+                    "neat.survival_threshold must be in (0, 1], got {}",
+                    neat.survival_threshold
+                ),
+            );
+            check(
+                neat.compatibility_threshold > 0.0,
+                format!(
+                    "neat.compatibility_threshold must be positive, got {}",
+                    neat.compatibility_threshold
+                ),
+            );
+            check(
+                neat.weights.sigma >= 0.0,
+                format!("neat.weights.sigma must not be negative, got {}", neat.weights.sigma),
+            );
+            for (name, activation) in [
+                ("hidden_activation", neat.hidden_activation),
+                ("output_activation", neat.output_activation),
+            ] {
+                check(
+                    activation != Activation::Softmax,
+                    format!("neat.{} cannot be softmax, nodes are activated one by one", name),
+                );
+            }
+            check(
+                self.output.weights.is_none(),
+                "output.weights cannot be set with neat, genomes are only saved as JSON"
+                    .to_string(),
+            );
+            check(
+                self.population.control == ControlMode::Discrete,
+                "population.control cannot be analog with neat, genomes only press the controls"
+                    .to_string(),
+            );
+        }
+        check(
+            self.output.population.is_none() || (self.neat.is_none() && self.es.is_none()),
+            "output.population can only be set for the genetic algorithm, not with neat or es"
+                .to_string(),
+        );
+        if let Some(es) = &self.es {
+            check(
+                self.neat.is_none(),
+                "es and neat cannot both be set".to_string(),
+            );
+            check(
+                es.sigma > 0.0 && es.learning_rate > 0.0,
+                format!(
+                    "es.sigma and es.learning_rate must be positive, got {} and {}",
+                    es.sigma, es.learning_rate
+                ),
+            );
+            check(
+                es.weight_decay >= 0.0,
+                format!("es.weight_decay must not be negative, got {}", es.weight_decay),
+            );
+            if let Some(hidden_layers) = &es.hidden_layers {
+                check(
+                    hidden_layers.iter().all(|&n| n > 0),
+                    format!("es.hidden_layers must not be empty layers, got {:?}", hidden_layers),
+                );
+                check(
+                    !hidden_layers.is_empty() || self.population.memory.is_none(),
+                    "population.memory needs at least one of es.hidden_layers".to_string(),
+                );
+            }
+        }
+        check(
+            self.fitness.harsh_steering_max_rate > 0.0,
+            format!(
+                "fitness.harsh_steering_max_rate must be positive, got {}",
+                self.fitness.harsh_steering_max_rate
+            ),
+        );
+        let imitation = &self.imitation;
+        check(
+            imitation.epochs > 0 && imitation.batch_size > 0,
+            format!(
+                "imitation.epochs and imitation.batch_size must be at least 1, got {} and {}",
+                imitation.epochs, imitation.batch_size
+            ),
+        );
+        check(
+            imitation.learning_rate > 0.0,
+            format!(
+                "imitation.learning_rate must be positive, got {}",
+                imitation.learning_rate
+            ),
+        );
+        check(
+            (0.0..1.0).contains(&imitation.validation_split),
+            format!(
+                "imitation.validation_split must be in [0, 1), got {}",
+                imitation.validation_split
+            ),
+        );
+        let dqn = &self.dqn;
+        check(
+            dqn.hidden_layers.iter().all(|&n| n > 0),
+            format!("dqn.hidden_layers must not be empty layers, got {:?}", dqn.hidden_layers),
+        );
+        check(
+            dqn.replay_capacity > 0 && dqn.batch_size > 0,
+            format!(
+                "dqn.replay_capacity and dqn.batch_size must be at least 1, got {} and {}",
+                dqn.replay_capacity, dqn.batch_size
+            ),
+        );
+        check(
+            dqn.learning_rate > 0.0,
+            format!("dqn.learning_rate must be positive, got {}", dqn.learning_rate),
+        );
+        check(
+            (0.0..=1.0).contains(&dqn.discount),
+            format!("dqn.discount must be in [0, 1], got {}", dqn.discount),
+        );
+        for (name, epsilon) in [
+            ("epsilon_start", dqn.epsilon_start),
+            ("epsilon_end", dqn.epsilon_end),
+        ] {
+            check(
+                (0.0..=1.0).contains(&epsilon),
+                format!("dqn.{} must be in [0, 1], got {}", name, epsilon),
+            );
+        }
+        for (name, interval) in [
+            ("target_update_interval", dqn.target_update_interval),
+            ("train_interval", dqn.train_interval),
+            ("evaluation_interval", dqn.evaluation_interval as u64),
+            ("evaluation_seeds", dqn.evaluation_seeds),
+        ] {
+            check(interval > 0, format!("dqn.{} must be at least 1", name));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    pub fn simulation_config(&self) -> SimulationConfig {
+        SimulationConfig {
+            road_center_x: (self.window.width / 2) as i32,
+            road_width: (self.window.width as f32 * self.world.road_width) as i32,
+            lanes: self.world.lanes,
+            view_height: self.window.height as f32,
+            amount_cars: self.population.size,
+            traffic_size: self.traffic.size,
+            traffic_min_velocity: self.traffic.min_velocity,
+            seed: self.world.seed,
+            fixed_delta_t_s: self.world.fixed_delta_t_s,
+            generation_time_limit_s: self.world.generation_time_limit_s,
+            inference: self.inference,
+            features: self.observation.features.clone(),
+        }
+    }
+
+    pub fn evolution_config(&self) -> EvolutionConfig {
+        EvolutionConfig {
+            selection: self.population.selection,
+            elite_count: self.population.elite_count,
+            crossover: self.population.crossover,
+            mutation: self.population.mutation,
+            mutation_schedule: self.population.mutation_schedule,
+        }
+    }
+
+    pub fn fitness(&self) -> Box<dyn Fitness> {
+        let f = &self.fitness;
+        let terms: Vec<(f64, Box<dyn Fitness>)> = vec![
+            (f.distance, Box::new(fitness::Distance)),
+            (f.time_alive, Box::new(fitness::TimeAlive)),
+            (f.overtakes, Box::new(fitness::Overtakes)),
+            (f.lane_deviation, Box::new(fitness::LaneDeviation)),
+            (
+                f.harsh_steering,
+                Box::new(fitness::HarshSteering {
+                    max_rate: f.harsh_steering_max_rate,
+                }),
+            ),
+            (f.collisions, Box::new(fitness::Collisions)),
+        ];
+        Box::new(Weighted(
+            terms.into_iter().filter(|(w, _)| *w != 0.0).collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn presets_are_valid() {
+        for entry in std::fs::read_dir("experiments").unwrap() {
+            let path = entry.unwrap().path();
+            Experiment::load(path.to_str().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let experiment: Experiment = toml::from_str(
+            r#"
+            [world]
+            lanes = 0
+
+            [observation]
+            features = ["velocity", "heading", "velocity"]
+
+            [population]
+            size = 1
+            mutation = { type = "gaussian", rate = 2.0 }
+
+            [imitation]
+            validation_split = 1.0
+            "#,
+        )
+        .unwrap();
+        let err = experiment.validate().unwrap_err();
+        assert!(err.contains("world.lanes"));
+        assert!(err.contains("observation.features lists Velocity"));
+        assert!(err.contains("population.size"));
+        assert!(err.contains("population.mutation.rate"));
+        assert!(err.contains("imitation.validation_split"));
+    }
+
+    #[test]
+    fn checks_neat_settings() {
+        let experiment: Experiment = toml::from_str(
+            r#"
+            [neat]
+            crossover_rate = 1.5
+            output_activation = "softmax"
+            [population]
+            control = "analog"
+            [output]
+            weights = "f16"
+            "#,
+        )
+        .unwrap();
+        let err = experiment.validate().unwrap_err();
+        assert!(err.contains("neat.crossover_rate"));
+        assert!(err.contains("neat.output_activation"));
+        assert!(err.contains("output.weights"));
+        assert!(err.contains("population.control"));
+        assert!(toml::from_str::<Experiment>("").unwrap().neat.is_none());
+    }
+
+    #[test]
+    fn checks_es_settings() {
+        let experiment: Experiment = toml::from_str(
+            r#"
+            [population]
+            memory = "gru"
+            [es]
+            method = "cma"
+            sigma = 0.0
+            hidden_layers = []
+            [output]
+            population = "brains/es/population.bin"
+            "#,
+        )
+        .unwrap();
+        let err = experiment.validate().unwrap_err();
+        assert!(err.contains("es.sigma"));
+        assert!(err.contains("population.memory"));
+        assert!(err.contains("output.population"));
+        assert!(toml::from_str::<Experiment>("[es]
+method = \"ga\"").is_err());
+    }
+
+    #[test]
+    fn checks_dqn_settings() {
+        let experiment: Experiment = toml::from_str(
+            r#"
+            [dqn]
+            hidden_layers = [32, 0]
+            discount = 1.5
+            epsilon_end = -0.1
+            train_interval = 0
+            "#,
+        )
+        .unwrap();
+        let err = experiment.validate().unwrap_err();
+        assert!(err.contains("dqn.hidden_layers"));
+        assert!(err.contains("dqn.discount"));
+        assert!(err.contains("dqn.epsilon_end"));
+        assert!(err.contains("dqn.train_interval"));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(toml::from_str::<Experiment>("[world]\nlane = 3").is_err());
+    }
+}
@@ -0,0 +1,142 @@
+/// What happened to a car during one simulation step.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StepTelemetry {
+    /// in seconds
+    pub delta_t_s: f32,
+    /// still driving at the end of the step
+    pub alive: bool,
+    /// crashed during this step
+    pub crashed: bool,
+    /// forward distance travelled during the step, in meters
+    pub distance_m: f32,
+    /// distance from the closest lane center, in meters
+    pub lane_deviation_m: f32,
+    /// in degrees per second
+    pub steering_rate: f32,
+    /// traffic cars passed during the step minus the ones that passed the car
+    pub overtakes: i32,
+}
+
+/// Scores a car one step at a time, a car's fitness being the sum over every
+/// step it drove.
+pub trait Fitness: Send + Sync {
+    fn evaluate(&self, step: &StepTelemetry) -> f64;
+}
+
+/// Meters travelled down the road, negative when driving backwards.
+pub struct Distance;
+impl Fitness for Distance {
+    fn evaluate(&self, step: &StepTelemetry) -> f64 {
+        step.distance_m as f64
+    }
+}
+
+/// Seconds spent without crashing.
+pub struct TimeAlive;
+impl Fitness for TimeAlive {
+    fn evaluate(&self, step: &StepTelemetry) -> f64 {
+        if step.alive {
+            step.delta_t_s as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Net amount of traffic cars overtaken.
+pub struct Overtakes;
+impl Fitness for Overtakes {
+    fn evaluate(&self, step: &StepTelemetry) -> f64 {
+        step.overtakes as f64
+    }
+}
+
+/// Distance from the lane center integrated over time, in meter seconds.
+pub struct LaneDeviation;
+impl Fitness for LaneDeviation {
+    fn evaluate(&self, step: &StepTelemetry) -> f64 {
+        if step.alive {
+            (step.lane_deviation_m * step.delta_t_s) as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Steering faster than `max_rate` degrees per second, integrated over time
+/// relative to `max_rate`.
+pub struct HarshSteering {
+    pub max_rate: f32,
+}
+impl Fitness for HarshSteering {
+    fn evaluate(&self, step: &StepTelemetry) -> f64 {
+        let excess = (step.steering_rate.abs() - self.max_rate).max(0.0);
+        (excess / self.max_rate * step.delta_t_s) as f64
+    }
+}
+
+/// One for the step a car crashes in.
+pub struct Collisions;
+impl Fitness for Collisions {
+    fn evaluate(&self, step: &StepTelemetry) -> f64 {
+        if step.crashed {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Sum of other fitness functions, negative weights turn them into penalties.
+pub struct Weighted(pub Vec<(f64, Box<dyn Fitness>)>);
+impl Fitness for Weighted {
+    fn evaluate(&self, step: &StepTelemetry) -> f64 {
+        self.0.iter().map(|(w, f)| w * f.evaluate(step)).sum()
+    }
+}
+
+/// Rewards covering distance and overtaking while staying in lane, driving
+/// smoothly and not crashing.
+pub fn default_fitness() -> Box<dyn Fitness> {
+    Box::new(Weighted(vec![
+        (1.0, Box::new(Distance)),
+        (20.0, Box::new(Overtakes)),
+        (-2.0, Box::new(LaneDeviation)),
+        (-1.0, Box::new(HarshSteering { max_rate: 120.0 })),
+        (-100.0, Box::new(Collisions)),
+    ]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parked_car_earns_nothing() {
+        let parked = StepTelemetry {
+            delta_t_s: 1.0 / 60.0,
+            alive: true,
+            ..Default::default()
+        };
+        assert_eq!(default_fitness().evaluate(&parked), 0.0);
+    }
+
+    #[test]
+    fn weights_combine() {
+        let step = StepTelemetry {
+            delta_t_s: 0.5,
+            alive: true,
+            distance_m: 10.0,
+            lane_deviation_m: 1.0,
+            overtakes: 1,
+            ..Default::default()
+        };
+        let fitness = Weighted(vec![
+            (1.0, Box::new(Distance)),
+            (2.0, Box::new(Overtakes)),
+            (-4.0, Box::new(LaneDeviation)),
+            (3.0, Box::new(TimeAlive)),
+        ]);
+        assert_eq!(fitness.evaluate(&step), 10.0 + 2.0 - 2.0 + 1.5);
+    }
+}
@@ -0,0 +1,627 @@
+use std::sync::{mpsc, Arc};
+
+use wgpu::{
+    BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType, BufferDescriptor, BufferUsages,
+    CommandEncoderDescriptor, ComputePassDescriptor, ComputePipeline, ComputePipelineDescriptor,
+    PipelineCompilationOptions, PipelineLayoutDescriptor, ShaderModuleDescriptor, ShaderSource,
+    ShaderStages,
+};
+
+use crate::inference::Inference;
+use crate::network::{Activation, NeuralNetwork, LEAKY_RELU_SLOPE};
+
+/// invocations per workgroup of every entry point of the shader
+const WORKGROUP_SIZE: u32 = 64;
+
+/// A device with the feed forward pipelines compiled, shared by every
+/// population uploaded to it.
+pub struct GpuContext {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    pub adapter_info: wgpu::AdapterInfo,
+    batch_bind_group_layout: BindGroupLayout,
+    batch_level_pipeline: ComputePipeline,
+    batch_softmax_pipeline: ComputePipeline,
+}
+
+impl GpuContext {
+    /// Opens the first adapter wgpu picks, or its software fallback when
+    /// `software` is set. `None` when there is no such adapter.
+    pub fn new(software: bool) -> Option<Self> {
+        futures::executor::block_on(Self::request(software))
+    }
+
+    async fn request(software: bool) -> Option<Self> {
+        let instance = wgpu::Instance::default();
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                force_fallback_adapter: software,
+                ..Default::default()
+            })
+            .await?;
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("feed forward device"),
+                    required_limits: adapter.limits(),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .ok()?;
+
+        let shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("feed forward shader"),
+            source: ShaderSource::Wgsl(shader().into()),
+        });
+        let storage = |binding: u32, read_only: bool| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let params = BindGroupLayoutEntry {
+            binding: 4,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let entries = [
+            //inputs
+            storage(0, true),
+            //weights
+            storage(1, true),
+            //biases
+            storage(2, true),
+            //outputs
+            storage(3, false),
+            //sizes and activation
+            params,
+            //brain of every row
+            storage(5, true),
+        ];
+        let batch_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("batch feed forward bind group layout"),
+            entries: &entries,
+        });
+        let pipeline = |entry_point: &str, bind_group_layout: &BindGroupLayout| {
+            let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some(entry_point),
+                bind_group_layouts: &[bind_group_layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: PipelineCompilationOptions::default(),
+                cache: None,
+            })
+        };
+        let batch_level_pipeline = pipeline("batch_level", &batch_bind_group_layout);
+        let batch_softmax_pipeline = pipeline("batch_softmax", &batch_bind_group_layout);
+
+        Some(Self {
+            adapter_info: adapter.get_info(),
+            device,
+            queue,
+            batch_bind_group_layout,
+            batch_level_pipeline,
+            batch_softmax_pipeline,
+        })
+    }
+}
+
+/// Size in bytes of a buffer of `count` floats, never empty.
+fn buffer_size(count: usize) -> u64 {
+    (count.max(1) * std::mem::size_of::<f32>()) as u64
+}
+
+/// Every brain of a population on the device, the weights of a level of
+/// all of them stored back to back in one buffer. A car is a row of the
+/// batch, evaluated with the brain its index picks, so a level is a single
+/// dispatch for the whole population.
+pub struct GpuInference {
+    gpu: Arc<GpuContext>,
+    topology: Vec<u32>,
+    brain_count: usize,
+    levels: Vec<BatchLevel>,
+    /// rows the buffers below have room for
+    capacity: usize,
+    brain_indices: Buffer,
+    /// `activations[0]` holds the observations, `activations[i + 1]` the outputs of level `i`
+    activations: Vec<Buffer>,
+    staging: Buffer,
+    bind_groups: Vec<BindGroup>,
+    outputs: Vec<f32>,
+}
+
+struct BatchLevel {
+    weights: Buffer,
+    biases: Buffer,
+    params: Buffer,
+    input_count: u32,
+    output_count: u32,
+    activation: Activation,
+}
+
+impl GpuInference {
+    pub fn new(gpu: Arc<GpuContext>) -> Self {
+        let brain_indices = batch_buffer(&gpu, "brain index buffer", 0, BufferUsages::STORAGE);
+        let staging = batch_buffer(&gpu, "staging buffer", 0, BufferUsages::MAP_READ);
+        Self {
+            gpu,
+            topology: vec![],
+            brain_count: 0,
+            levels: vec![],
+            capacity: 0,
+            brain_indices,
+            activations: vec![],
+            staging,
+            bind_groups: vec![],
+            outputs: vec![],
+        }
+    }
+
+    /// Makes room for `rows` rows, keeping the buffers when they are large enough.
+    fn reserve(&mut self, rows: usize) {
+        if rows <= self.capacity && self.activations.len() == self.topology.len() {
+            return;
+        }
+        self.capacity = self.capacity.max(rows.next_power_of_two());
+        let gpu = &self.gpu;
+        let storage = BufferUsages::STORAGE | BufferUsages::COPY_SRC;
+        self.brain_indices = batch_buffer(
+            gpu,
+            "brain index buffer",
+            self.capacity,
+            BufferUsages::STORAGE,
+        );
+        self.activations = self
+            .topology
+            .iter()
+            .map(|&size| {
+                batch_buffer(
+                    gpu,
+                    "activation buffer",
+                    self.capacity * size as usize,
+                    storage,
+                )
+            })
+            .collect();
+        let output_count = *self.topology.last().unwrap_or(&0) as usize;
+        self.staging = batch_buffer(
+            gpu,
+            "staging buffer",
+            self.capacity * output_count,
+            BufferUsages::MAP_READ,
+        );
+        self.bind_groups.clear();
+    }
+
+    fn bind(&mut self) {
+        if !self.bind_groups.is_empty() {
+            return;
+        }
+        let gpu = &self.gpu;
+        self.bind_groups = self
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| {
+                let buffers = [
+                    &self.activations[i],
+                    &level.weights,
+                    &level.biases,
+                    &self.activations[i + 1],
+                    &level.params,
+                    &self.brain_indices,
+                ];
+                let entries: Vec<BindGroupEntry> = buffers
+                    .iter()
+                    .enumerate()
+                    .map(|(binding, buffer)| BindGroupEntry {
+                        binding: binding as u32,
+                        resource: buffer.as_entire_binding(),
+                    })
+                    .collect();
+                gpu.device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("batch feed forward bind group"),
+                    layout: &gpu.batch_bind_group_layout,
+                    entries: &entries,
+                })
+            })
+            .collect();
+    }
+}
+
+impl Inference for GpuInference {
+    fn load(&mut self, brains: &[NeuralNetwork]) {
+        let topology = brains.first().map(|b| b.topology()).unwrap_or_default();
+        let activations = brains.first().map(|b| b.activations()).unwrap_or_default();
+        for brain in brains {
+            assert!(!brain.is_recurrent(), "recurrent brains cannot be batched");
+            assert_eq!(
+                brain.topology(),
+                topology,
+                "brains of a batch differ in topology"
+            );
+            assert_eq!(
+                brain.activations(),
+                activations,
+                "brains of a batch differ in activations"
+            );
+        }
+
+        if topology != self.topology || brains.len() != self.brain_count {
+            let gpu = &self.gpu;
+            let destination = BufferUsages::STORAGE | BufferUsages::COPY_DST;
+            self.levels = topology
+                .windows(2)
+                .map(|sizes| {
+                    let (input_count, output_count) = (sizes[0] as usize, sizes[1] as usize);
+                    BatchLevel {
+                        weights: batch_buffer(
+                            gpu,
+                            "weight buffer",
+                            brains.len() * input_count * output_count,
+                            destination,
+                        ),
+                        biases: batch_buffer(
+                            gpu,
+                            "bias buffer",
+                            brains.len() * output_count,
+                            destination,
+                        ),
+                        params: batch_buffer(
+                            gpu,
+                            "params buffer",
+                            4,
+                            BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                        ),
+                        input_count: sizes[0],
+                        output_count: sizes[1],
+                        activation: Activation::default(),
+                    }
+                })
+                .collect();
+            // the activation buffers depend on the topology too
+            self.activations.clear();
+            self.bind_groups.clear();
+            self.topology = topology;
+            self.brain_count = brains.len();
+        }
+
+        let queue = &self.gpu.queue;
+        for (i, level) in self.levels.iter_mut().enumerate() {
+            let weights: Vec<f32> = brains
+                .iter()
+                .flat_map(|b| b.levels[i].weights.iter().copied())
+                .collect();
+            let biases: Vec<f32> = brains
+                .iter()
+                .flat_map(|b| b.levels[i].biases.iter().copied())
+                .collect();
+            if !weights.is_empty() {
+                queue.write_buffer(&level.weights, 0, bytemuck::cast_slice(&weights));
+            }
+            if !biases.is_empty() {
+                queue.write_buffer(&level.biases, 0, bytemuck::cast_slice(&biases));
+            }
+            level.activation = activations[i];
+        }
+    }
+
+    fn infer(&mut self, brain_indices: &[u32], observations: &[f32]) -> &[f32] {
+        let rows = brain_indices.len();
+        let output_count = *self.topology.last().unwrap_or(&0) as usize;
+        self.outputs.resize(rows * output_count, 0.0);
+        if rows == 0 || self.levels.is_empty() {
+            return &self.outputs;
+        }
+        assert_eq!(observations.len(), rows * self.topology[0] as usize);
+        assert!(
+            brain_indices
+                .iter()
+                .all(|&b| (b as usize) < self.brain_count),
+            "brain index out of range"
+        );
+        self.reserve(rows);
+        self.bind();
+
+        let gpu = &self.gpu;
+        gpu.queue
+            .write_buffer(&self.brain_indices, 0, bytemuck::cast_slice(brain_indices));
+        gpu.queue
+            .write_buffer(&self.activations[0], 0, bytemuck::cast_slice(observations));
+        for level in self.levels.iter() {
+            let params = [
+                level.input_count,
+                level.output_count,
+                level.activation.shader_code(),
+                rows as u32,
+            ];
+            gpu.queue
+                .write_buffer(&level.params, 0, bytemuck::cast_slice(&params));
+        }
+
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("batch feed forward encoder"),
+            });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("batch feed forward compute pass"),
+                timestamp_writes: None,
+            });
+            for (level, bind_group) in self.levels.iter().zip(&self.bind_groups) {
+                let outputs = rows as u32 * level.output_count;
+                compute_pass.set_bind_group(0, bind_group, &[]);
+                compute_pass.set_pipeline(&gpu.batch_level_pipeline);
+                compute_pass.dispatch_workgroups(outputs.div_ceil(WORKGROUP_SIZE).max(1), 1, 1);
+                if level.activation == Activation::Softmax {
+                    // one workgroup normalizes each row
+                    compute_pass.set_pipeline(&gpu.batch_softmax_pipeline);
+                    compute_pass.dispatch_workgroups(rows as u32, 1, 1);
+                }
+            }
+        }
+        let output_size = (self.outputs.len() * std::mem::size_of::<f32>()) as u64;
+        encoder.copy_buffer_to_buffer(
+            self.activations.last().unwrap(),
+            0,
+            &self.staging,
+            0,
+            output_size,
+        );
+        gpu.queue.submit(Some(encoder.finish()));
+        read_back(gpu, &self.staging, &mut self.outputs);
+        &self.outputs
+    }
+}
+
+impl GpuInference {
+    /// Like `infer` but reads back the outputs of every level for every row,
+    /// to compare them with the ones of the CPU.
+    #[cfg(test)]
+    pub fn infer_levels(&mut self, brain_indices: &[u32], observations: &[f32]) -> Vec<Vec<f32>> {
+        self.infer(brain_indices, observations);
+        let rows = brain_indices.len();
+        let gpu = &self.gpu;
+        self.activations[1..]
+            .iter()
+            .zip(&self.topology[1..])
+            .map(|(buffer, &size)| {
+                let mut outputs = vec![0.0; rows * size as usize];
+                let staging =
+                    batch_buffer(gpu, "staging buffer", outputs.len(), BufferUsages::MAP_READ);
+                let mut encoder = gpu
+                    .device
+                    .create_command_encoder(&CommandEncoderDescriptor {
+                        label: Some("read back encoder"),
+                    });
+                encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer_size(outputs.len()));
+                gpu.queue.submit(Some(encoder.finish()));
+                if !outputs.is_empty() {
+                    read_back(gpu, &staging, &mut outputs);
+                }
+                outputs
+            })
+            .collect()
+    }
+}
+
+/// A buffer of `count` floats or indices, never empty.
+fn batch_buffer(gpu: &GpuContext, label: &str, count: usize, usage: BufferUsages) -> Buffer {
+    gpu.device.create_buffer(&BufferDescriptor {
+        label: Some(label),
+        size: buffer_size(count),
+        usage: usage | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Waits for the submitted work and copies the start of `staging` into `outputs`.
+fn read_back(gpu: &GpuContext, staging: &Buffer, outputs: &mut [f32]) {
+    let output_size = std::mem::size_of_val(outputs) as u64;
+    let buffer_slice = staging.slice(..output_size);
+    let (sender, receiver) = mpsc::channel();
+    buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    gpu.device.poll(wgpu::Maintain::Wait);
+    receiver
+        .recv()
+        .expect("Failed to retrieve results")
+        .expect("Failed to map buffer");
+    outputs.copy_from_slice(bytemuck::cast_slice(&buffer_slice.get_mapped_range()));
+    staging.unmap();
+}
+
+fn shader() -> String {
+    let shader = r"
+struct Params {
+    input_count: u32,
+    output_count: u32,
+    activation: u32, // Activation::shader_code
+    row_count: u32,  // rows of a batch, each with its own inputs and outputs
+}
+
+@group(0) @binding(0) var<storage, read> inputs: array<f32>;        // Input array (length input_count)
+@group(0) @binding(1) var<storage, read> weights: array<f32>; // Weights array, row-major (output_count x input_count)
+@group(0) @binding(2) var<storage, read> biases: array<f32>;        // Bias array (length output_count)
+@group(0) @binding(3) var<storage, read_write> outputs: array<f32>;      // Output array (length output_count)
+@group(0) @binding(4) var<uniform> params: Params;
+@group(0) @binding(5) var<storage, read> brain_indices: array<u32>; // Brain of every row of a batch
+
+fn activate(x: f32) -> f32 {
+    switch params.activation {
+        case 1u: { return 1.0 / (1.0 + exp(-x)); }
+        case 2u: { return max(x, 0.0); }
+        case 3u: { return select(LEAKY_RELU_SLOPE * x, x, x > 0.0); }
+        // softmax is applied by its own entry point once the whole level is done
+        case 4u, 5u: { return x; }
+        default: { return tanh(x); }
+    }
+}
+
+// One invocation per output neuron of every row, each row evaluated with the
+// weights and biases of its brain, stored after each other
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn batch_level(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let k = global_id.x;
+    if (k >= params.row_count * params.output_count) {
+        return;
+    }
+    let r = k / params.output_count;
+    let i = k % params.output_count;
+    let neuron = brain_indices[r] * params.output_count + i;
+
+    var sum: f32 = 0.0;
+    let input = r * params.input_count;
+    let row = neuron * params.input_count;
+    for (var j: u32 = 0u; j < params.input_count; j = j + 1u) {
+        sum = sum + inputs[input + j] * weights[row + j];
+    }
+
+    outputs[k] = activate(sum + biases[neuron]);
+}
+
+var<workgroup> partial: array<f32, WORKGROUP_SIZE>;
+
+// Normalizes the outputs of a softmax level, one workgroup per row
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn batch_softmax(
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+) {
+    normalize(local_id.x, workgroup_id.x * params.output_count);
+}
+
+// Softmax of the output_count outputs starting at start, by the whole workgroup
+fn normalize(t: u32, start: u32) {
+    let end = start + params.output_count;
+
+    // Max of the level, subtracted so that exp cannot overflow
+    var local_max: f32 = -3.402823e38;
+    for (var i: u32 = start + t; i < end; i = i + WORKGROUP_SIZEu) {
+        local_max = max(local_max, outputs[i]);
+    }
+    partial[t] = local_max;
+    workgroupBarrier();
+    for (var stride: u32 = WORKGROUP_SIZEu / 2u; stride > 0u; stride = stride / 2u) {
+        if (t < stride) {
+            partial[t] = max(partial[t], partial[t + stride]);
+        }
+        workgroupBarrier();
+    }
+    let max_value = partial[0];
+    workgroupBarrier();
+
+    var local_sum: f32 = 0.0;
+    for (var i: u32 = start + t; i < end; i = i + WORKGROUP_SIZEu) {
+        let e = exp(outputs[i] - max_value);
+        outputs[i] = e;
+        local_sum = local_sum + e;
+    }
+    partial[t] = local_sum;
+    workgroupBarrier();
+    for (var stride: u32 = WORKGROUP_SIZEu / 2u; stride > 0u; stride = stride / 2u) {
+        if (t < stride) {
+            partial[t] = partial[t] + partial[t + stride];
+        }
+        workgroupBarrier();
+    }
+    let total = partial[0];
+
+    for (var i: u32 = start + t; i < end; i = i + WORKGROUP_SIZEu) {
+        outputs[i] = outputs[i] / total;
+    }
+}";
+    shader
+        .replace("LEAKY_RELU_SLOPE", &format!("{:?}", LEAKY_RELU_SLOPE))
+        .replace("WORKGROUP_SIZE", &WORKGROUP_SIZE.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inference::CpuInference;
+    use crate::parity;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn matches_cpu_on_software_adapter() {
+        let Some(gpu) = GpuContext::new(true) else {
+            println!("no software adapter, skipping");
+            return;
+        };
+        let gpu = Arc::new(gpu);
+        let mut rng = StdRng::seed_from_u64(4);
+        let activations = [Activation::Relu, Activation::LeakyRelu, Activation::Softmax];
+        let mut net = NeuralNetwork::with_activations(&[7, 130, 3, 5], &activations);
+        net.randomize(&mut rng);
+        let mut gpu_net = GpuInference::new(gpu.clone());
+        let tolerance = parity::tolerance();
+
+        for round in 0..2 {
+            if round == 1 {
+                net.randomize(&mut rng);
+            }
+            gpu_net.load(std::slice::from_ref(&net));
+            let inputs: Vec<f32> = (0..7).map(|_| rng.gen::<f32>() * 2.0 - 1.0).collect();
+            let expected = net.feed_forward(&inputs).to_vec();
+            let outputs = gpu_net.infer(&[0], &inputs);
+            for (e, o) in expected.iter().zip(outputs) {
+                assert!((e - o).abs() <= tolerance, "cpu {:?} gpu {:?}", expected, outputs);
+            }
+        }
+    }
+
+    #[test]
+    fn batch_matches_cpu_on_software_adapter() {
+        let Some(gpu) = GpuContext::new(true) else {
+            println!("no software adapter, skipping");
+            return;
+        };
+        let mut rng = StdRng::seed_from_u64(5);
+        let activations = [Activation::Tanh, Activation::Sigmoid, Activation::Softmax];
+        let mut brains: Vec<NeuralNetwork> = (0..6)
+            .map(|_| NeuralNetwork::with_activations(&[10, 70, 8, 4], &activations))
+            .collect();
+        let mut gpu_inference = GpuInference::new(Arc::new(gpu));
+        let mut cpu_inference = CpuInference::default();
+        let tolerance = parity::tolerance();
+
+        // fewer rows than brains, then repeated brains and more rows than
+        // fit in the first buffers
+        for brain_indices in [vec![1, 3, 4], vec![5, 0, 0, 2, 5, 1, 3, 3, 4]] {
+            for brain in brains.iter_mut() {
+                brain.randomize(&mut rng);
+            }
+            gpu_inference.load(&brains);
+            cpu_inference.load(&brains);
+            let observations: Vec<f32> = (0..brain_indices.len() * 10)
+                .map(|_| rng.gen::<f32>() * 2.0 - 1.0)
+                .collect();
+            let expected = cpu_inference.infer(&brain_indices, &observations).to_vec();
+            let outputs = gpu_inference.infer(&brain_indices, &observations);
+            assert_eq!(outputs.len(), expected.len());
+            for (e, o) in expected.iter().zip(outputs) {
+                assert!((e - o).abs() <= tolerance, "cpu {:?} gpu {:?}", expected, outputs);
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::network::NeuralNetwork;
+
+/// Gradients of a loss with respect to the weights and biases of every level
+/// of a dense network, shaped like it.
+#[derive(Clone)]
+pub struct Gradients {
+    levels: Vec<LevelGradient>,
+}
+
+#[derive(Clone)]
+struct LevelGradient {
+    weights: Vec<f32>,
+    biases: Vec<f32>,
+}
+
+impl Gradients {
+    pub fn zeros(network: &NeuralNetwork) -> Self {
+        let levels = network
+            .levels
+            .iter()
+            .map(|l| LevelGradient {
+                weights: vec![0.0; l.weights.len()],
+                biases: vec![0.0; l.biases.len()],
+            })
+            .collect();
+        Self { levels }
+    }
+
+    pub fn add(&mut self, other: &Gradients) {
+        for (a, b) in self.levels.iter_mut().zip(&other.levels) {
+            a.weights
+                .iter_mut()
+                .zip(&b.weights)
+                .for_each(|(a, b)| *a += b);
+            a.biases
+                .iter_mut()
+                .zip(&b.biases)
+                .for_each(|(a, b)| *a += b);
+        }
+    }
+}
+
+/// Inputs followed by the outputs of every level, computed without touching
+/// the scratch buffers of the network so that samples can run in parallel.
+/// Only for dense levels.
+pub fn forward(network: &NeuralNetwork, inputs: &[f32]) -> Vec<Vec<f32>> {
+    let mut layers = vec![inputs.to_vec()];
+    for level in network.levels.iter() {
+        let inputs = layers.last().unwrap();
+        let mut outputs: Vec<f32> = level
+            .rows()
+            .zip(&level.biases)
+            .map(|(row, bias)| bias + row.iter().zip(inputs).map(|(w, x)| w * x).sum::<f32>())
+            .collect();
+        level.activation.apply(&mut outputs);
+        layers.push(outputs);
+    }
+    layers
+}
+
+/// Adds to `gradients` the gradient of a loss of a single sample, given the
+/// `layers` `forward` computed for it and the gradient of the loss with
+/// respect to the outputs of the network.
+pub fn backpropagate(
+    network: &NeuralNetwork,
+    layers: &[Vec<f32>],
+    output_gradient: Vec<f32>,
+    gradients: &mut Gradients,
+) {
+    let mut grads = output_gradient;
+    for (l, level) in network.levels.iter().enumerate().rev() {
+        level.activation.backward(&layers[l + 1], &mut grads);
+        let inputs = &layers[l];
+        let gradient = &mut gradients.levels[l];
+        let width = inputs.len();
+        for (j, &g) in grads.iter().enumerate() {
+            gradient.biases[j] += g;
+            let row = &mut gradient.weights[j * width..(j + 1) * width];
+            row.iter_mut().zip(inputs).for_each(|(w, x)| *w += g * x);
+        }
+        if l > 0 {
+            let mut below = vec![0.0; width];
+            for (row, &g) in level.rows().zip(&grads) {
+                below.iter_mut().zip(row).for_each(|(b, w)| *b += g * w);
+            }
+            grads = below;
+        }
+    }
+}
+
+const BETA1: f32 = 0.9;
+const BETA2: f32 = 0.999;
+const EPSILON: f32 = 1e-8;
+
+/// Gradient descent with Adam over the weights and biases of a network.
+pub struct Adam {
+    learning_rate: f32,
+    /// running averages of the gradients and of their squares, shaped like
+    /// the weights and biases of every level
+    moments: Vec<Moments>,
+    steps: i32,
+}
+
+struct Moments {
+    weights: Vec<(f32, f32)>,
+    biases: Vec<(f32, f32)>,
+}
+
+impl Adam {
+    pub fn new(network: &NeuralNetwork, learning_rate: f32) -> Self {
+        let moments = network
+            .levels
+            .iter()
+            .map(|l| Moments {
+                weights: vec![(0.0, 0.0); l.weights.len()],
+                biases: vec![(0.0, 0.0); l.biases.len()],
+            })
+            .collect();
+        Self {
+            learning_rate,
+            moments,
+            steps: 0,
+        }
+    }
+
+    /// Moves every weight and bias against its gradient, summed over a batch
+    /// of `batch_len` samples.
+    pub fn step(&mut self, network: &mut NeuralNetwork, gradients: &Gradients, batch_len: usize) {
+        self.steps += 1;
+        let correction1 = 1.0 - BETA1.powi(self.steps);
+        let correction2 = 1.0 - BETA2.powi(self.steps);
+        let learning_rate = self.learning_rate;
+        let batch_len = batch_len.max(1) as f32;
+        let update = |value: &mut f32, moments: &mut (f32, f32), gradient: f32| {
+            let gradient = gradient / batch_len;
+            moments.0 = BETA1 * moments.0 + (1.0 - BETA1) * gradient;
+            moments.1 = BETA2 * moments.1 + (1.0 - BETA2) * gradient * gradient;
+            let m = moments.0 / correction1;
+            let v = moments.1 / correction2;
+            *value -= learning_rate * m / (v.sqrt() + EPSILON);
+        };
+        for ((level, moments), gradient) in network
+            .levels
+            .iter_mut()
+            .zip(self.moments.iter_mut())
+            .zip(&gradients.levels)
+        {
+            for ((w, m), &g) in level
+                .weights
+                .iter_mut()
+                .zip(&mut moments.weights)
+                .zip(&gradient.weights)
+            {
+                update(w, m, g);
+            }
+            for ((b, m), &g) in level
+                .biases
+                .iter_mut()
+                .zip(&mut moments.biases)
+                .zip(&gradient.biases)
+            {
+                update(b, m, g);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::Activation;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        let mut rng = StdRng::seed_from_u64(21);
+        let mut network = NeuralNetwork::with_activations(
+            &[3, 5, 4, 4],
+            &[Activation::Tanh, Activation::LeakyRelu, Activation::Softmax],
+        );
+        network.randomize(&mut rng);
+        let inputs = [0.3, -0.6, 0.9];
+        let targets = [1.0, 0.0, 0.0, 1.0];
+        // squared error, whose gradient is 2(o - t)
+        let loss = |network: &NeuralNetwork| {
+            let outputs = forward(network, &inputs).pop().unwrap();
+            let sum: f32 = outputs
+                .iter()
+                .zip(&targets)
+                .map(|(o, t)| (o - t).powi(2))
+                .sum();
+            sum as f64
+        };
+        let layers = forward(&network, &inputs);
+        let output_gradient = layers[3]
+            .iter()
+            .zip(&targets)
+            .map(|(o, t)| 2.0 * (o - t))
+            .collect();
+        let mut gradients = Gradients::zeros(&network);
+        backpropagate(&network, &layers, output_gradient, &mut gradients);
+
+        let h = 1e-3;
+        for (l, gradient) in gradients.levels.iter().enumerate() {
+            for (i, &analytic) in gradient.weights.iter().enumerate() {
+                let mut plus = network.clone();
+                plus.levels[l].weights[i] += h;
+                let mut minus = network.clone();
+                minus.levels[l].weights[i] -= h;
+                let numeric = (loss(&plus) - loss(&minus)) / (2.0 * h as f64);
+                assert!(
+                    (numeric - analytic as f64).abs() < 1e-3,
+                    "level {} weight {}: {} vs {}",
+                    l,
+                    i,
+                    numeric,
+                    analytic
+                );
+            }
+        }
+    }
+}
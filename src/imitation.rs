@@ -0,0 +1,295 @@
+use std::fs;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::brain;
+use crate::car::CONTROL_THRESHOLD;
+use crate::gradient::{backpropagate, forward, Adam, Gradients};
+use crate::network::NeuralNetwork;
+use crate::observation::{self, Feature};
+use crate::sensor::SensorLayout;
+
+/// Version written by `Dataset::save`.
+pub const DATASET_FORMAT_VERSION: u32 = 1;
+
+/// Settings of the gradient descent fitting a brain to recorded driving.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImitationConfig {
+    /// passes over the training samples
+    pub epochs: u32,
+    /// samples averaged into every step
+    pub batch_size: usize,
+    /// step size of Adam
+    pub learning_rate: f32,
+    /// fraction of the samples held out to measure how well the brain
+    /// generalizes, taken from the end of the recording since consecutive
+    /// frames are nearly identical
+    pub validation_split: f32,
+}
+impl Default for ImitationConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 30,
+            batch_size: 64,
+            learning_rate: 1e-3,
+            validation_split: 0.1,
+        }
+    }
+}
+
+/// What the driver saw and pressed during one step.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub readings: Vec<f32>,
+    /// in the order of `car::CONTROL_NAMES`
+    pub controls: [bool; 4],
+}
+
+impl Sample {
+    /// Outputs a brain should produce to press the same controls.
+    pub fn targets(&self) -> [f32; 4] {
+        self.controls.map(|pressed| pressed as u8 as f32)
+    }
+}
+
+/// Recorded driving as stored on disk.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Dataset {
+    pub version: u32,
+    /// sensors whose readings the samples hold, in order
+    pub sensors: Vec<SensorLayout>,
+    /// features of the car state the samples hold after the sensor readings
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    pub samples: Vec<Sample>,
+}
+
+impl Dataset {
+    pub fn new(sensors: Vec<SensorLayout>, features: Vec<Feature>) -> Self {
+        Self {
+            version: DATASET_FORMAT_VERSION,
+            sensors,
+            features,
+            samples: vec![],
+        }
+    }
+
+    /// Written atomically like `BrainFile::save`.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        brain::write_atomically(path, json.as_bytes())
+            .map_err(|e| format!("could not save dataset {}: {}", path, e))
+    }
+
+    /// Reads a dataset and checks its samples match its sensors and features.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("could not read dataset {}: {}", path, e))?;
+        let dataset: Dataset = serde_json::from_str(&content)
+            .map_err(|e| format!("invalid dataset {}: {}", path, e))?;
+        if dataset.version != DATASET_FORMAT_VERSION {
+            return Err(format!(
+                "dataset {} has format version {}, only {} is supported",
+                path, dataset.version, DATASET_FORMAT_VERSION
+            ));
+        }
+        let inputs = observation::input_count(&dataset.sensors, &dataset.features);
+        if let Some(i) = dataset
+            .samples
+            .iter()
+            .position(|s| s.readings.len() != inputs as usize)
+        {
+            return Err(format!(
+                "sample {} of dataset {} has {} readings but its sensors and features make {}",
+                i,
+                path,
+                dataset.samples[i].readings.len(),
+                inputs
+            ));
+        }
+        Ok(dataset)
+    }
+}
+
+/// How close a brain comes to the recorded controls.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Evaluation {
+    /// mean squared difference between the outputs and the targets
+    pub loss: f32,
+    /// fraction of the samples for which every control is pressed as recorded
+    pub accuracy: f32,
+}
+
+/// Fits a dense brain to samples with minibatch gradient descent and Adam,
+/// minimizing the squared difference between its outputs and the controls.
+pub struct Trainer {
+    config: ImitationConfig,
+    adam: Adam,
+}
+
+impl Trainer {
+    /// Panics if `network` has recurrent levels, whose state would have to
+    /// be unrolled through the recording.
+    pub fn new(network: &NeuralNetwork, config: ImitationConfig) -> Self {
+        assert!(!network.is_recurrent(), "only dense levels can be trained");
+        let adam = Adam::new(network, config.learning_rate);
+        Self { config, adam }
+    }
+
+    /// One pass over `samples` in a random order, returns the mean loss.
+    pub fn epoch(
+        &mut self,
+        network: &mut NeuralNetwork,
+        samples: &[Sample],
+        rng: &mut impl Rng,
+    ) -> f32 {
+        let mut order: Vec<usize> = (0..samples.len()).collect();
+        order.shuffle(rng);
+        let mut total_loss = 0.0;
+        for batch in order.chunks(self.config.batch_size.max(1)) {
+            let (gradients, loss) = batch
+                .par_iter()
+                .fold(
+                    || (Gradients::zeros(network), 0.0),
+                    |(mut gradients, loss), &i| {
+                        let sample_loss = learn(network, &samples[i], &mut gradients);
+                        (gradients, loss + sample_loss)
+                    },
+                )
+                .reduce(
+                    || (Gradients::zeros(network), 0.0),
+                    |(mut a, loss_a), (b, loss_b)| {
+                        a.add(&b);
+                        (a, loss_a + loss_b)
+                    },
+                );
+            total_loss += loss;
+            self.adam.step(network, &gradients, batch.len());
+        }
+        total_loss / samples.len().max(1) as f32
+    }
+}
+
+/// Loss and accuracy of `network` over `samples`.
+pub fn evaluate(network: &NeuralNetwork, samples: &[Sample]) -> Evaluation {
+    let (loss, correct) = samples
+        .par_iter()
+        .map(|sample| {
+            let outputs = forward(network, &sample.readings).pop().unwrap();
+            let loss = squared_error(&outputs, &sample.targets());
+            let pressed = outputs.iter().map(|&o| o > CONTROL_THRESHOLD);
+            let correct = pressed.eq(sample.controls.iter().copied());
+            (loss, correct as usize)
+        })
+        .reduce(|| (0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+    let count = samples.len().max(1) as f32;
+    Evaluation {
+        loss: loss / count,
+        accuracy: correct as f32 / count,
+    }
+}
+
+/// Splits off the last `validation_split` of the samples.
+pub fn split(samples: &[Sample], validation_split: f32) -> (&[Sample], &[Sample]) {
+    let validation = (samples.len() as f32 * validation_split).round() as usize;
+    samples.split_at(samples.len() - validation.min(samples.len()))
+}
+
+fn squared_error(outputs: &[f32], targets: &[f32]) -> f32 {
+    let sum: f32 = outputs
+        .iter()
+        .zip(targets)
+        .map(|(o, t)| (o - t).powi(2))
+        .sum();
+    sum / outputs.len() as f32
+}
+
+/// Adds the gradients of the loss of a single sample to `gradients` and
+/// returns that loss.
+fn learn(network: &NeuralNetwork, sample: &Sample, gradients: &mut Gradients) -> f32 {
+    let layers = forward(network, &sample.readings);
+    let outputs = layers.last().unwrap();
+    let targets = sample.targets();
+    let scale = 2.0 / outputs.len() as f32;
+    let output_gradient = outputs
+        .iter()
+        .zip(&targets)
+        .map(|(o, t)| scale * (o - t))
+        .collect();
+    let loss = squared_error(outputs, &targets);
+    backpropagate(network, &layers, output_gradient, gradients);
+    loss
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::car;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn sample(readings: Vec<f32>) -> Sample {
+        // brake when something is close ahead, steer away from the closer side
+        let controls = [
+            readings[0] < 0.5,
+            readings[0] >= 0.5,
+            readings[1] > readings[2],
+            readings[2] > readings[1],
+        ];
+        Sample { readings, controls }
+    }
+
+    #[test]
+    fn learns_to_imitate() {
+        let mut rng = StdRng::seed_from_u64(21);
+        let samples: Vec<Sample> = (0..2000)
+            .map(|_| sample((0..6).map(|_| rng.gen::<f32>()).collect()))
+            .collect();
+        let (train, validation) = split(&samples, 0.2);
+        assert_eq!(validation.len(), 400);
+
+        let mut network = NeuralNetwork::new(&[6, 16, 4]);
+        network.randomize(&mut rng);
+        let config = ImitationConfig {
+            epochs: 100,
+            learning_rate: 1e-2,
+            ..ImitationConfig::default()
+        };
+        let before = evaluate(&network, validation);
+        let mut trainer = Trainer::new(&network, config.clone());
+        for _ in 0..config.epochs {
+            trainer.epoch(&mut network, train, &mut rng);
+        }
+        let after = evaluate(&network, validation);
+        assert!(
+            after.loss < before.loss / 4.0,
+            "{:?} then {:?}",
+            before,
+            after
+        );
+        assert!(after.accuracy > 0.75, "{:?}", after);
+    }
+
+    #[test]
+    fn round_trips_datasets() {
+        let dir = std::env::temp_dir().join(format!("car-ai-dataset-{}", std::process::id()));
+        let path = dir.join("dataset.json");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let features = vec![Feature::Velocity];
+        let inputs = observation::input_count(&car::SENSOR_LAYOUT, &features);
+        let mut dataset = Dataset::new(car::SENSOR_LAYOUT.to_vec(), features.clone());
+        dataset.samples.push(sample((0..inputs).map(|i| i as f32 / 10.0).collect()));
+        dataset.save(path).unwrap();
+
+        let loaded = Dataset::load(path).unwrap();
+        // the recording side compares the sensors exactly
+        assert_eq!(loaded.sensors, car::SENSOR_LAYOUT);
+        assert_eq!(loaded.features, features);
+        assert_eq!(loaded.samples, dataset.samples);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::gpu::{GpuContext, GpuInference};
+use crate::network::{self, BatchBuffers, NeuralNetwork};
+
+/// Where the brains of a population are evaluated.
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Cpu,
+    /// falls back to the CPU when no adapter is found
+    Gpu,
+}
+
+/// Runs the observations of many cars through the brains of a population at once.
+pub trait Inference: Send {
+    /// Replaces the brains `infer` indexes into. They all share a topology
+    /// and the activations of their levels, and none is recurrent.
+    fn load(&mut self, brains: &[NeuralNetwork]);
+
+    /// Runs row `i` of `observations` through brain `brain_indices[i]` and
+    /// returns the outputs of every row, one after the other.
+    fn infer(&mut self, brain_indices: &[u32], observations: &[f32]) -> &[f32];
+}
+
+/// The backend for `backend`, the CPU one when the GPU is asked for but
+/// there is no adapter.
+pub fn new_inference(backend: Backend) -> Box<dyn Inference> {
+    match backend {
+        Backend::Cpu => Box::new(CpuInference::default()),
+        Backend::Gpu => match GpuContext::new(false) {
+            Some(gpu) => {
+                println!(
+                    "inference on {} ({:?})",
+                    gpu.adapter_info.name, gpu.adapter_info.backend
+                );
+                Box::new(GpuInference::new(Arc::new(gpu)))
+            }
+            None => {
+                println!("no GPU adapter found, inference on the CPU");
+                Box::new(CpuInference::default())
+            }
+        },
+    }
+}
+
+#[derive(Default)]
+pub struct CpuInference {
+    brains: Vec<NeuralNetwork>,
+    buffers: BatchBuffers,
+    outputs: Vec<f32>,
+}
+
+impl Inference for CpuInference {
+    fn load(&mut self, brains: &[NeuralNetwork]) {
+        self.brains = brains.to_vec();
+    }
+
+    fn infer(&mut self, brain_indices: &[u32], observations: &[f32]) -> &[f32] {
+        if brain_indices.is_empty() {
+            self.outputs.clear();
+            return &self.outputs;
+        }
+        if let [brain] = self.brains.as_slice() {
+            return brain.feed_forward_batch(observations, &mut self.buffers);
+        }
+        let output_count = self
+            .brains
+            .first()
+            .and_then(|b| b.levels.last())
+            .map_or(0, |l| l.outputs.len());
+        self.outputs.resize(brain_indices.len() * output_count, 0.0);
+        let input_count = observations
+            .len()
+            .checked_div(brain_indices.len())
+            .unwrap_or(0);
+
+        // every brain drives at most one car, each can run on its own thread
+        if brain_indices.windows(2).all(|w| w[0] < w[1]) {
+            let mut wanted = brain_indices.iter().copied().peekable();
+            let brains: Vec<&mut NeuralNetwork> = self
+                .brains
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, _)| wanted.next_if_eq(&(*i as u32)).is_some())
+                .map(|(_, b)| b)
+                .collect();
+            assert_eq!(
+                brains.len(),
+                brain_indices.len(),
+                "brain index out of range"
+            );
+            network::feed_forward_each(brains.into_par_iter(), observations, &mut self.outputs);
+        } else {
+            for ((&b, inputs), outputs) in brain_indices
+                .iter()
+                .zip(observations.chunks_exact(input_count.max(1)))
+                .zip(self.outputs.chunks_exact_mut(output_count.max(1)))
+            {
+                outputs.copy_from_slice(self.brains[b as usize].feed_forward(inputs));
+            }
+        }
+        &self.outputs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn cpu_matches_single_networks() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let mut brains: Vec<NeuralNetwork> = (0..5)
+            .map(|_| {
+                let mut brain = NeuralNetwork::new(&[6, 9, 3]);
+                brain.randomize(&mut rng);
+                brain
+            })
+            .collect();
+        let mut inference = CpuInference::default();
+
+        for brain_indices in [vec![0, 2, 3], vec![4, 1, 1, 0], vec![3]] {
+            let observations: Vec<f32> = (0..brain_indices.len() * 6)
+                .map(|_| rng.gen::<f32>() * 2.0 - 1.0)
+                .collect();
+            let expected: Vec<f32> = brain_indices
+                .iter()
+                .zip(observations.chunks(6))
+                .flat_map(|(&b, inputs)| brains[b as usize].feed_forward(inputs).to_vec())
+                .collect();
+            inference.load(&brains);
+            assert_eq!(inference.infer(&brain_indices, &observations), expected);
+            // no car driving
+            assert!(inference.infer(&[], &[]).is_empty());
+
+            // a single brain drives every car
+            let shared: Vec<f32> = observations
+                .chunks(6)
+                .flat_map(|inputs| brains[0].feed_forward(inputs).to_vec())
+                .collect();
+            inference.load(&brains[..1]);
+            let zeros = vec![0; brain_indices.len()];
+            assert_eq!(inference.infer(&zeros, &observations), shared);
+            assert!(inference.infer(&[], &[]).is_empty());
+        }
+    }
+}
@@ -0,0 +1,892 @@
+use std::collections::HashMap;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::evolution::{self, Evolve, GenerationStats};
+use crate::network::{Activation, MutationParams};
+use crate::simulation::Simulation;
+
+/// NeuroEvolution of Augmenting Topologies: genomes start as inputs wired
+/// straight to outputs and grow hidden nodes and connections as they evolve,
+/// protected by speciation while the new structure is tuned.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NeatConfig {
+    /// genomes closer than this belong to the same species
+    pub compatibility_threshold: f32,
+    /// weight of excess genes in the compatibility distance
+    pub excess_coefficient: f32,
+    /// weight of disjoint genes in the compatibility distance
+    pub disjoint_coefficient: f32,
+    /// weight of the mean weight difference of matching genes
+    pub weight_coefficient: f32,
+    /// chance of a child to get a hidden node splitting one of its connections
+    pub add_node_rate: f32,
+    pub add_connection_rate: f32,
+    pub disable_connection_rate: f32,
+    /// chance of a child to be bred from two parents of its species instead of one
+    pub crossover_rate: f32,
+    /// fraction of the fittest of every species allowed to breed
+    pub survival_threshold: f32,
+    /// species whose best fitness did not improve for this many generations
+    /// have no offspring, unless they hold the fittest genome
+    pub max_stagnation: u32,
+    /// mutation of the weights and biases of every child
+    pub weights: MutationParams,
+    pub hidden_activation: Activation,
+    pub output_activation: Activation,
+}
+impl Default for NeatConfig {
+    fn default() -> Self {
+        Self {
+            compatibility_threshold: 3.0,
+            excess_coefficient: 1.0,
+            disjoint_coefficient: 1.0,
+            weight_coefficient: 0.4,
+            add_node_rate: 0.03,
+            add_connection_rate: 0.05,
+            disable_connection_rate: 0.01,
+            crossover_rate: 0.75,
+            survival_threshold: 0.2,
+            max_stagnation: 15,
+            weights: MutationParams {
+                rate: 0.8,
+                sigma: 0.3,
+                reset_rate: 0.05,
+                clamp: Some(4.0),
+            },
+            hidden_activation: Activation::Tanh,
+            output_activation: Activation::Tanh,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Input,
+    Output,
+    Hidden,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeGene {
+    pub id: u32,
+    pub kind: NodeKind,
+    pub bias: f32,
+    /// softmax is not supported, a node is activated on its own
+    pub activation: Activation,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionGene {
+    /// the same for every connection between the same two nodes
+    pub innovation: u32,
+    pub from: u32,
+    pub to: u32,
+    pub weight: f32,
+    pub enabled: bool,
+}
+
+/// Hands out innovation numbers and hidden node ids so that the same
+/// structural mutation gets the same ones in every genome, which is what
+/// lets crossover and speciation line genes up.
+#[derive(Clone, Debug)]
+pub struct Innovations {
+    next_innovation: u32,
+    next_node: u32,
+    connections: HashMap<(u32, u32), u32>,
+    /// hidden node created by splitting a connection, by innovation of the connection
+    splits: HashMap<u32, u32>,
+}
+
+impl Innovations {
+    /// For genomes with `input_count` inputs and `output_count` outputs.
+    pub fn new(input_count: usize, output_count: usize) -> Self {
+        Self {
+            next_innovation: 0,
+            next_node: (input_count + output_count) as u32,
+            connections: HashMap::new(),
+            splits: HashMap::new(),
+        }
+    }
+
+    fn connection(&mut self, from: u32, to: u32) -> u32 {
+        *self.connections.entry((from, to)).or_insert_with(|| {
+            self.next_innovation += 1;
+            self.next_innovation - 1
+        })
+    }
+
+    fn split(&mut self, innovation: u32) -> u32 {
+        *self.splits.entry(innovation).or_insert_with(|| {
+            self.next_node += 1;
+            self.next_node - 1
+        })
+    }
+}
+
+/// A network grown by NEAT. Input nodes have the ids `0..input_count`,
+/// outputs the following ones and hidden nodes any id after them.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "GenomeGenes", into = "GenomeGenes")]
+pub struct Genome {
+    /// sorted by id
+    nodes: Vec<NodeGene>,
+    /// sorted by innovation
+    connections: Vec<ConnectionGene>,
+    input_count: usize,
+    output_count: usize,
+    plan: Plan,
+}
+
+/// How a genome is stored in files, only its genes.
+#[derive(Serialize, Deserialize, Clone)]
+struct GenomeGenes {
+    nodes: Vec<NodeGene>,
+    connections: Vec<ConnectionGene>,
+}
+impl TryFrom<GenomeGenes> for Genome {
+    type Error = String;
+
+    fn try_from(genes: GenomeGenes) -> std::result::Result<Self, Self::Error> {
+        let GenomeGenes {
+            mut nodes,
+            mut connections,
+        } = genes;
+        nodes.sort_by_key(|n| n.id);
+        connections.sort_by_key(|c| c.innovation);
+        let input_count = nodes.iter().filter(|n| n.kind == NodeKind::Input).count();
+        let output_count = nodes.iter().filter(|n| n.kind == NodeKind::Output).count();
+        for (i, node) in nodes.iter().enumerate() {
+            let expected = if i < input_count {
+                NodeKind::Input
+            } else if i < input_count + output_count {
+                NodeKind::Output
+            } else {
+                NodeKind::Hidden
+            };
+            if node.kind != expected || (node.kind != NodeKind::Hidden && node.id != i as u32) {
+                return Err(format!("node {} is out of place", node.id));
+            }
+            if i > 0 && nodes[i - 1].id == node.id {
+                return Err(format!("node {} appears twice", node.id));
+            }
+        }
+        if let Some(c) = connections
+            .windows(2)
+            .find(|c| c[0].innovation == c[1].innovation)
+        {
+            return Err(format!("innovation {} appears twice", c[0].innovation));
+        }
+        let plan = Plan::new(&nodes, &connections, input_count)?;
+        Ok(Self {
+            nodes,
+            connections,
+            input_count,
+            output_count,
+            plan,
+        })
+    }
+}
+impl From<Genome> for GenomeGenes {
+    fn from(genome: Genome) -> Self {
+        Self {
+            nodes: genome.nodes,
+            connections: genome.connections,
+        }
+    }
+}
+
+/// Evaluation order of a genome, rebuilt whenever its structure changes.
+#[derive(Clone, Default)]
+struct Plan {
+    /// indices of every node but the inputs, each after the nodes feeding it
+    order: Vec<usize>,
+    /// enabled connections into every node, as indices of the source node
+    /// and of the connection
+    incoming: Vec<Vec<(usize, usize)>>,
+    values: Vec<f32>,
+    outputs: Vec<f32>,
+}
+
+impl Plan {
+    fn new(
+        nodes: &[NodeGene],
+        connections: &[ConnectionGene],
+        input_count: usize,
+    ) -> Result<Self, String> {
+        let index = |id: u32| {
+            nodes
+                .binary_search_by_key(&id, |n| n.id)
+                .map_err(|_| format!("connection to missing node {}", id))
+        };
+        let mut incoming = vec![vec![]; nodes.len()];
+        let mut outgoing = vec![vec![]; nodes.len()];
+        let mut pending = vec![0usize; nodes.len()];
+        for (c, connection) in connections.iter().enumerate() {
+            let (from, to) = (index(connection.from)?, index(connection.to)?);
+            if to < input_count {
+                return Err(format!("connection into input {}", connection.to));
+            }
+            // disabled connections are ordered too, re-enabling one never makes a cycle
+            outgoing[from].push(to);
+            pending[to] += 1;
+            if connection.enabled {
+                incoming[to].push((from, c));
+            }
+        }
+
+        let mut ready: Vec<usize> = (0..nodes.len()).filter(|&n| pending[n] == 0).collect();
+        let mut order = Vec::with_capacity(nodes.len());
+        while let Some(n) = ready.pop() {
+            if n >= input_count {
+                order.push(n);
+            }
+            for &to in &outgoing[n] {
+                pending[to] -= 1;
+                if pending[to] == 0 {
+                    ready.push(to);
+                }
+            }
+        }
+        if order.len() != nodes.len() - input_count {
+            return Err("connections form a cycle".to_string());
+        }
+        let output_count = nodes.iter().filter(|n| n.kind == NodeKind::Output).count();
+        Ok(Self {
+            order,
+            incoming,
+            values: vec![0.0; nodes.len()],
+            outputs: vec![0.0; output_count],
+        })
+    }
+}
+
+impl Genome {
+    /// Every input connected to every output with a random weight.
+    pub fn new(
+        input_count: usize,
+        output_count: usize,
+        config: &NeatConfig,
+        innovations: &mut Innovations,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut nodes: Vec<NodeGene> = (0..input_count)
+            .map(|i| NodeGene {
+                id: i as u32,
+                kind: NodeKind::Input,
+                bias: 0.0,
+                activation: Activation::Linear,
+            })
+            .collect();
+        nodes.extend((0..output_count).map(|i| NodeGene {
+            id: (input_count + i) as u32,
+            kind: NodeKind::Output,
+            bias: rng.gen::<f32>() * 2.0 - 1.0,
+            activation: config.output_activation,
+        }));
+        let mut connections = vec![];
+        for from in 0..input_count as u32 {
+            for to in input_count as u32..(input_count + output_count) as u32 {
+                connections.push(ConnectionGene {
+                    innovation: innovations.connection(from, to),
+                    from,
+                    to,
+                    weight: rng.gen::<f32>() * 2.0 - 1.0,
+                    enabled: true,
+                });
+            }
+        }
+        connections.sort_by_key(|c| c.innovation);
+        let plan = Plan::new(&nodes, &connections, input_count).unwrap();
+        Self {
+            nodes,
+            connections,
+            input_count,
+            output_count,
+            plan,
+        }
+    }
+
+    pub fn input_count(&self) -> usize {
+        self.input_count
+    }
+
+    pub fn output_count(&self) -> usize {
+        self.output_count
+    }
+
+    pub fn nodes(&self) -> &[NodeGene] {
+        &self.nodes
+    }
+
+    pub fn connections(&self) -> &[ConnectionGene] {
+        &self.connections
+    }
+
+    /// Evaluates every node after the ones feeding it, without allocating.
+    pub fn feed_forward(&mut self, inputs: &[f32]) -> &[f32] {
+        assert_eq!(inputs.len(), self.input_count);
+        let Plan {
+            order,
+            incoming,
+            values,
+            outputs,
+        } = &mut self.plan;
+        values[..self.input_count].copy_from_slice(inputs);
+        for &n in order.iter() {
+            let node = &self.nodes[n];
+            let sum = incoming[n].iter().fold(node.bias, |sum, &(from, c)| {
+                sum + values[from] * self.connections[c].weight
+            });
+            let mut value = [sum];
+            node.activation.apply(&mut value);
+            values[n] = value[0];
+        }
+        let outputs_start = self.input_count;
+        outputs.copy_from_slice(&values[outputs_start..outputs_start + self.output_count]);
+        outputs
+    }
+
+    /// Perturbs the weights and biases and, each with its own chance, adds a
+    /// node, adds a connection and disables a connection.
+    pub fn mutate(
+        &mut self,
+        config: &NeatConfig,
+        innovations: &mut Innovations,
+        rng: &mut impl Rng,
+    ) {
+        for connection in self.connections.iter_mut() {
+            connection.weight = config.weights.mutate_gene(connection.weight, rng);
+        }
+        for node in self.nodes.iter_mut().filter(|n| n.kind != NodeKind::Input) {
+            node.bias = config.weights.mutate_gene(node.bias, rng);
+        }
+        if rng.gen::<f32>() < config.add_node_rate {
+            self.add_node(config.hidden_activation, innovations, rng);
+        }
+        if rng.gen::<f32>() < config.add_connection_rate {
+            self.add_connection(innovations, rng);
+        }
+        if rng.gen::<f32>() < config.disable_connection_rate {
+            self.disable_connection(rng);
+        }
+    }
+
+    /// Splits a random enabled connection in two with a new hidden node in
+    /// between. The connection into the node has a weight of 1 and the one
+    /// out of it the weight of the split connection, so the node starts out
+    /// close to transparent. Returns whether a node was added.
+    pub fn add_node(
+        &mut self,
+        activation: Activation,
+        innovations: &mut Innovations,
+        rng: &mut impl Rng,
+    ) -> bool {
+        let enabled: Vec<usize> = (0..self.connections.len())
+            .filter(|&c| self.connections[c].enabled)
+            .collect();
+        if enabled.is_empty() {
+            return false;
+        }
+        let c = enabled[rng.gen_range(0..enabled.len())];
+        let ConnectionGene {
+            innovation,
+            from,
+            to,
+            weight,
+            ..
+        } = self.connections[c];
+        let id = innovations.split(innovation);
+        // a child can inherit the split connection enabled next to the node
+        if self.node_index(id).is_some() {
+            return false;
+        }
+
+        self.connections[c].enabled = false;
+        let position = self.nodes.partition_point(|n| n.id < id);
+        self.nodes.insert(
+            position,
+            NodeGene {
+                id,
+                kind: NodeKind::Hidden,
+                bias: 0.0,
+                activation,
+            },
+        );
+        self.insert_connection(innovations, from, id, 1.0);
+        self.insert_connection(innovations, id, to, weight);
+        self.rebuild();
+        true
+    }
+
+    /// Connects two nodes that are not connected yet, as long as that does
+    /// not make a cycle. Returns whether a connection was added.
+    pub fn add_connection(&mut self, innovations: &mut Innovations, rng: &mut impl Rng) -> bool {
+        const ATTEMPTS: usize = 20;
+        for _ in 0..ATTEMPTS {
+            let from = self.nodes[rng.gen_range(0..self.nodes.len())].id;
+            let to = self.nodes[rng.gen_range(self.input_count..self.nodes.len())].id;
+            let connected = self
+                .connections
+                .iter()
+                .any(|c| (c.from, c.to) == (from, to));
+            if from == to || connected || self.reaches(to, from) {
+                continue;
+            }
+            self.insert_connection(innovations, from, to, rng.gen::<f32>() * 2.0 - 1.0);
+            self.rebuild();
+            return true;
+        }
+        false
+    }
+
+    /// Returns whether an enabled connection was disabled.
+    pub fn disable_connection(&mut self, rng: &mut impl Rng) -> bool {
+        let enabled: Vec<usize> = (0..self.connections.len())
+            .filter(|&c| self.connections[c].enabled)
+            .collect();
+        if enabled.is_empty() {
+            return false;
+        }
+        self.connections[enabled[rng.gen_range(0..enabled.len())]].enabled = false;
+        self.rebuild();
+        true
+    }
+
+    /// A child with the structure of `fitter`, genes both parents have coming
+    /// from either of them. A gene disabled in one parent is disabled in the
+    /// child three times out of four.
+    pub fn crossover(fitter: &Genome, other: &Genome, rng: &mut impl Rng) -> Genome {
+        let mut child = fitter.clone();
+        for node in child.nodes.iter_mut() {
+            if let Some(i) = other.node_index(node.id) {
+                if rng.gen::<bool>() {
+                    node.bias = other.nodes[i].bias;
+                }
+            }
+        }
+        for connection in child.connections.iter_mut() {
+            let Ok(i) = other
+                .connections
+                .binary_search_by_key(&connection.innovation, |c| c.innovation)
+            else {
+                continue;
+            };
+            let matching = &other.connections[i];
+            if rng.gen::<bool>() {
+                connection.weight = matching.weight;
+            }
+            if !connection.enabled || !matching.enabled {
+                connection.enabled = rng.gen::<f32>() >= 0.75;
+            }
+        }
+        child.rebuild();
+        child
+    }
+
+    /// How different the structures and weights of two genomes are, see
+    /// `NeatConfig::compatibility_threshold`.
+    pub fn distance(&self, other: &Genome, config: &NeatConfig) -> f32 {
+        let (a, b) = (&self.connections, &other.connections);
+        let (mut i, mut j) = (0, 0);
+        let (mut disjoint, mut matching, mut weight_difference) = (0, 0, 0.0);
+        while i < a.len() && j < b.len() {
+            match a[i].innovation.cmp(&b[j].innovation) {
+                std::cmp::Ordering::Equal => {
+                    matching += 1;
+                    weight_difference += (a[i].weight - b[j].weight).abs();
+                    i += 1;
+                    j += 1;
+                }
+                std::cmp::Ordering::Less => {
+                    disjoint += 1;
+                    i += 1;
+                }
+                std::cmp::Ordering::Greater => {
+                    disjoint += 1;
+                    j += 1;
+                }
+            }
+        }
+        let excess = (a.len() - i) + (b.len() - j);
+        let gene_count = a.len().max(b.len()).max(1) as f32;
+        config.excess_coefficient * excess as f32 / gene_count
+            + config.disjoint_coefficient * disjoint as f32 / gene_count
+            + config.weight_coefficient * weight_difference / matching.max(1) as f32
+    }
+
+    fn node_index(&self, id: u32) -> Option<usize> {
+        self.nodes.binary_search_by_key(&id, |n| n.id).ok()
+    }
+
+    /// Whether a path of connections, enabled or not, leads from `from` to `to`.
+    fn reaches(&self, from: u32, to: u32) -> bool {
+        let mut stack = vec![from];
+        let mut visited = vec![from];
+        while let Some(id) = stack.pop() {
+            if id == to {
+                return true;
+            }
+            for c in self.connections.iter().filter(|c| c.from == id) {
+                if !visited.contains(&c.to) {
+                    visited.push(c.to);
+                    stack.push(c.to);
+                }
+            }
+        }
+        false
+    }
+
+    fn insert_connection(
+        &mut self,
+        innovations: &mut Innovations,
+        from: u32,
+        to: u32,
+        weight: f32,
+    ) {
+        let innovation = innovations.connection(from, to);
+        let position = self
+            .connections
+            .partition_point(|c| c.innovation < innovation);
+        self.connections.insert(
+            position,
+            ConnectionGene {
+                innovation,
+                from,
+                to,
+                weight,
+                enabled: true,
+            },
+        );
+    }
+
+    fn rebuild(&mut self) {
+        self.plan = Plan::new(&self.nodes, &self.connections, self.input_count)
+            .expect("mutations keep genomes acyclic");
+    }
+}
+
+/// Genomes close enough to each other to compete among themselves.
+pub struct Species {
+    /// genomes are compared to it to find their species
+    representative: Genome,
+    /// indices of the members in the genomes of the population
+    members: Vec<usize>,
+    best_fitness: f64,
+    /// generations since `best_fitness` last improved
+    stagnation: u32,
+}
+
+impl Species {
+    fn len(&self) -> usize {
+        self.members.len()
+    }
+}
+
+pub struct NeatPopulation {
+    pub config: NeatConfig,
+    pub generation: u32,
+    pub history: Vec<GenerationStats>,
+    genomes: Vec<Genome>,
+    species: Vec<Species>,
+    /// fittest genomes of the last evaluated generation with their fitness, best first
+    champions: Vec<(Genome, f64)>,
+    innovations: Innovations,
+    rng: StdRng,
+}
+
+impl NeatPopulation {
+    /// `size` minimal genomes with random weights.
+    pub fn new(
+        config: NeatConfig,
+        size: usize,
+        input_count: usize,
+        output_count: usize,
+        seed: u64,
+    ) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut innovations = Innovations::new(input_count, output_count);
+        let genomes = (0..size)
+            .map(|_| {
+                Genome::new(
+                    input_count,
+                    output_count,
+                    &config,
+                    &mut innovations,
+                    &mut rng,
+                )
+            })
+            .collect();
+        Self {
+            config,
+            generation: 0,
+            history: vec![],
+            genomes,
+            species: vec![],
+            champions: vec![],
+            innovations,
+            rng,
+        }
+    }
+
+    pub fn genomes(&self) -> &[Genome] {
+        &self.genomes
+    }
+
+    /// Species of the last evaluated generation.
+    #[cfg(test)]
+    pub fn species(&self) -> &[Species] {
+        &self.species
+    }
+
+    pub fn champions(&self) -> &[(Genome, f64)] {
+        &self.champions
+    }
+
+    /// Replaces the current generation with its offspring, `fitness[i]` being
+    /// the fitness of `genomes()[i]`. Every species gets offspring in
+    /// proportion to the mean fitness of its members, so a species cannot
+    /// take over the population just by being large.
+    pub fn evolve(&mut self, fitness: &[f64]) -> GenerationStats {
+        assert_eq!(fitness.len(), self.genomes.len());
+        let ranking = evolution::rank(fitness);
+        let mut stats = evolution::stats(self.generation, fitness, &ranking);
+        self.champions = ranking
+            .iter()
+            .take(2)
+            .map(|&i| (self.genomes[i].clone(), fitness[i]))
+            .collect();
+
+        self.speciate();
+        stats.species = Some(self.species.len());
+        for species in self.species.iter_mut() {
+            species.members.sort_by(|&a, &b| {
+                fitness[b]
+                    .partial_cmp(&fitness[a])
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            let best = fitness[species.members[0]];
+            if best > species.best_fitness {
+                species.best_fitness = best;
+                species.stagnation = 0;
+            } else {
+                species.stagnation += 1;
+            }
+        }
+        let fittest = ranking.first().copied();
+        let max_stagnation = self.config.max_stagnation;
+        self.species.retain(|s| {
+            s.stagnation <= max_stagnation || fittest.is_some_and(|f| s.members[0] == f)
+        });
+
+        // shifted so that every species has a chance, fitness can be negative
+        let min = fitness.iter().copied().fold(f64::INFINITY, f64::min);
+        let shares: Vec<f64> = self
+            .species
+            .iter()
+            .map(|s| {
+                s.members
+                    .iter()
+                    .map(|&i| fitness[i] - min + 1.0)
+                    .sum::<f64>()
+                    / s.len() as f64
+            })
+            .collect();
+        let offspring = apportion(&shares, self.genomes.len());
+
+        let mut next = Vec::with_capacity(self.genomes.len());
+        for (species, &count) in self.species.iter().zip(&offspring) {
+            if count == 0 {
+                continue;
+            }
+            // the champion of every species breeding survives unchanged
+            next.push(self.genomes[species.members[0]].clone());
+            let survivors = (species.len() as f32 * self.config.survival_threshold).ceil() as usize;
+            let parents = &species.members[..survivors.clamp(1, species.len())];
+            for _ in 1..count {
+                let a = parents[self.rng.gen_range(0..parents.len())];
+                let mut child = if parents.len() > 1
+                    && self.rng.gen::<f32>() < self.config.crossover_rate
+                {
+                    let b = parents[self.rng.gen_range(0..parents.len())];
+                    let (fitter, other) = if fitness[a] >= fitness[b] {
+                        (a, b)
+                    } else {
+                        (b, a)
+                    };
+                    Genome::crossover(&self.genomes[fitter], &self.genomes[other], &mut self.rng)
+                } else {
+                    self.genomes[a].clone()
+                };
+                child.mutate(&self.config, &mut self.innovations, &mut self.rng);
+                next.push(child);
+            }
+        }
+
+        for species in self.species.iter_mut() {
+            species.representative = self.genomes[species.members[0]].clone();
+        }
+        self.genomes = next;
+        self.history.push(stats.clone());
+        self.generation += 1;
+        stats
+    }
+
+    /// Puts every genome in the first species whose representative is close
+    /// enough, or in a new one.
+    fn speciate(&mut self) {
+        for species in self.species.iter_mut() {
+            species.members.clear();
+        }
+        for (i, genome) in self.genomes.iter().enumerate() {
+            let threshold = self.config.compatibility_threshold;
+            match self
+                .species
+                .iter_mut()
+                .find(|s| genome.distance(&s.representative, &self.config) < threshold)
+            {
+                Some(species) => species.members.push(i),
+                None => {
+                    self.species.push(Species {
+                        representative: genome.clone(),
+                        members: vec![i],
+                        best_fitness: f64::NEG_INFINITY,
+                        stagnation: 0,
+                    });
+                }
+            }
+        }
+        self.species.retain(|s| !s.members.is_empty());
+    }
+}
+
+impl Evolve for NeatPopulation {
+    fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn next_generation(&mut self, sim: &mut Simulation) -> GenerationStats {
+        let stats = self.evolve(&sim.fitness());
+        sim.restart(&self.genomes);
+        stats
+    }
+}
+
+/// Splits `total` in proportion to `shares`, the rounding remainder going
+/// to the largest fractions.
+fn apportion(shares: &[f64], total: usize) -> Vec<usize> {
+    let sum: f64 = shares.iter().sum();
+    if shares.is_empty() || sum <= 0.0 {
+        return vec![0; shares.len()];
+    }
+    let exact: Vec<f64> = shares.iter().map(|s| s / sum * total as f64).collect();
+    let mut counts: Vec<usize> = exact.iter().map(|e| e.floor() as usize).collect();
+    let mut by_fraction: Vec<usize> = (0..shares.len()).collect();
+    by_fraction.sort_by(|&a, &b| {
+        (exact[b] - exact[b].floor())
+            .partial_cmp(&(exact[a] - exact[a].floor()))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let remainder = total - counts.iter().sum::<usize>();
+    for &i in by_fraction.iter().cycle().take(remainder) {
+        counts[i] += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn grows_acyclic_genomes_that_round_trip() {
+        let config = NeatConfig::default();
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut innovations = Innovations::new(5, 2);
+        let mut genome = Genome::new(5, 2, &config, &mut innovations, &mut rng);
+        for _ in 0..40 {
+            genome.add_node(Activation::Relu, &mut innovations, &mut rng);
+            genome.add_connection(&mut innovations, &mut rng);
+        }
+        genome.disable_connection(&mut rng);
+        assert!(genome.nodes().len() > 7);
+        assert!(genome.connections().iter().any(|c| !c.enabled));
+
+        let inputs = [0.3, -0.2, 0.9, 0.0, -1.0];
+        let outputs = genome.feed_forward(&inputs).to_vec();
+        let json = serde_json::to_string(&genome).unwrap();
+        let mut loaded: Genome = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.feed_forward(&inputs), outputs);
+
+        let mut cyclic: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let connections = cyclic["connections"].as_array_mut().unwrap();
+        let last = connections.last().unwrap().clone();
+        let mut back = last.clone();
+        back["from"] = last["to"].clone();
+        back["to"] = last["from"].clone();
+        back["innovation"] = 10_000.into();
+        connections.push(back);
+        assert!(serde_json::from_value::<Genome>(cyclic).is_err());
+    }
+
+    #[test]
+    fn same_mutation_same_innovation() {
+        let config = NeatConfig::default();
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut innovations = Innovations::new(1, 1);
+        let mut a = Genome::new(1, 1, &config, &mut innovations, &mut rng);
+        let mut b = a.clone();
+        assert_eq!(a.distance(&b, &config), 0.0);
+        let before = a.feed_forward(&[0.5]).to_vec();
+
+        // with a single connection both split the same one
+        assert!(a.add_node(Activation::Linear, &mut innovations, &mut rng));
+        assert!(b.add_node(Activation::Linear, &mut innovations, &mut rng));
+        assert_eq!(a.nodes(), b.nodes());
+        // and the new node passes its input on through the old weight
+        assert_eq!(a.feed_forward(&[0.5]), before);
+        let innovations_of = |g: &Genome| {
+            g.connections()
+                .iter()
+                .map(|c| c.innovation)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(innovations_of(&a), innovations_of(&b));
+
+        let child = Genome::crossover(&a, &b, &mut rng);
+        assert_eq!(innovations_of(&child), innovations_of(&a));
+    }
+
+    #[test]
+    fn speciates_and_keeps_the_champion() {
+        let config = NeatConfig {
+            add_node_rate: 0.5,
+            add_connection_rate: 0.5,
+            ..Default::default()
+        };
+        let mut population = NeatPopulation::new(config, 30, 4, 2, 9);
+        for generation in 0..5 {
+            let fitness: Vec<f64> = population
+                .genomes()
+                .iter()
+                .map(|g| g.connections().len() as f64 - generation as f64)
+                .collect();
+            let stats = population.evolve(&fitness);
+            assert_eq!(population.genomes().len(), 30);
+            assert!(!population.species().is_empty());
+            assert!(stats.species.is_some_and(|n| n >= population.species().len()));
+            let best = &population.champions()[0];
+            assert_eq!(best.1, stats.best);
+            assert!(population
+                .genomes()
+                .iter()
+                .any(|g| g.connections() == best.0.connections()));
+        }
+        assert_eq!(population.generation, 5);
+        assert_eq!(apportion(&[1.0, 1.0, 1.0], 10), vec![4, 3, 3]);
+    }
+}
@@ -1,18 +1,17 @@
-use crate::fns::lerpf32;
-use serde::{de::Error, Deserialize, Serialize};
-use serde_json::Result;
-use wgpu::{
-    util::{BufferInitDescriptor, DeviceExt},
-    BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
-    BindGroupLayoutEntry, BindingType, BufferBindingType, BufferDescriptor,
-    CommandEncoderDescriptor, ComputePassDescriptor, ComputePipeline, ComputePipelineDescriptor,
-    PipelineCompilationOptions, PipelineLayout, PipelineLayoutDescriptor, ShaderModuleDescriptor,
-    ShaderSource, ShaderStages,
-};
+use crate::car::ControlMode;
+use crate::fns::{lerpf32, sigmoid};
+use rand::Rng;
+use rand_distr::StandardNormal;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct NeuralNetwork {
     pub levels: Vec<Level>,
+    /// how cars read the outputs, kept in the metadata of saved brains
+    #[serde(skip)]
+    pub control: ControlMode,
 }
 impl NeuralNetwork {
     pub fn new(neuron_count: &[u32]) -> Self {
@@ -20,408 +19,1036 @@ impl NeuralNetwork {
         for i in 0..neuron_count.len() - 1 {
             levels.push(Level::new(neuron_count[i], neuron_count[i + 1]));
         }
-        Self { levels }
+        Self {
+            levels,
+            control: ControlMode::default(),
+        }
+    }
+
+    /// Like `new`, with `activations[i]` applied to the outputs of level `i`.
+    pub fn with_activations(neuron_count: &[u32], activations: &[Activation]) -> Self {
+        assert_eq!(activations.len() + 1, neuron_count.len());
+        let mut net = Self::new(neuron_count);
+        for (level, &activation) in net.levels.iter_mut().zip(activations) {
+            level.activation = activation;
+        }
+        net
+    }
+
+    /// Like `new`, with level `i` of kind `kinds[i]`.
+    pub fn with_kinds(neuron_count: &[u32], kinds: &[LevelKind]) -> Self {
+        assert_eq!(kinds.len() + 1, neuron_count.len());
+        let levels = neuron_count
+            .windows(2)
+            .zip(kinds)
+            .map(|(counts, &kind)| Level::with_kind(counts[0], counts[1], kind))
+            .collect();
+        Self {
+            levels,
+            control: ControlMode::default(),
+        }
+    }
+
+    pub fn activations(&self) -> Vec<Activation> {
+        self.levels.iter().map(|l| l.activation).collect()
+    }
+
+    pub fn kinds(&self) -> Vec<LevelKind> {
+        self.levels.iter().map(|l| l.kind).collect()
     }
 
-    pub fn randomize(&mut self) {
+    /// Whether the outputs depend on earlier inputs too, in which case every
+    /// network keeps its own state and cannot be part of a batch.
+    pub fn is_recurrent(&self) -> bool {
+        self.levels.iter().any(|l| l.kind != LevelKind::Dense)
+    }
+
+    /// Forgets every earlier input, as if the network had just been created.
+    pub fn reset_state(&mut self) {
+        for level in self.levels.iter_mut().filter(|l| l.kind != LevelKind::Dense) {
+            level.outputs.fill(0.0);
+        }
+    }
+
+    pub fn randomize(&mut self, rng: &mut impl Rng) {
         for level in self.levels.iter_mut() {
-            level.randomize();
+            level.randomize(rng);
         }
     }
 
-    pub fn feed_forward(&mut self, inputs: &Vec<f32>) -> &Vec<f32> {
-		let mut outputs = self.levels[0].feed_forward(inputs);
-        for i in 1..self.levels.len() {
-			outputs = self.levels[i].feed_forward(&outputs);
-			// let (used, remaining) = self.levels.split_at_mut(i);
-			// if i == 1 {
-			// 	used.last_mut().unwrap().feed_forward(inputs);
-			// } else {
-			// 	remaining.first_mut().unwrap().feed_forward(&used.last().unwrap().outputs);
-			// }
-        }
-		&self.levels.last().unwrap().outputs
-    }
-
-    pub async fn gpu_feed_forward<'a>(
-        &mut self,
-        inputs: &Vec<f32>,
-        gpu_handler_factory: &mut GpuHandlerFactory<'a>,
-    ) -> Vec<f32> {
-        let mut outputs = self.levels[0]
-            .gpu_feed_forward(inputs, gpu_handler_factory)
-            .await;
+    /// Runs the inputs through every level, each one feeding on the output
+    /// buffer of the previous one so that nothing is allocated.
+    pub fn feed_forward(&mut self, inputs: &[f32]) -> &[f32] {
+        self.levels[0].feed_forward(inputs);
         for i in 1..self.levels.len() {
-            outputs = self.levels[i]
-                .gpu_feed_forward(&outputs, gpu_handler_factory)
-                .await;
+            let (used, remaining) = self.levels.split_at_mut(i);
+            remaining[0].feed_forward(&used[i - 1].outputs);
         }
-        outputs
+        &self.levels.last().unwrap().outputs
+    }
+
+    /// Feeds a batch of observations, laid out one after the other in
+    /// `inputs`, through the network and returns their outputs laid out the
+    /// same way. Every level multiplies its weights with the whole batch at
+    /// once; each output is equal to what `feed_forward` gives for its observation.
+    /// Recurrent networks cannot be batched.
+    pub fn feed_forward_batch<'b>(
+        &self,
+        inputs: &[f32],
+        buffers: &'b mut BatchBuffers,
+    ) -> &'b [f32] {
+        assert!(!self.is_recurrent(), "recurrent networks cannot be batched");
+        let input_count = self.levels[0].input_count();
+        assert_eq!(inputs.len() % input_count.max(1), 0);
+        let batch = inputs.len() / input_count.max(1);
+        let BatchBuffers { current, next } = buffers;
+        for (x, level) in self.levels.iter().enumerate() {
+            let level_inputs: &[f32] = if x == 0 { inputs } else { current };
+            next.resize(batch * level.outputs.len(), 0.0);
+            level.feed_forward_batch(level_inputs, next);
+            std::mem::swap(current, next);
+        }
+        current
     }
 
+    /// Pulls the weights and biases toward those of `base`, which must have
+    /// the same topology and level kinds.
     pub fn prune(&mut self, base: &NeuralNetwork, t: f32) {
         for (x, level) in self.levels.iter_mut().enumerate() {
+            level.activation = base.levels[x].activation;
             for i in 0..level.biases.len() {
                 level.biases[i] = lerpf32(level.biases[i], base.levels[x].biases[i], t);
             }
 
-            for i in 0..level.weights.len() {
-                for j in 0..level.weights[i].len() {
-                    level.weights[i][j] =
-                        lerpf32(level.weights[i][j], base.levels[x].weights[i][j], t);
+            for (weight, base) in level.weights.iter_mut().zip(&base.levels[x].weights) {
+                *weight = lerpf32(*weight, *base, t);
+            }
+        }
+    }
+
+    pub fn mutate(&mut self, params: &MutationParams, rng: &mut impl Rng) {
+        for level in self.levels.iter_mut() {
+            level.mutate(params, rng);
+        }
+    }
+
+    /// Number of weights and biases.
+    pub fn parameter_count(&self) -> usize {
+        self.levels
+            .iter()
+            .map(|l| l.weights.len() + l.biases.len())
+            .sum()
+    }
+
+    /// Weights and biases flattened into one vector, level after level and
+    /// the weights of a level before its biases.
+    pub fn parameters(&self) -> Vec<f32> {
+        let mut parameters = Vec::with_capacity(self.parameter_count());
+        for level in self.levels.iter() {
+            parameters.extend_from_slice(&level.weights);
+            parameters.extend_from_slice(&level.biases);
+        }
+        parameters
+    }
+
+    /// Reverses `parameters`.
+    pub fn set_parameters(&mut self, parameters: &[f32]) {
+        assert_eq!(parameters.len(), self.parameter_count());
+        let mut rest = parameters;
+        for level in self.levels.iter_mut() {
+            let (weights, tail) = rest.split_at(level.weights.len());
+            let (biases, tail) = tail.split_at(level.biases.len());
+            level.weights.copy_from_slice(weights);
+            level.biases.copy_from_slice(biases);
+            rest = tail;
+        }
+    }
+
+    /// Neuron count of every layer, inputs first, as given to `NeuralNetwork::new`.
+    pub fn topology(&self) -> Vec<u32> {
+        let mut topology: Vec<u32> = self
+            .levels
+            .iter()
+            .take(1)
+            .map(|l| l.input_count() as u32)
+            .collect();
+        topology.extend(self.levels.iter().map(|l| l.outputs.len() as u32));
+        topology
+    }
+
+    /// Builds a child out of the genes of `parents`, which must all share the
+    /// same topology and level kinds.
+    pub fn crossover(
+        parents: &[&NeuralNetwork],
+        method: Crossover,
+        rng: &mut impl Rng,
+    ) -> std::result::Result<NeuralNetwork, CrossoverError> {
+        let first = parents.first().ok_or(CrossoverError::NoParents)?;
+        let expected = first.topology();
+        for parent in parents.iter().skip(1) {
+            let found = parent.topology();
+            if found != expected {
+                return Err(CrossoverError::TopologyMismatch { expected, found });
+            }
+            if parent.kinds() != first.kinds() {
+                return Err(CrossoverError::KindMismatch {
+                    expected: first.kinds(),
+                    found: parent.kinds(),
+                });
+            }
+        }
+
+        let mut child = (*first).clone();
+        match method {
+            Crossover::Uniform => {
+                for (x, level) in child.levels.iter_mut().enumerate() {
+                    for i in 0..level.biases.len() {
+                        let parent = parents[rng.gen_range(0..parents.len())];
+                        level.biases[i] = parent.levels[x].biases[i];
+                        for j in 0..level.row_width() {
+                            let parent = parents[rng.gen_range(0..parents.len())];
+                            level.row_mut(i)[j] = parent.levels[x].row(i)[j];
+                        }
+                    }
                 }
             }
+            Crossover::Neuron => {
+                for (x, level) in child.levels.iter_mut().enumerate() {
+                    for i in 0..level.biases.len() {
+                        let parent = parents[rng.gen_range(0..parents.len())];
+                        level.biases[i] = parent.levels[x].biases[i];
+                        level.row_mut(i).copy_from_slice(parent.levels[x].row(i));
+                    }
+                }
+            }
+            Crossover::SinglePoint => {
+                // one cut point between parents, each one hands down a contiguous run of levels
+                let mut cuts: Vec<usize> = (1..parents.len())
+                    .map(|_| rng.gen_range(0..=child.levels.len()))
+                    .collect();
+                cuts.sort_unstable();
+                for (x, level) in child.levels.iter_mut().enumerate() {
+                    let parent = parents[cuts.iter().filter(|&&c| c <= x).count()];
+                    level.biases.copy_from_slice(&parent.levels[x].biases);
+                    level.weights.copy_from_slice(&parent.levels[x].weights);
+                }
+            }
+        }
+        Ok(child)
+    }
+}
+
+/// Feeds row `k` of `inputs` through the `k`th brain into row `k` of
+/// `outputs`, the brains running in parallel.
+pub fn feed_forward_each<'a>(
+    brains: impl IndexedParallelIterator<Item = &'a mut NeuralNetwork>,
+    inputs: &[f32],
+    outputs: &mut [f32],
+) {
+    let count = brains.len().max(1);
+    brains
+        .zip(inputs.par_chunks(inputs.len() / count))
+        .zip(outputs.par_chunks_mut(outputs.len() / count))
+        .for_each(|((brain, inputs), outputs)| {
+            outputs.copy_from_slice(brain.feed_forward(inputs));
+        });
+}
+
+/// Scratch space of `NeuralNetwork::feed_forward_batch`, kept around so that
+/// batches of the same size do not allocate.
+#[derive(Default)]
+pub struct BatchBuffers {
+    current: Vec<f32>,
+    next: Vec<f32>,
+}
+
+/// Gaussian mutation of weights and biases.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MutationParams {
+    /// chance of every weight and bias to be perturbed
+    pub rate: f32,
+    /// standard deviation of the perturbation
+    pub sigma: f32,
+    /// chance of a weight or bias to be drawn again from scratch
+    pub reset_rate: f32,
+    /// weights and biases are kept within `-clamp..=clamp`
+    pub clamp: Option<f32>,
+}
+impl Default for MutationParams {
+    fn default() -> Self {
+        Self {
+            rate: 0.1,
+            sigma: 0.2,
+            reset_rate: 0.005,
+            clamp: Some(2.0),
+        }
+    }
+}
+impl MutationParams {
+    pub fn mutate_gene(&self, value: f32, rng: &mut impl Rng) -> f32 {
+        let value = if rng.gen::<f32>() < self.reset_rate {
+            rng.gen::<f32>() * 2.0 - 1.0
+        } else if rng.gen::<f32>() < self.rate {
+            value + rng.sample::<f32, _>(StandardNormal) * self.sigma
+        } else {
+            value
+        };
+        match self.clamp {
+            Some(clamp) => value.clamp(-clamp, clamp),
+            None => value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Crossover {
+    /// every weight and bias comes from a random parent
+    Uniform,
+    /// every neuron, its row of weights together with its bias, comes from a random parent
+    Neuron,
+    /// levels are split at random cut points, each parent hands down a run of levels
+    SinglePoint,
+}
+
+/// Function applied to the weighted sums of a level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Activation {
+    #[default]
+    Tanh,
+    Sigmoid,
+    Relu,
+    /// relu letting through `LEAKY_RELU_SLOPE` of negative sums
+    LeakyRelu,
+    Linear,
+    /// normalizes the whole level into probabilities, meant for output heads
+    Softmax,
+}
+
+pub const LEAKY_RELU_SLOPE: f32 = 0.01;
+
+impl Activation {
+    /// Applies the activation in place to the weighted sums of a whole level.
+    pub fn apply(&self, values: &mut [f32]) {
+        match self {
+            Activation::Tanh => values.iter_mut().for_each(|v| *v = v.tanh()),
+            Activation::Sigmoid => values
+                .iter_mut()
+                .for_each(|v| *v = sigmoid(*v as f64) as f32),
+            Activation::Relu => values.iter_mut().for_each(|v| *v = v.max(0.0)),
+            Activation::LeakyRelu => values.iter_mut().for_each(|v| {
+                if *v < 0.0 {
+                    *v *= LEAKY_RELU_SLOPE
+                }
+            }),
+            Activation::Linear => {}
+            Activation::Softmax => {
+                // shifted by the max so that exp cannot overflow
+                let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                values.iter_mut().for_each(|v| *v = (*v - max).exp());
+                let total: f32 = values.iter().sum();
+                values.iter_mut().for_each(|v| *v /= total);
+            }
+        }
+    }
+
+    /// Turns the gradient of a loss with respect to the `outputs` of `apply`
+    /// into its gradient with respect to the weighted sums, in place.
+    pub fn backward(&self, outputs: &[f32], grads: &mut [f32]) {
+        let derivative = |y: f32| match self {
+            Activation::Tanh => 1.0 - y * y,
+            Activation::Sigmoid => y * (1.0 - y),
+            Activation::Relu => (y > 0.0) as u8 as f32,
+            Activation::LeakyRelu => if y > 0.0 { 1.0 } else { LEAKY_RELU_SLOPE },
+            Activation::Linear | Activation::Softmax => 1.0,
+        };
+        if *self == Activation::Softmax {
+            // every output depends on every sum: g_i ← y_i (g_i - Σ_j g_j y_j)
+            let dot: f32 = outputs.iter().zip(grads.iter()).map(|(y, g)| y * g).sum();
+            grads.iter_mut().zip(outputs).for_each(|(g, y)| *g = y * (*g - dot));
+            return;
         }
+        grads
+            .iter_mut()
+            .zip(outputs)
+            .for_each(|(g, &y)| *g *= derivative(y));
     }
 
-    pub fn save_as_file(&self, path: &str) -> Result<()> {
-        let json = serde_json::to_string(&self).unwrap();
-        std::fs::write(path, json).unwrap();
-        Ok(())
+    /// Identifier the feed forward shader switches on.
+    pub fn shader_code(&self) -> u32 {
+        match self {
+            Activation::Tanh => 0,
+            Activation::Sigmoid => 1,
+            Activation::Relu => 2,
+            Activation::LeakyRelu => 3,
+            Activation::Linear => 4,
+            Activation::Softmax => 5,
+        }
+    }
+}
+
+/// How a level turns its inputs into outputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LevelKind {
+    #[default]
+    Dense,
+    /// dense over the inputs followed by the outputs of the previous call
+    Elman,
+    /// gated recurrent unit, the activation applies to the candidate state
+    Gru,
+}
+
+impl LevelKind {
+    /// Rows of weights, and biases, every output has.
+    pub fn gate_count(&self) -> usize {
+        match self {
+            LevelKind::Dense | LevelKind::Elman => 1,
+            LevelKind::Gru => 3,
+        }
     }
+}
 
-    pub fn load_from_file(path: &str) -> Result<NeuralNetwork> {
-        let json = std::fs::read_to_string(path);
-        if let Ok(json) = json {
-            return Ok(serde_json::from_str(&json).unwrap());
+#[derive(Debug, PartialEq)]
+pub enum CrossoverError {
+    NoParents,
+    TopologyMismatch { expected: Vec<u32>, found: Vec<u32> },
+    KindMismatch {
+        expected: Vec<LevelKind>,
+        found: Vec<LevelKind>,
+    },
+}
+impl fmt::Display for CrossoverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrossoverError::NoParents => write!(f, "crossover needs at least one parent"),
+            CrossoverError::TopologyMismatch { expected, found } => write!(
+                f,
+                "parents have different topologies: {:?} and {:?}",
+                expected, found
+            ),
+            CrossoverError::KindMismatch { expected, found } => write!(
+                f,
+                "parents have different level kinds: {:?} and {:?}",
+                expected, found
+            ),
         }
-        Err(Error::custom("File not found"))
     }
 }
+impl std::error::Error for CrossoverError {}
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "LevelFile", into = "LevelFile")]
 pub struct Level {
-    pub inputs: Vec<f32>,
+    /// scratch buffer the level writes its outputs into, the state of
+    /// recurrent levels
     pub outputs: Vec<f32>,
+    /// one per row of weights
     pub biases: Vec<f32>,
-    pub weights: Vec<Vec<f32>>,
+    /// row-major, row `i` holds the `row_width` weights of output `i`, gru
+    /// levels have their update gate rows first, then their reset gate rows,
+    /// then the rows of the candidate state
+    pub weights: Vec<f32>,
+    pub activation: Activation,
+    pub kind: LevelKind,
+    input_count: usize,
+    /// inputs followed by the previous outputs, those scaled by the reset
+    /// gate for gru levels, as multiplied with the weights of recurrent levels
+    context: Vec<f32>,
+    /// update gate, reset gate and candidate state of gru levels
+    gates: Vec<f32>,
+}
+
+/// How a level is stored in brain files, weights nested by output.
+#[derive(Serialize, Deserialize, Clone)]
+struct LevelFile {
+    biases: Vec<f32>,
+    weights: Vec<Vec<f32>>,
+    /// brains saved before activations were configurable all used tanh
+    #[serde(default)]
+    activation: Activation,
+    #[serde(default)]
+    kind: LevelKind,
+}
+impl TryFrom<LevelFile> for Level {
+    type Error = String;
+
+    fn try_from(file: LevelFile) -> std::result::Result<Self, Self::Error> {
+        let row_width = file.weights.first().map_or(0, |row| row.len());
+        if !file.biases.len().is_multiple_of(file.kind.gate_count()) {
+            return Err(format!(
+                "{:?} level has {} biases, not a multiple of {}",
+                file.kind,
+                file.biases.len(),
+                file.kind.gate_count()
+            ));
+        }
+        let output_count = file.biases.len() / file.kind.gate_count();
+        let input_count = match file.kind {
+            LevelKind::Dense => row_width,
+            LevelKind::Elman | LevelKind::Gru => row_width
+                .checked_sub(output_count)
+                .ok_or_else(|| format!("recurrent level has rows of {} weights", row_width))?,
+        };
+        if file.weights.len() != file.biases.len() {
+            return Err(format!(
+                "level has {} rows of weights but {} biases",
+                file.weights.len(),
+                file.biases.len()
+            ));
+        }
+        if let Some(row) = file.weights.iter().find(|row| row.len() != row_width) {
+            return Err(format!(
+                "level has rows of {} and {} weights",
+                row_width,
+                row.len()
+            ));
+        }
+        let mut level = Self::with_kind(input_count as u32, output_count as u32, file.kind);
+        level.biases = file.biases;
+        level.weights = file.weights.concat();
+        level.activation = file.activation;
+        Ok(level)
+    }
+}
+impl From<Level> for LevelFile {
+    fn from(level: Level) -> Self {
+        Self {
+            weights: level.rows().map(|row| row.to_vec()).collect(),
+            biases: level.biases,
+            activation: level.activation,
+            kind: level.kind,
+        }
+    }
 }
 
 impl Level {
     pub fn new(input_count: u32, output_count: u32) -> Self {
+        Self::with_kind(input_count, output_count, LevelKind::Dense)
+    }
+
+    pub fn with_kind(input_count: u32, output_count: u32, kind: LevelKind) -> Self {
+        let (n, m) = (input_count as usize, output_count as usize);
+        let row_width = match kind {
+            LevelKind::Dense => n,
+            LevelKind::Elman | LevelKind::Gru => n + m,
+        };
+        let row_count = m * kind.gate_count();
         Self {
-            inputs: vec![0.0; input_count as usize],
-            outputs: vec![0.0; output_count as usize],
-            biases: vec![0.0; output_count as usize],
-            weights: vec![vec![0.0; input_count as usize]; output_count as usize],
+            outputs: vec![0.0; m],
+            biases: vec![0.0; row_count],
+            weights: vec![0.0; row_count * row_width],
+            activation: Activation::default(),
+            kind,
+            input_count: n,
+            context: if kind == LevelKind::Dense {
+                vec![]
+            } else {
+                vec![0.0; row_width]
+            },
+            gates: if kind == LevelKind::Gru {
+                vec![0.0; row_count]
+            } else {
+                vec![]
+            },
         }
     }
 
-    pub fn randomize(&mut self) {
-        for i in 0..self.inputs.len() {
-            for j in 0..self.outputs.len() {
-                self.weights[j][i] = rand::random::<f32>() * 2.0 - 1.0;
+    pub fn input_count(&self) -> usize {
+        self.input_count
+    }
+
+    /// Weights in a row, the input count plus the output count for recurrent levels.
+    pub fn row_width(&self) -> usize {
+        match self.kind {
+            LevelKind::Dense => self.input_count,
+            LevelKind::Elman | LevelKind::Gru => self.input_count + self.outputs.len(),
+        }
+    }
+
+    /// Weights of row `i`, the weights of output `i` for all but gru levels.
+    pub fn row(&self, i: usize) -> &[f32] {
+        let width = self.row_width();
+        &self.weights[i * width..(i + 1) * width]
+    }
+
+    pub fn row_mut(&mut self, i: usize) -> &mut [f32] {
+        let width = self.row_width();
+        &mut self.weights[i * width..(i + 1) * width]
+    }
+
+    /// Weight from input `i` to output `j`, the one of the candidate state
+    /// for gru levels.
+    pub fn input_weight(&self, j: usize, i: usize) -> f32 {
+        let row = match self.kind {
+            LevelKind::Dense | LevelKind::Elman => j,
+            LevelKind::Gru => 2 * self.outputs.len() + j,
+        };
+        self.row(row)[i]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[f32]> {
+        // chunks_exact panics on 0, a level without inputs has empty rows
+        (0..self.biases.len()).map(|i| self.row(i))
+    }
+
+    pub fn randomize(&mut self, rng: &mut impl Rng) {
+        let (width, row_count) = (self.row_width(), self.biases.len());
+        for i in 0..width {
+            for j in 0..row_count {
+                self.weights[j * width + i] = rng.gen::<f32>() * 2.0 - 1.0;
             }
         }
 
         for i in 0..self.biases.len() {
-            self.biases[i] = rand::random::<f32>() * 2.0 - 1.0;
+            self.biases[i] = rng.gen::<f32>() * 2.0 - 1.0;
         }
     }
 
-    pub fn feed_forward(&mut self, inputs: &Vec<f32>) -> Vec<f32> {
-        assert_eq!(self.inputs.len(), inputs.len());
-        for i in 0..self.inputs.len() {
-            self.inputs[i] = inputs[i];
+    pub fn mutate(&mut self, params: &MutationParams, rng: &mut impl Rng) {
+        for weight in self.weights.iter_mut() {
+            *weight = params.mutate_gene(*weight, rng);
         }
+        for bias in self.biases.iter_mut() {
+            *bias = params.mutate_gene(*bias, rng);
+        }
+    }
 
-        for i in 0..self.outputs.len() {
-            let mut sum = 0.0;
-            for j in 0..self.inputs.len() {
-                sum += self.inputs[j] * self.weights[i][j];
+    /// Computes the outputs into `self.outputs` without allocating.
+    pub fn feed_forward(&mut self, inputs: &[f32]) -> &[f32] {
+        assert_eq!(self.input_count, inputs.len());
+        let n = self.input_count;
+        match self.kind {
+            LevelKind::Dense => {
+                mat_vec(&self.weights, inputs, &self.biases, &mut self.outputs);
+                self.activation.apply(&mut self.outputs);
+            }
+            LevelKind::Elman => {
+                self.context[..n].copy_from_slice(inputs);
+                self.context[n..].copy_from_slice(&self.outputs);
+                mat_vec(&self.weights, &self.context, &self.biases, &mut self.outputs);
+                self.activation.apply(&mut self.outputs);
+            }
+            LevelKind::Gru => {
+                // z = σ(Wz·[x, h] + bz), r = σ(Wr·[x, h] + br)
+                // c = f(Wc·[x, r⊙h] + bc), h' = (1 - z)⊙h + z⊙c
+                let m = self.outputs.len();
+                let split = 2 * m * self.row_width();
+                self.context[..n].copy_from_slice(inputs);
+                self.context[n..].copy_from_slice(&self.outputs);
+                let (gates, candidate) = self.gates.split_at_mut(2 * m);
+                mat_vec(&self.weights[..split], &self.context, &self.biases[..2 * m], gates);
+                Activation::Sigmoid.apply(gates);
+                let (update, reset) = gates.split_at(m);
+                for ((c, h), r) in self.context[n..].iter_mut().zip(&self.outputs).zip(reset) {
+                    *c = h * r;
+                }
+                mat_vec(&self.weights[split..], &self.context, &self.biases[2 * m..], candidate);
+                self.activation.apply(candidate);
+                for ((h, z), c) in self.outputs.iter_mut().zip(update).zip(candidate.iter()) {
+                    *h = (1.0 - z) * *h + z * c;
+                }
             }
-
-            sum += self.biases[i];
-
-            self.outputs[i] = sum.tanh();
         }
-
-		self.outputs.clone()
+        &self.outputs
     }
 
-    pub async fn gpu_feed_forward<'a>(
-        &mut self,
-        inputs: &Vec<f32>,
-        gpu_handler_factory: &mut GpuHandlerFactory<'a>,
-    ) -> Vec<f32> {
-        let flat_weights: Vec<f32> = self.weights.clone().into_iter().flatten().collect();
-        let matrix_buffer = MatrixBuffer::new(
-            gpu_handler_factory.device,
-            &inputs,
-            &flat_weights,
-            &self.biases,
-            &self.outputs,
-        );
-        let mut gpu_handler = gpu_handler_factory.create_handler(matrix_buffer);
-        gpu_handler.dispatch();
-        let outputs = gpu_handler.read_staging_buffer().await;
-        self.outputs = outputs.clone();
+    /// `feed_forward` of every observation in `inputs`, laid out one after
+    /// the other, into `outputs`. Blocks of the batch run in parallel.
+    /// Only for dense levels, recurrent ones keep a state per network.
+    pub fn feed_forward_batch(&self, inputs: &[f32], outputs: &mut [f32]) {
+        assert_eq!(self.kind, LevelKind::Dense);
+        let (n, m) = (self.input_count, self.outputs.len());
+        if m == 0 {
+            return;
+        }
         outputs
+            .par_chunks_mut(BATCH_BLOCK * m)
+            .zip(inputs.par_chunks(BATCH_BLOCK * n.max(1)))
+            .for_each(|(outputs, inputs)| {
+                mat_mat(&self.weights, inputs, &self.biases, outputs);
+                for outputs in outputs.chunks_exact_mut(m) {
+                    self.activation.apply(outputs);
+                }
+            });
     }
 }
 
-pub struct GpuHandlerFactory<'a> {
-    pub device: &'a wgpu::Device,
-    queue: &'a wgpu::Queue,
-    shader: wgpu::ShaderModule,
-    pub bind_group_layout: BindGroupLayout,
-    pub pipeline_layout: PipelineLayout,
+/// `outputs = weights · inputs + biases` for row-major `weights`.
+///
+/// Four rows are computed together so that their sums are independent and can
+/// be pipelined, while every sum still adds its products in input order,
+/// leaving the results bit for bit equal to a naive row by row loop.
+fn mat_vec(weights: &[f32], inputs: &[f32], biases: &[f32], outputs: &mut [f32]) {
+    let n = inputs.len();
+    let mut rows = outputs.chunks_exact_mut(4);
+    let mut i = 0;
+    for out in rows.by_ref() {
+        let w = &weights[i * n..(i + 4) * n];
+        let (w0, rest) = w.split_at(n);
+        let (w1, rest) = rest.split_at(n);
+        let (w2, w3) = rest.split_at(n);
+        let mut sums = [0.0f32; 4];
+        for ((((x, a), b), c), d) in inputs.iter().zip(w0).zip(w1).zip(w2).zip(w3) {
+            sums[0] += x * a;
+            sums[1] += x * b;
+            sums[2] += x * c;
+            sums[3] += x * d;
+        }
+        for k in 0..4 {
+            out[k] = sums[k] + biases[i + k];
+        }
+        i += 4;
+    }
+    for out in rows.into_remainder() {
+        let row = &weights[i * n..(i + 1) * n];
+        let mut sum = 0.0f32;
+        for (x, w) in inputs.iter().zip(row) {
+            sum += x * w;
+        }
+        *out = sum + biases[i];
+        i += 1;
+    }
 }
 
-pub struct GpuHandler<'a> {
-    device: &'a wgpu::Device,
-    queue: &'a wgpu::Queue,
-    shader: &'a wgpu::ShaderModule,
-    matrix_buffer: MatrixBuffer,
-    bind_group: BindGroup,
-    pipeline: ComputePipeline,
+/// observations of a batch given to one rayon task
+const BATCH_BLOCK: usize = 64;
+
+/// `mat_vec` of every observation in `inputs`, laid out one after the other.
+///
+/// Every row of weights is loaded once for the whole block and multiplied with
+/// four observations at a time, each sum again adding its products in input order.
+fn mat_mat(weights: &[f32], inputs: &[f32], biases: &[f32], outputs: &mut [f32]) {
+    let m = biases.len();
+    let batch = outputs.len() / m;
+    let n = inputs.len().checked_div(batch).unwrap_or(0);
+    for (i, bias) in biases.iter().enumerate() {
+        let row = &weights[i * n..(i + 1) * n];
+        let mut s = 0;
+        while s + 4 <= batch {
+            let x = &inputs[s * n..(s + 4) * n];
+            let (x0, rest) = x.split_at(n);
+            let (x1, rest) = rest.split_at(n);
+            let (x2, x3) = rest.split_at(n);
+            let mut sums = [0.0f32; 4];
+            for ((((w, a), b), c), d) in row.iter().zip(x0).zip(x1).zip(x2).zip(x3) {
+                sums[0] += a * w;
+                sums[1] += b * w;
+                sums[2] += c * w;
+                sums[3] += d * w;
+            }
+            for (k, sum) in sums.iter().enumerate() {
+                outputs[(s + k) * m + i] = sum + bias;
+            }
+            s += 4;
+        }
+        for s in s..batch {
+            let mut sum = 0.0f32;
+            for (x, w) in inputs[s * n..(s + 1) * n].iter().zip(row) {
+                sum += x * w;
+            }
+            outputs[s * m + i] = sum + bias;
+        }
+    }
 }
 
-impl<'a> GpuHandler<'a> {
-    pub fn new(factory: &'a mut GpuHandlerFactory, matrix_buffer: MatrixBuffer) -> Self {
-        let bind_group = factory.device.create_bind_group(&BindGroupDescriptor {
-            label: Some("feed forward bind group"),
-            layout: &factory.bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: matrix_buffer.input_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: matrix_buffer.weight_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: matrix_buffer.bias_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: matrix_buffer.output_buffer.as_entire_binding(),
-                },
-            ],
-        });
-        let pipeline = factory
-            .device
-            .create_compute_pipeline(&ComputePipelineDescriptor {
-                label: Some("feed forward pipeline"),
-                layout: Some(&factory.pipeline_layout),
-                module: &factory.shader,
-                entry_point: Some("main"),
-                compilation_options: PipelineCompilationOptions::default(),
-                cache: None,
-            });
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::time::Instant;
 
-        Self {
-            device: &factory.device,
-            queue: &factory.queue,
-            shader: &factory.shader,
-            matrix_buffer,
-            bind_group,
-            pipeline,
+    /// A level as weights nested by output, fed forward the way levels were
+    /// before their weights were flattened: copying the inputs and cloning the
+    /// outputs on every call.
+    struct NestedLevel {
+        inputs: Vec<f32>,
+        biases: Vec<f32>,
+        weights: Vec<Vec<f32>>,
+        activation: Activation,
+    }
+    impl NestedLevel {
+        fn feed_forward(&mut self, inputs: &[f32]) -> Vec<f32> {
+            self.inputs.copy_from_slice(inputs);
+            let mut outputs = vec![0.0; self.biases.len()];
+            for (i, output) in outputs.iter_mut().enumerate() {
+                let mut sum = 0.0;
+                for (input, weight) in self.inputs.iter().zip(&self.weights[i]) {
+                    sum += input * weight;
+                }
+                sum += self.biases[i];
+                *output = sum;
+            }
+            self.activation.apply(&mut outputs);
+            outputs.clone()
         }
     }
 
-    pub fn dispatch(&mut self) {
-        let mut encoder = self
-            .device
-            .create_command_encoder(&CommandEncoderDescriptor {
-                label: Some("feed forward encoder"),
+    struct NestedNetwork(Vec<NestedLevel>);
+    impl NestedNetwork {
+        fn new(net: &NeuralNetwork) -> Self {
+            let levels = net.levels.iter().map(|l| NestedLevel {
+                inputs: vec![0.0; l.input_count()],
+                biases: l.biases.clone(),
+                weights: l.rows().map(|row| row.to_vec()).collect(),
+                activation: l.activation,
             });
+            Self(levels.collect())
+        }
 
-        {
-            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: Some("feed forward compute pass"),
-                timestamp_writes: None,
-            });
-            compute_pass.set_pipeline(&self.pipeline);
-            compute_pass.set_bind_group(0, &self.bind_group, &[]);
-            compute_pass.dispatch_workgroups(64, 1, 1);
+        fn feed_forward(&mut self, inputs: &[f32]) -> Vec<f32> {
+            let mut outputs = self.0[0].feed_forward(inputs);
+            for level in self.0.iter_mut().skip(1) {
+                outputs = level.feed_forward(&outputs);
+            }
+            outputs
         }
+    }
 
-        encoder.copy_buffer_to_buffer(
-            &self.matrix_buffer.output_buffer,
-            0,
-            &self.matrix_buffer.staging_buffer,
-            0,
-            (64 * std::mem::size_of::<f32>()) as u64,
-        );
+    fn random_inputs(count: usize, rng: &mut impl Rng) -> Vec<f32> {
+        (0..count).map(|_| rng.gen::<f32>()).collect()
+    }
 
-        let cmd = encoder.finish();
-        self.queue.submit(Some(cmd));
+    fn filled(neuron_count: &[u32], value: f32) -> NeuralNetwork {
+        let mut net = NeuralNetwork::new(neuron_count);
+        for level in net.levels.iter_mut() {
+            level.biases.fill(value);
+            level.weights.fill(value);
+        }
+        net
     }
 
-    pub async fn read_staging_buffer(&self) -> Vec<f32> {
-        let (sender, receiver) = tokio::sync::oneshot::channel();
-        let buffer_slice = self.matrix_buffer.staging_buffer.slice(..);
+    #[test]
+    fn crossover_takes_genes_from_parents() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let a = filled(&[8, 6, 6, 2], 0.0);
+        let b = filled(&[8, 6, 6, 2], 1.0);
 
-        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
-            sender.send(result).unwrap();
-        });
-        self.device.poll(wgpu::Maintain::Wait);
-        receiver
-            .await
-            .expect("Failed to retrieve results")
-            .expect("Failed to map buffer");
+        for method in [Crossover::Uniform, Crossover::Neuron, Crossover::SinglePoint] {
+            let child = NeuralNetwork::crossover(&[&a, &b], method, &mut rng).unwrap();
+            assert_eq!(child.topology(), vec![8, 6, 6, 2]);
+            for level in child.levels.iter() {
+                for (i, row) in level.rows().enumerate() {
+                    assert!(row.iter().all(|&w| w == 0.0 || w == 1.0));
+                    if method != Crossover::Uniform {
+                        assert!(row.iter().all(|&w| w == level.biases[i]));
+                    }
+                }
+            }
+        }
+    }
 
-        let data = buffer_slice.get_mapped_range();
-        let results: Vec<f32> = bytemuck::cast_slice(&data).to_vec();
+    #[test]
+    fn mutation_respects_rate_and_clamp() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let parent = filled(&[8, 6, 2], 0.3);
 
-        drop(data);
-        self.matrix_buffer.staging_buffer.unmap();
+        let mut child = parent.clone();
+        let params = MutationParams {
+            rate: 0.0,
+            reset_rate: 0.0,
+            ..Default::default()
+        };
+        child.mutate(&params, &mut rng);
+        assert_eq!(child.levels[0].weights, parent.levels[0].weights);
 
-        results.iter().map(|x| *x as f32).collect()
+        let params = MutationParams {
+            rate: 1.0,
+            sigma: 5.0,
+            reset_rate: 0.0,
+            clamp: Some(0.5),
+        };
+        child.mutate(&params, &mut rng);
+        assert_ne!(child.levels[0].weights, parent.levels[0].weights);
+        for level in child.levels.iter() {
+            assert!(level.biases.iter().all(|b| b.abs() <= 0.5));
+            assert!(level.weights.iter().all(|w| w.abs() <= 0.5));
+        }
     }
-}
 
-impl<'a> GpuHandlerFactory<'a> {
-    pub fn new(device: &'a wgpu::Device, queue: &'a wgpu::Queue) -> Self {
-        let shader = device.create_shader_module(ShaderModuleDescriptor {
-            label: Some("feed forward shader"),
-            source: ShaderSource::Wgsl(Self::shader_mmul_64x64().into()),
-        });
-        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            label: Some("feed forward bind group layout"),
-            entries: &[
-                //inputs
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                //weights
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                //biases
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                //outputs
-                BindGroupLayoutEntry {
-                    binding: 3,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
-        });
-        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
-            label: Some("feed forward pipeline layout"),
-            bind_group_layouts: &[&bind_group_layout],
-            push_constant_ranges: &[],
-        });
-        Self {
-            device,
-            queue,
-            shader,
-            bind_group_layout,
-            pipeline_layout,
-        }
+    #[test]
+    fn applies_level_activations() {
+        let mut net = filled(&[2, 3, 3], 0.5);
+        net.levels[0].activation = Activation::Relu;
+        net.levels[1].activation = Activation::Softmax;
+        net.levels[1].row_mut(0)[0] = 2.0;
+        let outputs = net.feed_forward(&[-4.0, 1.0]).to_vec();
+        assert_eq!(net.levels[0].outputs, vec![0.0; 3]);
+        assert!((outputs.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(outputs.iter().all(|&o| (o - 1.0 / 3.0).abs() < 1e-6));
+
+        let mut values = [-2.0, 0.0, 3.0];
+        Activation::LeakyRelu.apply(&mut values);
+        assert_eq!(values, [-2.0 * LEAKY_RELU_SLOPE, 0.0, 3.0]);
+
+        let legacy: Level =
+            serde_json::from_str(r#"{ "biases": [0.0], "weights": [[0.0]] }"#).unwrap();
+        assert_eq!(legacy.activation, Activation::Tanh);
     }
 
-    pub fn create_handler(&mut self, buffer: MatrixBuffer) -> GpuHandler {
-        GpuHandler::new(self, buffer)
+    #[test]
+    fn flat_inference_matches_nested() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for topology in [vec![64, 64, 64, 4], vec![7, 13, 6, 3, 4], vec![5, 1]] {
+            let mut net = NeuralNetwork::new(&topology);
+            net.randomize(&mut rng);
+            let mut nested = NestedNetwork::new(&net);
+            for _ in 0..10 {
+                let inputs = random_inputs(topology[0] as usize, &mut rng);
+                assert_eq!(net.feed_forward(&inputs), nested.feed_forward(&inputs));
+            }
+        }
     }
 
-    fn shader_mmul_64x64() -> String {
-        let mut shader = r"
-@group(0) @binding(0) var<storage, read> inputs: array<f32>;        // Input array (length 64)
-@group(0) @binding(1) var<storage, read> weights: array<f32>; // Weights array (length 64x64)
-@group(0) @binding(2) var<storage, read> biases: array<f32>;        // Bias array (length 64)
-@group(0) @binding(3) var<storage, read_write> outputs: array<f32>;      // Output array (length 64)
+    #[test]
+    fn batches_match_single_inference() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let topology = [6, 9, 5, 4];
+        let mut brains: Vec<NeuralNetwork> = (0..7)
+            .map(|_| {
+                let mut brain = NeuralNetwork::new(&topology);
+                brain.randomize(&mut rng);
+                brain
+            })
+            .collect();
+        brains[0].levels[2].activation = Activation::Softmax;
+        let inputs = random_inputs(6 * 7, &mut rng);
+        let expected: Vec<f32> = brains
+            .iter_mut()
+            .zip(inputs.chunks(6))
+            .flat_map(|(brain, inputs)| brain.feed_forward(inputs).to_vec())
+            .collect();
 
-// Entry point for the compute shader
-@compute @workgroup_size(64) // Process 64 outputs in parallel
+        let mut outputs = vec![0.0; 4 * 7];
+        feed_forward_each(brains.par_iter_mut(), &inputs, &mut outputs);
+        assert_eq!(outputs, expected);
 
-fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    let i = global_id.x; // Index for output neuron
+        let mut shared = brains[0].clone();
+        let mut buffers = BatchBuffers::default();
+        let batched = shared.feed_forward_batch(&inputs, &mut buffers).to_vec();
+        for (inputs, outputs) in inputs.chunks(6).zip(batched.chunks(4)) {
+            assert_eq!(shared.feed_forward(inputs), outputs);
+        }
+    }
 
-    if (i < 64u) { // Check bounds
-        var sum: f32 = 0.0;
+    #[test]
+    fn recurrent_levels_remember_until_reset() {
+        let mut rng = StdRng::seed_from_u64(12);
+        for kind in [LevelKind::Elman, LevelKind::Gru] {
+            let mut net = NeuralNetwork::with_kinds(&[3, 5, 2], &[kind, LevelKind::Dense]);
+            net.randomize(&mut rng);
+            assert!(net.is_recurrent());
+            assert_eq!(net.topology(), vec![3, 5, 2]);
+            assert_eq!(net.levels[0].row_width(), 8);
+            let inputs = [0.4, -0.3, 0.9];
+            let run = |net: &mut NeuralNetwork| -> Vec<Vec<f32>> {
+                (0..3).map(|_| net.feed_forward(&inputs).to_vec()).collect()
+            };
 
-        // Perform dot product of inputs and weights for this output neuron
-        for (var j: u32 = 0u; j < 64u; j = j + 1u) {
-            sum = sum + inputs[j] * weights[i * 64u + j];
-        }
+            let outputs = run(&mut net);
+            assert_ne!(outputs[0], outputs[1], "{:?} does not remember", kind);
+            net.reset_state();
+            let mut loaded: NeuralNetwork =
+                serde_json::from_str(&serde_json::to_string(&net).unwrap()).unwrap();
+            assert_eq!(run(&mut net), outputs);
+            assert_eq!(run(&mut loaded), outputs);
 
-        // Add bias
-        sum = sum + biases[i];
+            let other = net.clone();
+            for method in [Crossover::Uniform, Crossover::Neuron, Crossover::SinglePoint] {
+                let mut child = NeuralNetwork::crossover(&[&net, &other], method, &mut rng).unwrap();
+                child.mutate(&MutationParams::default(), &mut rng);
+                assert_eq!(child.kinds(), net.kinds());
+                child.feed_forward(&inputs);
+            }
+        }
 
-        // Apply activation function (tanh)
-        outputs[i] = tanh(sum);
+        let elman = NeuralNetwork::with_kinds(&[3, 2], &[LevelKind::Elman]);
+        let dense = NeuralNetwork::new(&[3, 2]);
+        assert!(matches!(
+            NeuralNetwork::crossover(&[&elman, &dense], Crossover::Neuron, &mut rng),
+            Err(CrossoverError::KindMismatch { .. })
+        ));
     }
-}";
-        shader.to_string()
+
+    #[test]
+    fn gru_follows_its_equations() {
+        let mut level = Level::with_kind(1, 1, LevelKind::Gru);
+        // update gate, reset gate and candidate rows, each [input, state]
+        level.weights = vec![0.5, -1.0, 0.3, 0.8, 1.2, -0.7];
+        level.biases = vec![0.1, -0.2, 0.05];
+        let sigmoid = |v: f32| 1.0 / (1.0 + (-v).exp());
+        let mut h = 0.0f32;
+        for x in [1.0f32, -0.5, 0.25] {
+            let z = sigmoid(0.5 * x - 1.0 * h + 0.1);
+            let r = sigmoid(0.3 * x + 0.8 * h - 0.2);
+            let c = (1.2 * x - 0.7 * (r * h) + 0.05).tanh();
+            h = (1.0 - z) * h + z * c;
+            let output = level.feed_forward(&[x])[0];
+            assert!((output - h).abs() < 1e-6, "{} != {}", output, h);
+        }
     }
-}
 
-struct MatrixBuffer {
-    input_buffer: wgpu::Buffer,
-    weight_buffer: wgpu::Buffer,
-    bias_buffer: wgpu::Buffer,
-    output_buffer: wgpu::Buffer,
-    staging_buffer: wgpu::Buffer,
-}
+    /// cargo test --release bench_cpu_inference -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_cpu_inference() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let topology = crate::car::brain_topology(&[]);
+        let cars = 240;
+        let frames = 600;
+        let mut brains: Vec<NeuralNetwork> = (0..cars)
+            .map(|_| {
+                let mut brain = NeuralNetwork::new(&topology);
+                brain.randomize(&mut rng);
+                brain
+            })
+            .collect();
+        let mut nested: Vec<NestedNetwork> = brains.iter().map(NestedNetwork::new).collect();
+        let inputs = random_inputs(topology[0] as usize, &mut rng);
 
-impl MatrixBuffer {
-    pub fn new(
-        device: &wgpu::Device,
-        input: &Vec<f32>,
-        weights: &Vec<f32>,
-        biases: &Vec<f32>,
-        outputs: &Vec<f32>,
-    ) -> Self {
-        Self {
-            input_buffer: device.create_buffer_init(&BufferInitDescriptor {
-                label: Some("input buffer"),
-                contents: bytemuck::cast_slice(input),
-                usage: wgpu::BufferUsages::STORAGE,
-            }),
-            weight_buffer: device.create_buffer_init(&BufferInitDescriptor {
-                label: Some("weight buffer"),
-                contents: bytemuck::cast_slice(weights),
-                usage: wgpu::BufferUsages::STORAGE,
-            }),
-            bias_buffer: device.create_buffer_init(&BufferInitDescriptor {
-                label: Some("bias buffer"),
-                contents: bytemuck::cast_slice(biases),
-                usage: wgpu::BufferUsages::STORAGE,
-            }),
-            output_buffer: device.create_buffer_init(&BufferInitDescriptor {
-                label: Some("output buffer"),
-                contents: bytemuck::cast_slice(outputs),
-                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
-            }),
-            staging_buffer: device.create_buffer(&BufferDescriptor {
-                label: Some("staging buffer"),
-                size: outputs.len() as u64 * std::mem::size_of::<f32>() as u64,
-                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-                mapped_at_creation: false,
-            }),
+        let start_time = Instant::now();
+        let mut checksum = 0.0;
+        for _ in 0..frames {
+            for brain in nested.iter_mut() {
+                checksum += brain.feed_forward(&inputs)[0];
+            }
+        }
+        let nested_time = start_time.elapsed();
+
+        let start_time = Instant::now();
+        let mut flat_checksum = 0.0;
+        for _ in 0..frames {
+            for brain in brains.iter_mut() {
+                flat_checksum += brain.feed_forward(&inputs)[0];
+            }
         }
+        let flat_time = start_time.elapsed();
+
+        assert_eq!(checksum, flat_checksum);
+        println!(
+            "{} cars, {} frames: nested {} ms, flat {} ms, {:.2}x",
+            cars,
+            frames,
+            nested_time.as_millis(),
+            flat_time.as_millis(),
+            nested_time.as_secs_f64() / flat_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn crossover_rejects_mismatched_topologies() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let a = filled(&[8, 6, 2], 0.0);
+        let b = filled(&[8, 4, 2], 1.0);
+        let err = NeuralNetwork::crossover(&[&a, &b], Crossover::Uniform, &mut rng);
+        assert_eq!(
+            err.err(),
+            Some(CrossoverError::TopologyMismatch {
+                expected: vec![8, 6, 2],
+                found: vec![8, 4, 2],
+            })
+        );
     }
 }
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+
+use crate::sensor::SensorLayout;
+
+/// A piece of the state of a car, normalized to about [-1, 1], that can be
+/// appended to the sensor readings a brain is fed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// velocity over the top speed, negative when reversing
+    Velocity,
+    /// steering angle over its maximum, negative to the left
+    SteeringAngle,
+    /// heading relative to straight up the road over half a turn, negative
+    /// to the left
+    Heading,
+    /// distance from the closest lane center over half a lane, negative to
+    /// the left
+    LaneOffset,
+}
+
+/// What a car knows about itself, which every feature is taken from.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CarState {
+    /// in meters per second
+    pub velocity: f32,
+    /// in meters per second
+    pub max_velocity: f32,
+    /// in degrees
+    pub steering_angle: f32,
+    /// in degrees
+    pub steering_max_angle: f32,
+    /// in degrees, clockwise from straight up the road
+    pub heading: f64,
+    /// signed horizontal distance from the closest lane center, in pixels
+    pub lane_offset: f32,
+    /// in pixels
+    pub lane_width: f32,
+}
+
+impl Feature {
+    pub fn observe(&self, state: &CarState) -> f32 {
+        match self {
+            Feature::Velocity => state.velocity / state.max_velocity.max(f32::EPSILON),
+            Feature::SteeringAngle => {
+                state.steering_angle / state.steering_max_angle.max(f32::EPSILON)
+            }
+            Feature::Heading => {
+                let heading = state.heading.rem_euclid(360.0);
+                let heading = if heading > 180.0 {
+                    heading - 360.0
+                } else {
+                    heading
+                };
+                (heading / 180.0) as f32
+            }
+            Feature::LaneOffset => state.lane_offset / (state.lane_width / 2.0).max(f32::EPSILON),
+        }
+    }
+}
+
+/// What a brain is fed besides the readings of the sensors.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObservationConfig {
+    /// appended to the sensor readings in this order, brains only drive
+    /// cars observing the features they were trained with
+    pub features: Vec<Feature>,
+}
+
+/// Inputs of a brain fed the readings of `sensors` followed by `features`.
+pub fn input_count(sensors: &[SensorLayout], features: &[Feature]) -> u32 {
+    sensors.iter().map(|s| s.ray_count).sum::<u32>() + features.len() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalizes_the_car_state() {
+        let state = CarState {
+            velocity: -5.0,
+            max_velocity: 20.0,
+            steering_angle: 35.0,
+            steering_max_angle: 70.0,
+            heading: 315.0,
+            lane_offset: -27.0,
+            lane_width: 108.0,
+        };
+        let observe = |f: Feature| f.observe(&state);
+        assert_eq!(observe(Feature::Velocity), -0.25);
+        assert_eq!(observe(Feature::SteeringAngle), 0.5);
+        assert_eq!(observe(Feature::Heading), -0.25);
+        assert_eq!(observe(Feature::LaneOffset), -0.5);
+        let straight = CarState {
+            heading: 0.0,
+            ..state
+        };
+        assert_eq!(Feature::Heading.observe(&straight), 0.0);
+        assert_eq!(Feature::Velocity.observe(&CarState::default()), 0.0);
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::gpu::{GpuContext, GpuInference};
+use crate::inference::{CpuInference, Inference};
+use crate::network::{Activation, NeuralNetwork};
+
+pub const DEFAULT_TOLERANCE: f32 = 1e-4;
+
+const ACTIVATIONS: [Activation; 6] = [
+    Activation::Tanh,
+    Activation::Sigmoid,
+    Activation::Relu,
+    Activation::LeakyRelu,
+    Activation::Linear,
+    Activation::Softmax,
+];
+
+/// Absolute differences between the outputs of both backends.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ErrorStats {
+    pub max: f32,
+    pub mean: f32,
+}
+
+impl ErrorStats {
+    pub fn between(cpu: &[f32], gpu: &[f32]) -> Self {
+        assert_eq!(cpu.len(), gpu.len());
+        let errors = cpu.iter().zip(gpu).map(|(c, g)| (c - g).abs());
+        let (max, sum) = errors.fold((0.0f32, 0.0), |(max, sum), e| (max.max(e), sum + e));
+        Self {
+            // a NaN on one side only is as wrong as it gets
+            max: if cpu.iter().zip(gpu).any(|(c, g)| c.is_nan() != g.is_nan()) {
+                f32::INFINITY
+            } else {
+                max
+            },
+            mean: sum / cpu.len().max(1) as f32,
+        }
+    }
+}
+
+impl fmt::Display for ErrorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "max {:.3e} mean {:.3e}", self.max, self.mean)
+    }
+}
+
+pub struct ParityReport {
+    pub topology: Vec<u32>,
+    pub activations: Vec<Activation>,
+    /// error of every level of a single network, evaluated as a batch of one row
+    pub levels: Vec<ErrorStats>,
+    /// error of the outputs of a population evaluated as one batch, see `GpuInference`
+    pub batch: ErrorStats,
+}
+
+impl ParityReport {
+    pub fn max(&self) -> f32 {
+        self.levels
+            .iter()
+            .chain([&self.batch])
+            .map(|e| e.max)
+            .fold(0.0, f32::max)
+    }
+}
+
+impl fmt::Display for ParityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "topology {:?}", self.topology)?;
+        for (i, (error, activation)) in self.levels.iter().zip(&self.activations).enumerate() {
+            writeln!(f, "  level {} {:?}: {}", i, activation, error)?;
+        }
+        write!(f, "  batch: {}", self.batch)
+    }
+}
+
+/// The adapter wgpu picks, or its software fallback. `None` when there is neither.
+pub fn adapter() -> Option<Arc<GpuContext>> {
+    GpuContext::new(false)
+        .or_else(|| GpuContext::new(true))
+        .map(Arc::new)
+}
+
+/// Largest absolute error allowed, `PARITY_TOLERANCE` overrides the default.
+pub fn tolerance() -> f32 {
+    env_or("PARITY_TOLERANCE", DEFAULT_TOLERANCE)
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is not valid: {}", name, value)),
+        Err(_) => default,
+    }
+}
+
+/// Between one and four levels of up to 96 neurons each, with random
+/// activations and weights.
+pub fn random_network(rng: &mut impl Rng) -> NeuralNetwork {
+    let level_count = rng.gen_range(1..=4);
+    let topology: Vec<u32> = (0..=level_count).map(|_| rng.gen_range(1..=96)).collect();
+    let activations: Vec<Activation> = (0..level_count)
+        .map(|_| ACTIVATIONS[rng.gen_range(0..ACTIVATIONS.len())])
+        .collect();
+    let mut network = NeuralNetwork::with_activations(&topology, &activations);
+    network.randomize(rng);
+    network
+}
+
+/// Feeds random inputs through `network` on both backends, then `rows`
+/// random inputs through a population of networks shaped like it.
+pub fn check(
+    gpu: &Arc<GpuContext>,
+    network: &NeuralNetwork,
+    rows: usize,
+    rng: &mut impl Rng,
+) -> ParityReport {
+    let topology = network.topology();
+    let input_count = topology[0] as usize;
+    let inputs = random_inputs(input_count, rng);
+
+    let mut cpu_network = network.clone();
+    cpu_network.feed_forward(&inputs);
+    let mut single = GpuInference::new(gpu.clone());
+    single.load(std::slice::from_ref(network));
+    let gpu_levels = single.infer_levels(&[0], &inputs);
+    let levels = cpu_network
+        .levels
+        .iter()
+        .zip(&gpu_levels)
+        .map(|(cpu, gpu)| ErrorStats::between(&cpu.outputs, gpu))
+        .collect();
+
+    let brains: Vec<NeuralNetwork> = (0..rng.gen_range(2..=5))
+        .map(|_| {
+            let mut brain = network.clone();
+            brain.randomize(rng);
+            brain
+        })
+        .collect();
+    let brain_indices: Vec<u32> = (0..rows)
+        .map(|_| rng.gen_range(0..brains.len() as u32))
+        .collect();
+    let observations = random_inputs(rows * input_count, rng);
+    let mut cpu_inference = CpuInference::default();
+    let mut gpu_inference = GpuInference::new(gpu.clone());
+    cpu_inference.load(&brains);
+    gpu_inference.load(&brains);
+    let batch = ErrorStats::between(
+        cpu_inference.infer(&brain_indices, &observations),
+        gpu_inference.infer(&brain_indices, &observations),
+    );
+
+    ParityReport {
+        topology,
+        activations: network.activations(),
+        levels,
+        batch,
+    }
+}
+
+/// Uniform in [-1, 1], like sensor readings.
+fn random_inputs(count: usize, rng: &mut impl Rng) -> Vec<f32> {
+    (0..count).map(|_| rng.gen::<f32>() * 2.0 - 1.0).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// `PARITY_CASES` and `PARITY_SEED` pick how many networks are tried and
+    /// the seed they are generated from.
+    #[test]
+    fn gpu_matches_cpu() {
+        let Some(gpu) = adapter() else {
+            println!("no adapter, skipping the parity check");
+            return;
+        };
+        let tolerance = tolerance();
+        let seed = env_or("PARITY_SEED", 0);
+        let mut rng = StdRng::seed_from_u64(seed);
+        println!("{} ({:?})", gpu.adapter_info.name, gpu.adapter_info.backend);
+
+        let mut failures = vec![];
+        for case in 0..env_or("PARITY_CASES", 24) {
+            let network = random_network(&mut rng);
+            let rows = rng.gen_range(1..=300);
+            let report = check(&gpu, &network, rows, &mut rng);
+            println!("case {}, {}", case, report);
+            if report.max() > tolerance {
+                failures.push(case);
+            }
+        }
+        assert!(
+            failures.is_empty(),
+            "cases {:?} of seed {} exceed the tolerance of {:e}",
+            failures,
+            seed,
+            tolerance
+        );
+    }
+
+    #[test]
+    fn reports_the_largest_error() {
+        let error = ErrorStats::between(&[1.0, 2.0, 3.0, 4.0], &[1.0, 2.5, 3.0, 3.9]);
+        assert_eq!(error.max, 0.5);
+        assert!((error.mean - 0.15).abs() < 1e-6);
+        assert_eq!(ErrorStats::between(&[1.0], &[f32::NAN]).max, f32::INFINITY);
+    }
+}
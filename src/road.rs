@@ -54,7 +54,7 @@ impl Road {
                 border.start.x,
                 border.start.y - offset as i32,
                 5,
-                ((border.end.y - border.start.y) - offset as i32) as u32,
+                (border.end.y - border.start.y) as u32,
             );
             canvas.fill_rect(rect).map_err(|e| e.to_string())?;
         }
@@ -105,8 +105,37 @@ impl Road {
         }
     }
 
-	pub fn random_lane_idx(&self) -> u32 {
-		rand::thread_rng().gen_range(0..(self.lanes as u32))
+	/// Moves the borders along with the view, whose top is at `offset`, so
+	/// that the road never ends however far the cars drive.
+	pub fn follow(&mut self, offset: f32) {
+		let y = offset as i32;
+		for border in self.borders.iter_mut() {
+			border.start.y = y - INFINITY / 2;
+			border.end.y = y + INFINITY / 2;
+		}
+	}
+
+	pub fn random_lane_idx(&self, rng: &mut impl Rng) -> u32 {
+		rng.gen_range(0..(self.lanes as u32))
+	}
+
+	/// Horizontal distance from `x` to the closest lane center, in pixels.
+	pub fn lane_center_deviation(&self, x: f32) -> f32 {
+		self.lane_center_offset(x).abs()
+	}
+
+	/// Signed horizontal distance from the closest lane center to `x`,
+	/// negative left of it, in pixels.
+	pub fn lane_center_offset(&self, x: f32) -> f32 {
+		(0..self.lanes as u32)
+			.filter_map(|i| self.lane_center(i))
+			.map(|center| x - center)
+			.fold(f32::INFINITY, |a, b| if b.abs() < a.abs() { b } else { a })
+	}
+
+	/// in pixels
+	pub fn lane_width(&self) -> f32 {
+		(self.width / self.lanes) as f32
 	}
 
 	pub fn is_close_to_lane_center(&self, x: f32, tolerance: f32) -> bool {
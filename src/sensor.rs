@@ -1,5 +1,3 @@
-use std::f64::consts::PI;
-
 use crate::car::Car;
 use crate::fns::{get_intersectionf, lerpf64};
 use crate::road::Border;
@@ -7,6 +5,18 @@ use sdl2::pixels::Color;
 use sdl2::rect::FPoint;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
+use serde::{Deserialize, Serialize};
+
+/// Shape of a sensor, recorded in brain files since the inputs of a brain
+/// only make sense for the sensors it was trained with.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SensorLayout {
+    pub ray_count: u32,
+    /// in pixels
+    pub ray_length: f32,
+    /// in radians
+    pub ray_spread: f64,
+}
 
 pub struct Sensor {
     pub rays: Vec<Ray>,
@@ -14,6 +24,10 @@ pub struct Sensor {
 }
 
 impl Sensor {
+    pub fn from_layout(layout: SensorLayout, w: u16, h: u16) -> Self {
+        Self::new(layout.ray_count, layout.ray_length, layout.ray_spread, w, h)
+    }
+
     pub fn new(ray_count: u32, ray_length: f32, ray_spread: f64, w: u16, h: u16) -> Self {
         let mut rays = Vec::new();
         let start = FPoint::new(0.0, 0.0);
@@ -41,21 +55,20 @@ impl Sensor {
         x: f32,
         y: f32,
         angle: f64,
-		offset: f32,
         borders: &Vec<Border>,
-        traffic: &Vec<Car>,
+        traffic: &[Car],
     ) -> &'a Vec<f32> {
 		for (i, ray) in self.rays.iter_mut().enumerate() {
-			ray.update(x, y, angle, offset, borders, traffic);
+			ray.update(x, y, angle, borders, traffic);
 			self.readings[i] = ray.value.unwrap_or(0.0);
 		}
 		// println!("readings: {:#?}", &self.readings);
 		&self.readings
     }
 
-    pub fn render(&self, canvas: &mut Canvas<Window>) -> Result<(), String> {
+    pub fn render(&self, canvas: &mut Canvas<Window>, offset: f32) -> Result<(), String> {
         for ray in self.rays.iter() {
-            ray.render(canvas)?;
+            ray.render(canvas, offset)?;
         }
 		Ok(())
     }
@@ -91,13 +104,12 @@ impl Ray {
         x: f32,
         y: f32,
         angle: f64,
-		offset: f32,
         borders: &Vec<Border>,
-        traffic: &Vec<Car>,
+        traffic: &[Car],
     ) {
         let (base_x, base_y) = (
 			x + self.w as f32 / 2.0,
-			(y + self.h as f32 / 2.0) - offset
+			y + self.h as f32 / 2.0
 		);
 
         self.start.x = base_x;
@@ -155,15 +167,18 @@ impl Ray {
         }
     }
 
-    pub fn render(&self, canvas: &mut Canvas<Window>) -> Result<(), String> {
+    pub fn render(&self, canvas: &mut Canvas<Window>, offset: f32) -> Result<(), String> {
+        let start = FPoint::new(self.start.x, self.start.y - offset);
+        let mid = FPoint::new(self.mid.x, self.mid.y - offset);
+        let end = FPoint::new(self.end.x, self.end.y - offset);
         canvas.set_draw_color(Color::RGB(32, 232, 32));
-        canvas.draw_fline(self.start, self.mid)?;
+        canvas.draw_fline(start, mid)?;
 
         let val = self.value.unwrap_or(0.0);
 
         if val > 0.1 && val < 0.99 {
             canvas.set_draw_color(Color::RGB(255, 32, 64));
-            canvas.draw_fline(self.mid, self.end)?;
+            canvas.draw_fline(mid, end)?;
         };
 		Ok(())
     }
@@ -0,0 +1,717 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::brain::Brain;
+use crate::car::{self, Car, ControlMode, ControlledCar};
+use crate::fitness::{self, Fitness};
+use crate::inference::{self, Backend, Inference};
+use crate::network::NeuralNetwork;
+use crate::observation::Feature;
+use crate::road::Road;
+
+/// y every car starts a generation at
+const START_Y: f32 = 600.0;
+
+pub struct SimulationConfig {
+    /// world x of the road center, in pixels
+    pub road_center_x: i32,
+    /// in pixels
+    pub road_width: i32,
+    pub lanes: i32,
+    /// height of the stretch of road tracked around the leading car, in pixels
+    pub view_height: f32,
+    pub amount_cars: u32,
+    pub traffic_size: u32,
+    /// in meters per second
+    pub traffic_min_velocity: f32,
+    /// seed of the simulation rng, a random one is picked when missing
+    pub seed: Option<u64>,
+    /// when set, physics always advances by this many seconds per step
+    /// regardless of the wall clock
+    pub fixed_delta_t_s: Option<f32>,
+    /// a generation ends once every car is out or after this many simulated seconds
+    pub generation_time_limit_s: f32,
+    pub inference: Backend,
+    /// features of their own state the AI cars append to their sensor readings
+    pub features: Vec<Feature>,
+}
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            road_center_x: 540,
+            road_width: 324,
+            lanes: 3,
+            view_height: 800.0,
+            amount_cars: 200,
+            traffic_size: 4,
+            traffic_min_velocity: 27.33, // ~98 km/h
+            seed: None,
+            fixed_delta_t_s: None,
+            generation_time_limit_s: 60.0,
+            inference: Backend::default(),
+            features: vec![],
+        }
+    }
+}
+
+/// Owns the road, the AI cars and the traffic and advances them in world
+/// coordinates. Nothing in here needs a window, so it can run headless.
+pub struct Simulation {
+    pub config: SimulationConfig,
+    pub road: Road,
+    pub cars: Vec<Car>,
+    pub traffic: Vec<Car>,
+    pub controlled_car: Option<ControlledCar>,
+    /// scores every AI car after each step
+    pub fitness: Box<dyn Fitness>,
+    pub leader_idx: usize,
+    pub top_score_idx: usize,
+    /// simulated seconds since the current generation started
+    pub elapsed_s: f32,
+    /// when set every AI car drives with this brain instead of its own, all
+    /// of them evaluated as a single batch, see `restart_shared`
+    pub shared_brain: Option<NeuralNetwork>,
+    /// evaluates the batchable brains of every car, which it holds a copy
+    /// of, genomes and recurrent brains are evaluated by their cars
+    inference: Box<dyn Inference>,
+    /// sensor readings of the cars in `driving`, gathered for batched inference
+    observations: Vec<f32>,
+    driving: Vec<usize>,
+    /// index into the brains of `inference` of every car in `driving`
+    brain_indices: Vec<u32>,
+    car_texture_size: (u32, u32),
+    seed: u64,
+    rng: StdRng,
+    accumulated_t_s: f32,
+}
+
+impl Simulation {
+    pub fn new(
+        config: SimulationConfig,
+        ref_brain: Option<NeuralNetwork>,
+        ref_brain2: Option<NeuralNetwork>,
+    ) -> Result<Self, String> {
+        let car_texture_size = car::texture_size()?;
+        let seed = config.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        let mut rng = StdRng::seed_from_u64(seed);
+        let road = Road::new(config.road_center_x, config.road_width, config.lanes);
+        let cars = generate_ai_cars(
+            config.amount_cars,
+            &road,
+            &config.features,
+            ref_brain.as_ref(),
+            ref_brain2.as_ref(),
+            car_texture_size,
+            &mut rng,
+        );
+        let traffic = generate_traffic(
+            config.traffic_size,
+            config.traffic_min_velocity,
+            config.view_height,
+            &road,
+            car_texture_size,
+            &mut rng,
+        );
+        let inference = inference::new_inference(config.inference);
+
+        let mut sim = Self {
+            config,
+            road,
+            cars,
+            traffic,
+            controlled_car: None,
+            fitness: fitness::default_fitness(),
+            leader_idx: 0,
+            top_score_idx: 0,
+            elapsed_s: 0.0,
+            shared_brain: None,
+            inference,
+            observations: vec![],
+            driving: vec![],
+            brain_indices: vec![],
+            car_texture_size,
+            seed,
+            rng,
+            accumulated_t_s: 0.0,
+        };
+        sim.load_brains();
+        Ok(sim)
+    }
+
+    /// Hands the brains the cars drive with to the inference backend.
+    fn load_brains(&mut self) {
+        match &self.shared_brain {
+            Some(brain) => self.inference.load(std::slice::from_ref(brain)),
+            None => {
+                let brains: Vec<NeuralNetwork> = self
+                    .cars
+                    .iter()
+                    .filter_map(|c| c.brain.as_ref().and_then(Brain::as_batchable).cloned())
+                    .collect();
+                self.inference.load(&brains);
+            }
+        }
+    }
+
+    /// Adds a human driven car placed in the middle lane.
+    pub fn spawn_controlled_car(&mut self) -> Result<(), String> {
+        let (w, h) = self.car_texture_size;
+        let mut car = Car::new(1, w, h, &self.config.features, None, 0.0, &mut self.rng);
+        car.src_crop_center(194, 380, 0.3);
+        car.set_in_lane(&self.road, 1)?;
+        self.controlled_car = Some(ControlledCar::new(car));
+        Ok(())
+    }
+
+    /// Top of the tracked stretch of road. Cars below
+    /// `view_offset() + view_height` are considered left behind.
+    pub fn view_offset(&self) -> f32 {
+        let anchor = self.config.view_height * 0.7;
+        if let Some(controlled_car) = &self.controlled_car {
+            return controlled_car.screen_offset(anchor);
+        }
+        self.cars
+            .get(self.leader_idx)
+            .or_else(|| self.traffic.first())
+            .map(|c| c.position.y - anchor)
+            .unwrap_or(-anchor)
+    }
+
+    /// Seed the simulation was started with, enough to reproduce the run.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Whether the time is up or every car, the one driven by hand included,
+    /// has crashed.
+    pub fn is_generation_over(&self) -> bool {
+        self.elapsed_s >= self.config.generation_time_limit_s
+            || (self.cars.iter().all(|c| c.damaged)
+                && self.controlled_car.as_ref().is_none_or(|c| c.car.damaged))
+    }
+
+    pub fn fitness(&self) -> Vec<f64> {
+        self.cars.iter().map(|c| c.fitness).collect()
+    }
+
+    /// Starts a new generation driven by `brains`, one car per brain, on fresh traffic.
+    pub fn restart<B: Clone + Into<Brain>>(&mut self, brains: &[B]) {
+        self.shared_brain = None;
+        self.resize_cars(brains.len());
+        for (car, brain) in self.cars.iter_mut().zip(brains) {
+            car.brain = Some(brain.clone().into());
+        }
+        self.load_brains();
+        self.replay();
+    }
+
+    /// Starts a new generation of `count` cars all driving `brain`, on fresh
+    /// traffic. Genomes and recurrent brains are not batched, every car gets
+    /// a copy.
+    pub fn restart_shared(&mut self, brain: impl Into<Brain>, count: usize) {
+        match brain.into() {
+            Brain::Dense(network) if !network.is_recurrent() => {
+                self.resize_cars(count);
+                for car in self.cars.iter_mut() {
+                    car.brain = None;
+                }
+                self.inference.load(std::slice::from_ref(&network));
+                self.shared_brain = Some(network);
+                self.replay();
+            }
+            brain => self.restart(&vec![brain; count]),
+        }
+    }
+
+    /// Starts a new generation of `count` cars without brains, on fresh
+    /// traffic. They keep their controls unless driven through `step_with`.
+    pub fn restart_brainless(&mut self, count: usize) {
+        self.shared_brain = None;
+        self.resize_cars(count);
+        for car in self.cars.iter_mut() {
+            car.brain = None;
+        }
+        self.load_brains();
+        self.replay();
+    }
+
+    fn resize_cars(&mut self, count: usize) {
+        let (w, h) = self.car_texture_size;
+        self.cars.truncate(count);
+        while self.cars.len() < count {
+            let mut car = Car::new(0, w, h, &self.config.features, None, 0.0, &mut self.rng);
+            car.src_crop_center(194, 380, 0.3);
+            self.cars.push(car);
+        }
+    }
+
+    /// Starts the cars over with the brains they have, on fresh traffic.
+    pub fn replay(&mut self) {
+        for car in self.cars.iter_mut() {
+            car.reset(START_Y, &self.road, &mut self.rng);
+        }
+        self.traffic = generate_traffic(
+            self.traffic.len() as u32,
+            self.config.traffic_min_velocity,
+            self.config.view_height,
+            &self.road,
+            self.car_texture_size,
+            &mut self.rng,
+        );
+        self.leader_idx = 0;
+        self.top_score_idx = 0;
+        self.elapsed_s = 0.0;
+    }
+
+    /// Marks the leading car as crashed so the rest of the population takes over.
+    pub fn crash_leader(&mut self) {
+        if let Some(car) = self.cars.get_mut(self.leader_idx) {
+            car.damaged = true;
+            car.fitness = -1000.0;
+        }
+    }
+
+    /// Advances the simulation by `elapsed_s` of wall clock time. With a fixed
+    /// timestep the time is accumulated and consumed in whole steps, so the
+    /// result does not depend on the frame rate.
+    pub fn advance(&mut self, elapsed_s: f32) {
+        let Some(fixed_delta_t_s) = self.config.fixed_delta_t_s else {
+            self.step(elapsed_s);
+            return;
+        };
+        // never try to catch up more than a few steps after a long stall
+        self.accumulated_t_s = (self.accumulated_t_s + elapsed_s).min(fixed_delta_t_s * 5.0);
+        while self.accumulated_t_s >= fixed_delta_t_s {
+            self.step(fixed_delta_t_s);
+            self.accumulated_t_s -= fixed_delta_t_s;
+        }
+    }
+
+    pub fn step(&mut self, delta_t_s: f32) {
+        self.step_with(delta_t_s, |_, _| None);
+    }
+
+    /// Like `step`, but once the brains have driven, `drive` is given the
+    /// index and sensor readings of every car that needs controls and may
+    /// return outputs, read as discrete controls, to drive it with instead,
+    /// e.g. for an agent learning outside the simulation.
+    pub fn step_with(
+        &mut self,
+        delta_t_s: f32,
+        mut drive: impl FnMut(usize, &[f32]) -> Option<[f32; 4]>,
+    ) {
+        self.track_best_cars();
+        self.elapsed_s += delta_t_s;
+        let offset = self.view_offset();
+        let view_height = self.config.view_height;
+        self.road.follow(offset);
+
+        for car in self.traffic.iter_mut() {
+            car.update(delta_t_s, &self.road, &[]);
+            if car.is_passed_bottom_bound(view_height as i32, offset) {
+                reset_passed_car(
+                    car,
+                    self.config.traffic_min_velocity,
+                    view_height,
+                    &self.road,
+                    &mut self.rng,
+                );
+            }
+        }
+
+        let road = &self.road;
+        let traffic = &self.traffic;
+        self.cars.par_iter_mut().for_each(|car| car.sense(road, traffic));
+        self.drive_cars();
+        for (i, car) in self.cars.iter_mut().enumerate() {
+            if !car.needs_controls() {
+                continue;
+            }
+            if let Some(outputs) = drive(i, car.sensor_readings()) {
+                car.apply_brain_outputs(&outputs, ControlMode::Discrete);
+            }
+        }
+        let road = &self.road;
+        let traffic = &self.traffic;
+        let fitness = &self.fitness;
+        self.cars.par_iter_mut().for_each(|car| {
+            car.act(delta_t_s, road, traffic);
+            car.fitness += fitness.evaluate(&car.telemetry);
+        });
+
+        // cars left behind by the leader are out for the rest of the generation
+        for car in self.cars.iter_mut() {
+            if car.is_passed_bottom_bound(view_height as i32, offset) {
+                car.damaged = true;
+            }
+        }
+
+        if let Some(controlled_car) = self.controlled_car.as_mut() {
+            controlled_car.update(delta_t_s, &self.road, &self.traffic);
+        }
+    }
+
+    /// Gathers the sensor readings of every car still driving a batchable
+    /// brain, runs them through the brains in one batched call and hands the
+    /// controls back. Cars driving a genome or a recurrent brain evaluate it
+    /// themselves.
+    fn drive_cars(&mut self) {
+        self.cars
+            .par_iter_mut()
+            .filter(|c| c.brain.as_ref().is_some_and(|b| b.as_batchable().is_none()))
+            .for_each(Car::think);
+
+        self.driving.clear();
+        self.observations.clear();
+        self.brain_indices.clear();
+        let shared = self.shared_brain.is_some();
+        let mut brain_idx = 0;
+        for (i, car) in self.cars.iter().enumerate() {
+            let batchable = car.brain.as_ref().is_some_and(|b| b.as_batchable().is_some());
+            if !shared && !batchable {
+                continue;
+            }
+            if car.needs_controls() {
+                self.driving.push(i);
+                self.observations.extend_from_slice(car.sensor_readings());
+                self.brain_indices.push(if shared { 0 } else { brain_idx });
+            }
+            brain_idx += 1;
+        }
+        if self.driving.is_empty() {
+            return;
+        }
+
+        let actions = self
+            .inference
+            .infer(&self.brain_indices, &self.observations);
+        let output_count = actions.len() / self.driving.len();
+        let shared_mode = self.shared_brain.as_ref().map(|b| b.control);
+        for (&i, outputs) in self.driving.iter().zip(actions.chunks_exact(output_count)) {
+            let car = &mut self.cars[i];
+            let mode = shared_mode
+                .or_else(|| car.brain.as_ref().map(Brain::control_mode))
+                .unwrap_or_default();
+            car.apply_brain_outputs(outputs, mode);
+        }
+    }
+
+    /// Keeps track of the furthest and of the best scoring car still alive.
+    fn track_best_cars(&mut self) {
+        let alive = self.cars.iter().enumerate().filter(|(_, c)| !c.damaged);
+        if let Some((idx, _)) = alive.clone().min_by(|(_, a), (_, b)| {
+            a.position
+                .y
+                .partial_cmp(&b.position.y)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        }) {
+            self.leader_idx = idx;
+        }
+        if let Some((idx, _)) = alive.max_by(|(_, a), (_, b)| {
+            a.fitness
+                .partial_cmp(&b.fitness)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        }) {
+            self.top_score_idx = idx;
+        }
+    }
+}
+
+fn generate_ai_cars(
+    amount: u32,
+    road: &Road,
+    features: &[Feature],
+    ref_brain: Option<&NeuralNetwork>,
+    ref_brain2: Option<&NeuralNetwork>,
+    (w, h): (u32, u32),
+    rng: &mut StdRng,
+) -> Vec<Car> {
+    let mut cars = Vec::with_capacity(amount as usize);
+    let mut car;
+
+    for i in 0..amount {
+        let brain = if i % 2 == 0 { ref_brain } else { ref_brain2 };
+        let t = if i % 5 == 0 { 0.33 } else { 0.92 };
+        let lane_idx = road.random_lane_idx(rng);
+        car = Car::new(lane_idx, w, h, features, brain, t, rng);
+        car.src_crop_center(194, 380, 0.3);
+        let _ = car.set_in_lane(road, lane_idx);
+        cars.push(car);
+    }
+    cars
+}
+
+fn generate_traffic(
+    amount: u32,
+    min_velocity: f32,
+    view_height: f32,
+    road: &Road,
+    (w, h): (u32, u32),
+    rng: &mut StdRng,
+) -> Vec<Car> {
+    let mut cars = Vec::with_capacity(amount as usize);
+    let mut car;
+    for _ in 0..amount {
+        let lane_idx = road.random_lane_idx(rng);
+        car = Car::new(lane_idx, w, h, &[], None, 0.0, rng);
+        let max_velocity = rng.gen_range(min_velocity..min_velocity + 4.0); // 4.0 m/s ≃ 15 km/h
+        let y_step = rng.gen_range(1..6);
+        let start_y = view_height + y_step as f32 * 3.0;
+        car.src_crop_center(194, 380, 0.3);
+        car.position.y -= start_y;
+        let _ = car.set_in_lane(road, lane_idx);
+        car.as_dummy(max_velocity);
+
+        cars.push(car);
+    }
+    cars
+}
+
+fn reset_passed_car(
+    car: &mut Car,
+    min_velocity: f32,
+    view_height: f32,
+    road: &Road,
+    rng: &mut StdRng,
+) {
+    let lane_idx = road.random_lane_idx(rng);
+    let max_velocity = rng.gen_range(min_velocity..min_velocity + 4.0);
+    let y_step = rng.gen_range(1..6);
+    let start_y = view_height + (y_step as f32 * (view_height * 0.15));
+    car.position.y -= start_y;
+    let _ = car.set_in_lane(road, lane_idx);
+    car.as_dummy(max_velocity);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::LevelKind;
+
+    #[test]
+    fn runs_headless() {
+        let config = SimulationConfig {
+            amount_cars: 8,
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(config, None, None).unwrap();
+        let start_y = sim.traffic[0].position.y;
+        for _ in 0..120 {
+            sim.step(1.0 / 60.0);
+        }
+        assert_eq!(sim.cars.len(), 8);
+        assert!(sim.traffic[0].position.y < start_y);
+    }
+
+    #[test]
+    fn waits_for_the_controlled_car() {
+        let config = SimulationConfig {
+            amount_cars: 0,
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(config, None, None).unwrap();
+        sim.spawn_controlled_car().unwrap();
+        assert!(!sim.is_generation_over());
+        sim.controlled_car.as_mut().unwrap().car.damaged = true;
+        assert!(sim.is_generation_over());
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let run = || {
+            let config = SimulationConfig {
+                amount_cars: 16,
+                seed: Some(42),
+                fixed_delta_t_s: Some(1.0 / 60.0),
+                ..Default::default()
+            };
+            let mut sim = Simulation::new(config, None, None).unwrap();
+            for _ in 0..600 {
+                sim.advance(1.0 / 60.0);
+            }
+            sim.cars
+                .iter()
+                .chain(sim.traffic.iter())
+                .map(|c| (c.position.x.to_bits(), c.position.y.to_bits(), c.fitness.to_bits()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn shared_brain_drives_like_copies() {
+        let run = |shared: bool| {
+            let config = SimulationConfig {
+                amount_cars: 6,
+                seed: Some(3),
+                ..Default::default()
+            };
+            let mut sim = Simulation::new(config, None, None).unwrap();
+            let brain = sim.cars[0].brain.as_ref().and_then(Brain::as_dense).cloned().unwrap();
+            if shared {
+                sim.restart_shared(brain, 6);
+            } else {
+                sim.restart(&vec![brain; 6]);
+            }
+            for _ in 0..300 {
+                sim.step(1.0 / 60.0);
+            }
+            sim.cars
+                .iter()
+                .map(|c| (c.position.x.to_bits(), c.position.y.to_bits(), c.damaged))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(true), run(false));
+    }
+
+    #[test]
+    fn drives_brainless_cars_from_outside() {
+        let config = SimulationConfig {
+            amount_cars: 0,
+            seed: Some(4),
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(config, None, None).unwrap();
+        sim.restart_brainless(3);
+        assert!(sim.cars.iter().all(|c| c.brain.is_none()));
+        let start_x = sim.cars[0].position.x;
+        let inputs = car::brain_topology(&[])[0] as usize;
+        let mut driven = vec![];
+        for _ in 0..30 {
+            sim.step_with(1.0 / 60.0, |i, readings| {
+                assert_eq!(readings.len(), inputs);
+                driven.push(i);
+                (i == 0).then_some([1.0, 0.0, 1.0, 0.0])
+            });
+        }
+        assert!(driven.contains(&2));
+        assert!(sim.cars[0].position.x < start_x);
+    }
+
+    #[test]
+    fn analog_brains_steer_partway() {
+        let run = |control: ControlMode| {
+            let config = SimulationConfig {
+                amount_cars: 0,
+                seed: Some(6),
+                ..Default::default()
+            };
+            let mut sim = Simulation::new(config, None, None).unwrap();
+            // all outputs are constant: forward at 0.46 and right at 0.2,
+            // which only presses forward in discrete mode
+            let mut brain = NeuralNetwork::new(&car::brain_topology(&[]));
+            brain.levels.last_mut().unwrap().biases = vec![0.5, 0.0, 0.0, 0.2];
+            brain.control = control;
+            sim.restart_shared(brain, 1);
+            let (start_x, start_y) = (sim.cars[0].position.x, sim.cars[0].position.y);
+            for _ in 0..30 {
+                sim.step(1.0 / 60.0);
+            }
+            let car = &sim.cars[0];
+            (car.position.x - start_x, car.position.y - start_y)
+        };
+        let (discrete_dx, discrete_dy) = run(ControlMode::Discrete);
+        let (analog_dx, analog_dy) = run(ControlMode::Analog);
+        assert_eq!(discrete_dx, 0.0);
+        assert!(analog_dx > 0.0);
+        // half throttle accelerates slower than the pedal floored
+        assert!(discrete_dy < analog_dy && analog_dy < 0.0);
+    }
+
+    #[test]
+    fn discrete_outputs_replace_analog_ones() {
+        let config = SimulationConfig {
+            amount_cars: 0,
+            seed: Some(8),
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(config, None, None).unwrap();
+        // steers right on its own
+        let mut brain = NeuralNetwork::new(&car::brain_topology(&[]));
+        brain.levels.last_mut().unwrap().biases = vec![0.5, 0.0, 0.0, 0.5];
+        brain.control = ControlMode::Analog;
+        sim.restart_shared(brain, 1);
+        let start_x = sim.cars[0].position.x;
+        for _ in 0..30 {
+            sim.step_with(1.0 / 60.0, |_, _| Some([1.0, 0.0, 1.0, 0.0]));
+        }
+        assert!(sim.cars[0].position.x < start_x);
+    }
+
+    #[test]
+    fn borders_follow_the_cars() {
+        let config = SimulationConfig {
+            amount_cars: 1,
+            seed: Some(7),
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(config, None, None).unwrap();
+        // further than the borders reached when the simulation started
+        sim.cars[0].position.y = -2_000_000.0;
+        sim.step(1.0 / 60.0);
+        let y = sim.cars[0].position.y as i32;
+        assert!(sim
+            .road
+            .borders
+            .iter()
+            .all(|b| b.start.y < y && y < b.end.y));
+    }
+
+    #[test]
+    fn drives_with_genomes() {
+        use crate::neat::NeatPopulation;
+
+        let config = SimulationConfig {
+            amount_cars: 0,
+            seed: Some(5),
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(config, None, None).unwrap();
+        let inputs = car::brain_topology(&[])[0] as usize;
+        let population = NeatPopulation::new(Default::default(), 6, inputs, 4, 5);
+        sim.restart(population.genomes());
+        let start_y: Vec<f32> = sim.cars.iter().map(|c| c.position.y).collect();
+        for _ in 0..60 {
+            sim.step(1.0 / 60.0);
+        }
+        assert_eq!(sim.cars.len(), 6);
+        assert!(sim
+            .cars
+            .iter()
+            .zip(start_y)
+            .any(|(c, y)| c.position.y != y));
+    }
+
+    #[test]
+    fn recurrent_brains_forget_between_generations() {
+        let config = SimulationConfig {
+            amount_cars: 0,
+            seed: Some(8),
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(config, None, None).unwrap();
+        let mut rng = StdRng::seed_from_u64(8);
+        let mut brain = NeuralNetwork::with_kinds(
+            &car::brain_topology(&[]),
+            &car::brain_kinds(Some(LevelKind::Gru)),
+        );
+        brain.randomize(&mut rng);
+        sim.restart_shared(brain, 4);
+        assert!(sim.shared_brain.is_none());
+
+        let memory = |sim: &Simulation| -> Vec<f32> {
+            sim.cars
+                .iter()
+                .filter_map(|c| c.brain.as_ref().and_then(Brain::as_dense))
+                .flat_map(|b| b.levels[b.levels.len() - 2].outputs.clone())
+                .collect()
+        };
+        for _ in 0..30 {
+            sim.step(1.0 / 60.0);
+        }
+        assert!(memory(&sim).iter().any(|&h| h != 0.0));
+        sim.replay();
+        assert!(memory(&sim).iter().all(|&h| h == 0.0));
+    }
+}
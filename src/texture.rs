@@ -25,21 +25,15 @@ pub fn from_file<'a>(
     let texture = tc
         .create_texture_from_surface(&surface)
         .map_err(|e| e.to_string())?;
-    Ok(SizedTexture::new(texture, width, height))
+    Ok(SizedTexture::new(texture))
 }
 
 pub struct SizedTexture<'a> {
     pub texture: Texture<'a>,
-    pub width: u32,
-    pub height: u32,
 }
 impl<'a> SizedTexture<'a> {
-    fn new(texture: Texture<'a>, width: u32, height: u32) -> SizedTexture<'a> {
-        SizedTexture {
-            texture,
-            width,
-            height,
-        }
+    fn new(texture: Texture<'a>) -> SizedTexture<'a> {
+        SizedTexture { texture }
     }
 }
 
@@ -0,0 +1,190 @@
+use sdl2::{event::Event, keyboard::Keycode, pixels::Color, rect::Rect};
+use std::time::{Duration, Instant};
+
+use crate::car;
+use crate::evolution::Evolve;
+use crate::experiment::WindowConfig;
+use crate::simulation::Simulation;
+use crate::texture::SizedTexture;
+use crate::visualizer::NetworkPanel;
+
+/// What happens when every AI car is out.
+pub enum Mode<'a> {
+    /// the population evolves, the window closes after `generations` if set
+    Train {
+        population: &'a mut dyn Evolve,
+        generations: Option<u32>,
+    },
+    /// the same brains drive again on new traffic
+    Watch,
+    /// nothing, a human is driving
+    Drive,
+}
+
+/// Opens a window and draws the simulation while advancing it in real time.
+/// N shows or hides the network of the focused car. Returns when the window
+/// is closed.
+pub fn run(sim: &mut Simulation, mut mode: Mode, window_config: &WindowConfig) -> Result<(), String> {
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
+    let window = video_subsystem
+        .window("AI Car", window_config.width, window_config.height)
+        .position(100, 100)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+
+    let texture_creator = canvas.texture_creator();
+    let focused_texture = car::create_main_texture(&texture_creator)?;
+    let unfocused_texture = car::create_unfocused_texture(&texture_creator)?;
+    let damaged_texture = car::create_damaged_texture(&texture_creator)?;
+    let texture_pool =
+        car::create_traffic_texture_pool(&texture_creator, sim.traffic.len() as u32)?;
+    let textures: Vec<&SizedTexture> = sim.traffic.iter().map(|_| texture_pool.get()).collect();
+
+    let mut event_pump = sdl_context.event_pump()?;
+    let target_frame_time = Duration::from_millis(1000 / window_config.fps as u64);
+
+    let mut previous_time = Instant::now();
+    let font = ttf_context.load_font("./assets/fonts/RedHatDisplay-Regular.ttf", 28)?;
+    let small_font = ttf_context.load_font("./assets/fonts/RedHatDisplay-Regular.ttf", 14)?;
+    let mut network_panel = NetworkPanel::new();
+    let panel_width = window_config.width * 3 / 10;
+    let panel_area = Rect::new(
+        (window_config.width - panel_width) as i32 - 16,
+        16,
+        panel_width,
+        window_config.height.saturating_sub(32).max(1),
+    );
+
+    'running: loop {
+        let current_time = Instant::now();
+        let delta_time = current_time.duration_since(previous_time);
+        previous_time = current_time;
+        let delta_t_s = delta_time.as_secs_f32();
+
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => {
+                    break 'running;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::X),
+                    ..
+                } if sim.controlled_car.is_none() => {
+                    sim.crash_leader();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::N),
+                    ..
+                } => network_panel.toggle(),
+                _ => {}
+            }
+            if let Some(controlled_car) = sim.controlled_car.as_mut() {
+                controlled_car.process_event(&event);
+            }
+        }
+
+        sim.advance(delta_t_s);
+        if sim.is_generation_over() {
+            match &mut mode {
+                Mode::Train {
+                    population,
+                    generations,
+                } => {
+                    println!("{}", population.next_generation(sim));
+                    if generations.is_some_and(|n| population.generation() >= n) {
+                        break 'running;
+                    }
+                }
+                Mode::Watch => sim.replay(),
+                Mode::Drive => {}
+            }
+        }
+        network_panel.update(sim);
+
+        canvas.set_draw_color(Color::RGB(12, 12, 16));
+        canvas.clear();
+
+        let camera_y_offset = sim.view_offset();
+
+        sim.road.render(&mut canvas, camera_y_offset)?;
+
+        for (i, car) in sim.traffic.iter().enumerate() {
+            let st = textures[i];
+            car.render(
+                &mut canvas,
+                camera_y_offset,
+                false,
+                &st.texture,
+                &st.texture,
+                &damaged_texture.texture,
+            )?;
+        }
+        for (i, car) in sim.cars.iter().enumerate() {
+            let is_best = i == sim.leader_idx || i == sim.top_score_idx;
+            car.render(
+                &mut canvas,
+                camera_y_offset,
+                is_best,
+                &focused_texture.texture,
+                &unfocused_texture.texture,
+                &damaged_texture.texture,
+            )?;
+        }
+        if let Some(controlled_car) = &sim.controlled_car {
+            controlled_car.render(
+                &mut canvas,
+                camera_y_offset,
+                true,
+                &focused_texture.texture,
+                &unfocused_texture.texture,
+                &damaged_texture.texture,
+            )?;
+        }
+
+        let generation = match &mode {
+            Mode::Train { population, .. } => Some(population.generation()),
+            _ => None,
+        };
+        let lines = [
+            generation.map(|g| format!("generation {}", g)),
+            sim.cars
+                .get(sim.leader_idx)
+                .map(|c| format!("#1 fitness = {:.1}", c.fitness)),
+            sim.cars
+                .get(sim.top_score_idx)
+                .map(|c| format!("#2 fitness = {:.1}", c.fitness)),
+        ];
+        let mut txt_y = 64;
+        for txt_content in lines.iter().flatten() {
+            let txt_surface = font
+                .render(txt_content)
+                .blended(Color::RGBA(255, 0, 0, 255))
+                .map_err(|e| e.to_string())?;
+            let txt_texture = texture_creator
+                .create_texture_from_surface(&txt_surface)
+                .map_err(|e| e.to_string())?;
+
+            let (txt_width, txt_height) = txt_surface.size();
+            let txt_target = Rect::new(64, txt_y, txt_width, txt_height);
+            canvas.copy(&txt_texture, None, Some(txt_target))?;
+            txt_y += txt_height as i32 + 12;
+        }
+        network_panel.render(&mut canvas, &texture_creator, &small_font, panel_area)?;
+
+        canvas.present();
+
+        let frame_duration = current_time.elapsed();
+        if frame_duration < target_frame_time {
+            std::thread::sleep(target_frame_time - frame_duration);
+        }
+    }
+    Ok(())
+}
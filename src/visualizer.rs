@@ -0,0 +1,275 @@
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{BlendMode, Canvas, TextureCreator};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+
+use crate::brain::Brain;
+use crate::car::{self, AnalogControls, ControlMode};
+use crate::network::{LevelKind, NeuralNetwork};
+use crate::simulation::Simulation;
+
+/// units drawn per layer at most, wider layers are sampled
+const MAX_UNITS: usize = 16;
+/// side of a node, in pixels
+const NODE_SIZE: u32 = 7;
+/// room left of the output nodes for their labels, in pixels
+const LABEL_WIDTH: i32 = 110;
+/// weights at or beyond this magnitude are drawn fully opaque and thickest
+const STRONG_WEIGHT: f32 = 1.0;
+
+/// Draws the brain of the focused car, nodes coloured by their activation
+/// and edges by their weight.
+pub struct NetworkPanel {
+    pub visible: bool,
+    /// copy of the focused brain fed with the sensor readings of its car,
+    /// batched brains are evaluated away from the cars
+    network: Option<NeuralNetwork>,
+    inputs: Vec<f32>,
+    /// node and connection count of a focused genome, which is not drawn
+    genome: Option<(usize, usize)>,
+}
+
+impl NetworkPanel {
+    pub fn new() -> Self {
+        Self {
+            visible: true,
+            network: None,
+            inputs: vec![],
+            genome: None,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    /// Catches up with the car the camera follows.
+    pub fn update(&mut self, sim: &Simulation) {
+        self.network = None;
+        self.genome = None;
+        if !self.visible {
+            return;
+        }
+        let Some(car) = sim.cars.get(sim.leader_idx) else {
+            return;
+        };
+        let network = match (&car.brain, &sim.shared_brain) {
+            (Some(Brain::Dense(network)), _) => network,
+            (None, Some(shared)) => shared,
+            (Some(Brain::Neat(genome)), _) => {
+                self.genome = Some((genome.nodes().len(), genome.connections().len()));
+                return;
+            }
+            (None, None) => return,
+        };
+        self.inputs.clear();
+        self.inputs.extend_from_slice(car.sensor_readings());
+        let mut network = network.clone();
+        // a recurrent brain is evaluated by its car, feeding it again would move its state on
+        if !network.is_recurrent() {
+            network.feed_forward(&self.inputs);
+        }
+        self.network = Some(network);
+    }
+
+    pub fn render(
+        &self,
+        canvas: &mut Canvas<Window>,
+        texture_creator: &TextureCreator<WindowContext>,
+        font: &Font,
+        area: Rect,
+    ) -> Result<(), String> {
+        if !self.visible || (self.network.is_none() && self.genome.is_none()) {
+            return Ok(());
+        }
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(Color::RGBA(12, 12, 16, 200));
+        canvas.fill_rect(area)?;
+        let text_color = Color::RGB(200, 200, 210);
+
+        let Some(network) = &self.network else {
+            if let Some((nodes, connections)) = self.genome {
+                let text = format!("genome: {} nodes, {} connections", nodes, connections);
+                draw_text(
+                    canvas,
+                    texture_creator,
+                    font,
+                    &text,
+                    area.x() + 8,
+                    area.y() + 8,
+                    text_color,
+                )?;
+            }
+            return Ok(());
+        };
+
+        let layers: Vec<&[f32]> = std::iter::once(self.inputs.as_slice())
+            .chain(network.levels.iter().map(|l| l.outputs.as_slice()))
+            .collect();
+        let units: Vec<Vec<usize>> = layers
+            .iter()
+            .map(|l| sample_units(l.len(), MAX_UNITS))
+            .collect();
+        let header_height = font.height() + 8;
+        let top = area.y() + header_height;
+        let height = area.height() as i32 - header_height - 8;
+        let left = area.x() + 16;
+        let width = area.width() as i32 - 32 - LABEL_WIDTH;
+        let column_x = |c: usize| left + width * c as i32 / (layers.len() as i32 - 1).max(1);
+        let node_y =
+            |c: usize, k: usize| top + height * (2 * k as i32 + 1) / (2 * units[c].len() as i32);
+
+        // edges first so that the nodes are drawn over them
+        for (c, level) in network.levels.iter().enumerate() {
+            let (x0, x1) = (column_x(c), column_x(c + 1));
+            for (k1, &j) in units[c + 1].iter().enumerate() {
+                for (k0, &i) in units[c].iter().enumerate() {
+                    let weight = level.input_weight(j, i);
+                    let strength = (weight.abs() / STRONG_WEIGHT).min(1.0);
+                    if strength < 0.05 {
+                        continue;
+                    }
+                    let alpha = (40.0 + 200.0 * strength) as u8;
+                    canvas.set_draw_color(if weight > 0.0 {
+                        Color::RGBA(80, 200, 120, alpha)
+                    } else {
+                        Color::RGBA(220, 80, 80, alpha)
+                    });
+                    let (y0, y1) = (node_y(c, k0), node_y(c + 1, k1));
+                    for d in 0..1 + (2.0 * strength) as i32 {
+                        canvas.draw_line(Point::new(x0, y0 + d), Point::new(x1, y1 + d))?;
+                    }
+                }
+            }
+        }
+
+        for (c, layer) in layers.iter().enumerate() {
+            let x = column_x(c);
+            let mut header = if units[c].len() < layer.len() {
+                format!("{}/{}", units[c].len(), layer.len())
+            } else {
+                layer.len().to_string()
+            };
+            if let Some(level) = c.checked_sub(1).map(|l| &network.levels[l]) {
+                if level.kind != LevelKind::Dense {
+                    header = format!("{} {:?}", header, level.kind).to_lowercase();
+                }
+            }
+            draw_text(
+                canvas,
+                texture_creator,
+                font,
+                &header,
+                x - 8,
+                area.y() + 4,
+                text_color,
+            )?;
+
+            for (k, &i) in units[c].iter().enumerate() {
+                canvas.set_draw_color(activation_color(layer[i]));
+                let half = NODE_SIZE as i32 / 2;
+                canvas.fill_rect(Rect::new(
+                    x - half,
+                    node_y(c, k) - half,
+                    NODE_SIZE,
+                    NODE_SIZE,
+                ))?;
+            }
+        }
+
+        let outputs = layers[layers.len() - 1];
+        if outputs.len() == car::CONTROL_NAMES.len() {
+            let x = column_x(layers.len() - 1) + 12;
+            let labels: Vec<(String, bool)> = match network.control {
+                ControlMode::Discrete => car::CONTROL_NAMES
+                    .iter()
+                    .zip(outputs)
+                    .map(|(name, &output)| {
+                        let pressed = output > car::CONTROL_THRESHOLD;
+                        (format!("{} {}", name, if pressed { "on" } else { "off" }), pressed)
+                    })
+                    .collect(),
+                // how far the pedals are pressed and the wheels turned each way
+                ControlMode::Analog => {
+                    let analog = AnalogControls::from_outputs(outputs);
+                    [
+                        ("throttle", analog.throttle),
+                        ("brake", analog.brake),
+                        ("left", (-analog.steering).max(0.0)),
+                        ("right", analog.steering.max(0.0)),
+                    ]
+                    .iter()
+                    .map(|&(name, value)| (format!("{} {:.2}", name, value), value > 0.0))
+                    .collect()
+                }
+            };
+            for (k, (text, pressed)) in labels.into_iter().enumerate() {
+                let color = if pressed {
+                    Color::RGB(120, 230, 140)
+                } else {
+                    text_color
+                };
+                let y = node_y(layers.len() - 1, k) - font.height() / 2;
+                draw_text(canvas, texture_creator, font, &text, x, y, color)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Indices of at most `max` of `count` units, evenly spread so that the same
+/// units are drawn from one frame to the next.
+pub fn sample_units(count: usize, max: usize) -> Vec<usize> {
+    if count <= max {
+        return (0..count).collect();
+    }
+    (0..max)
+        .map(|k| k * (count - 1) / (max - 1).max(1))
+        .collect()
+}
+
+/// Blue for negative activations, amber for positive ones, brighter the larger.
+fn activation_color(value: f32) -> Color {
+    let t = value.clamp(-1.0, 1.0);
+    let v = (60.0 + 195.0 * t.abs()) as u8;
+    if t >= 0.0 {
+        Color::RGB(v, (v as f32 * 0.8) as u8, 40)
+    } else {
+        Color::RGB(40, (v as f32 * 0.6) as u8, v)
+    }
+}
+
+fn draw_text(
+    canvas: &mut Canvas<Window>,
+    texture_creator: &TextureCreator<WindowContext>,
+    font: &Font,
+    text: &str,
+    x: i32,
+    y: i32,
+    color: Color,
+) -> Result<(), String> {
+    let surface = font
+        .render(text)
+        .blended(color)
+        .map_err(|e| e.to_string())?;
+    let texture = texture_creator
+        .create_texture_from_surface(&surface)
+        .map_err(|e| e.to_string())?;
+    let (width, height) = surface.size();
+    canvas.copy(&texture, None, Some(Rect::new(x, y, width, height)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn samples_wide_layers_evenly() {
+        assert_eq!(sample_units(4, 16), vec![0, 1, 2, 3]);
+        let units = sample_units(64, 16);
+        assert_eq!(units.len(), 16);
+        assert_eq!((units[0], units[15]), (0, 63));
+        assert!(units.windows(2).all(|w| w[0] < w[1]));
+    }
+}